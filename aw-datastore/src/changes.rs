@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+/// How many changes are remembered, readers which fall further behind than this have to
+/// assume that everything changed
+const MAX_CHANGES: usize = 10_000;
+
+/// A write to the datastore, recorded so that code which caches what it read from the
+/// datastore can tell whether it is still up to date
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Events of a bucket were inserted, updated or deleted somewhere between start and
+    /// end, or anywhere in the bucket if the range is None
+    Events {
+        bucket_id: String,
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    },
+    /// Buckets were created, deleted, renamed or had their hostname changed. A bucket id
+    /// may now refer to other events than before, so all events read may have changed.
+    Buckets,
+    /// A key-value pair was set or deleted
+    KeyValue(String),
+}
+
+impl Change {
+    /// Whether the events of a bucket which were read between start and end may have changed
+    pub fn affects_events(
+        &self,
+        bucket_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> bool {
+        match self {
+            Change::Events {
+                bucket_id: changed,
+                range,
+            } => {
+                changed == bucket_id
+                    && match range {
+                        Some((changed_start, changed_end)) => {
+                            *changed_start <= end && *changed_end >= start
+                        }
+                        None => true,
+                    }
+            }
+            Change::Buckets => true,
+            Change::KeyValue(_) => false,
+        }
+    }
+}
+
+/// Numbers the changes in the order they were made, starting at 1
+#[derive(Default)]
+pub(crate) struct ChangeLog {
+    counter: u64,
+    changes: VecDeque<(u64, Change)>,
+}
+
+impl ChangeLog {
+    pub fn push(&mut self, change: Change) {
+        self.counter += 1;
+        if self.changes.len() >= MAX_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back((self.counter, change));
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    pub fn since(&self, counter: u64) -> Option<Vec<Change>> {
+        if counter > self.counter {
+            return None;
+        }
+        // The log has to reach back to the change right after counter
+        let oldest = self.changes.front().map_or(self.counter + 1, |(n, _)| *n);
+        if oldest > counter + 1 {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .filter(|(n, _)| *n > counter)
+                .map(|(_, change)| change.clone())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_log() {
+        let mut log = ChangeLog::default();
+        assert_eq!(log.since(0), Some(vec![]));
+        log.push(Change::Buckets);
+        log.push(Change::KeyValue("a".to_string()));
+        assert_eq!(log.counter(), 2);
+        assert_eq!(log.since(1), Some(vec![Change::KeyValue("a".to_string())]));
+        assert_eq!(log.since(2), Some(vec![]));
+        assert_eq!(log.since(3), None);
+
+        for _ in 0..MAX_CHANGES {
+            log.push(Change::Buckets);
+        }
+        assert_eq!(log.since(1), None);
+        assert_eq!(log.since(2).map(|c| c.len()), Some(MAX_CHANGES));
+    }
+
+    #[test]
+    fn test_affects_events() {
+        let t = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let change = Change::Events {
+            bucket_id: "b".to_string(),
+            range: Some((t("2000-01-01T10:00:00Z"), t("2000-01-01T11:00:00Z"))),
+        };
+        let day = (t("2000-01-01T00:00:00Z"), t("2000-01-02T00:00:00Z"));
+        let next_day = (t("2000-01-02T00:00:00Z"), t("2000-01-03T00:00:00Z"));
+        assert!(change.affects_events("b", day.0, day.1));
+        assert!(!change.affects_events("b", next_day.0, next_day.1));
+        assert!(!change.affects_events("c", day.0, day.1));
+        assert!(Change::Buckets.affects_events("b", day.0, day.1));
+        assert!(!Change::KeyValue("b".to_string()).affects_events("b", day.0, day.1));
+    }
+}
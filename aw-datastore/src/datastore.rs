@@ -751,6 +751,7 @@ impl DatastoreInstance {
         Ok(row)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn get_events_inner(
         &mut self,
         conn: &Connection,
@@ -759,6 +760,7 @@ impl DatastoreInstance {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
         clip_to_query_range: bool,
+        keyvals: Option<(&str, &[String])>,
     ) -> Result<Vec<Event>, DatastoreError> {
         let bucket = self.get_bucket(bucket_id)?;
 
@@ -781,17 +783,33 @@ impl DatastoreInstance {
             None => -1,
         };
 
-        let mut stmt = match conn.prepare_cached(
+        // Only events with a string value for the key which is one of the values, the same
+        // as comparing the values with serde_json
+        let keyvals_filter = "
+                    AND EXISTS (
+                        SELECT 1 FROM json_each(events.data) AS d
+                        WHERE d.key = ?5
+                            AND d.type = 'text'
+                            AND d.value IN (SELECT value FROM json_each(?6))
+                    )";
+        let sql = format!(
             "
                 SELECT id, starttime, endtime, data
                 FROM events
                 WHERE bucketrow = ?1
                     AND endtime >= ?2
                     AND starttime <= ?3
+                    {}
                 ORDER BY starttime DESC
                 LIMIT ?4
             ;",
-        ) {
+            if keyvals.is_some() {
+                keyvals_filter
+            } else {
+                ""
+            }
+        );
+        let mut stmt = match conn.prepare_cached(&sql) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -800,42 +818,44 @@ impl DatastoreInstance {
             }
         };
 
-        let rows = match stmt.query_map(
-            [
-                &bucket.bid.unwrap(),
-                &starttime_filter_ns,
-                &endtime_filter_ns,
-                &limit,
-            ],
-            |row| {
-                let id = row.get(0)?;
-                let mut starttime_ns: i64 = row.get(1)?;
-                let mut endtime_ns: i64 = row.get(2)?;
-                let data_str: String = row.get(3)?;
-
-                if clip_to_query_range {
-                    if starttime_ns < starttime_filter_ns {
-                        starttime_ns = starttime_filter_ns
-                    }
-                    if endtime_ns > endtime_filter_ns {
-                        endtime_ns = endtime_filter_ns
-                    }
+        let bucketrow = bucket.bid.unwrap();
+        let mut params: Vec<&dyn ToSql> =
+            vec![&bucketrow, &starttime_filter_ns, &endtime_filter_ns, &limit];
+        let keyvals_json;
+        if let Some((key, values)) = &keyvals {
+            keyvals_json = serde_json::to_string(values).unwrap();
+            params.push(key);
+            params.push(&keyvals_json);
+        }
+
+        let rows = match stmt.query_map(&params[..], |row| {
+            let id = row.get(0)?;
+            let mut starttime_ns: i64 = row.get(1)?;
+            let mut endtime_ns: i64 = row.get(2)?;
+            let data_str: String = row.get(3)?;
+
+            if clip_to_query_range {
+                if starttime_ns < starttime_filter_ns {
+                    starttime_ns = starttime_filter_ns
                 }
-                let duration_ns = endtime_ns - starttime_ns;
-
-                let time_seconds: i64 = starttime_ns / 1_000_000_000;
-                let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-                let data: serde_json::map::Map<String, Value> =
-                    serde_json::from_str(&data_str).unwrap();
-
-                Ok(Event {
-                    id: Some(id),
-                    timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
-                    duration: Duration::nanoseconds(duration_ns),
-                    data,
-                })
-            },
-        ) {
+                if endtime_ns > endtime_filter_ns {
+                    endtime_ns = endtime_filter_ns
+                }
+            }
+            let duration_ns = endtime_ns - starttime_ns;
+
+            let time_seconds: i64 = starttime_ns / 1_000_000_000;
+            let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+            let data: serde_json::map::Map<String, Value> =
+                serde_json::from_str(&data_str).unwrap();
+
+            Ok(Event {
+                id: Some(id),
+                timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
+                duration: Duration::nanoseconds(duration_ns),
+                data,
+            })
+        }) {
             Ok(rows) => rows,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -861,7 +881,39 @@ impl DatastoreInstance {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
-        self.get_events_inner(conn, bucket_id, starttime_opt, endtime_opt, limit_opt, true)
+        self.get_events_inner(
+            conn,
+            bucket_id,
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            true,
+            None,
+        )
+    }
+
+    /// Like get_events, but only returns the events which have a string value for the key
+    /// which is one of the values. Filtering in SQL saves parsing the data of all others.
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_events_with_keyvals(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        key: &str,
+        values: &[String],
+    ) -> Result<Vec<Event>, DatastoreError> {
+        self.get_events_inner(
+            conn,
+            bucket_id,
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            true,
+            Some((key, values)),
+        )
     }
 
     pub fn get_events_unclipped(
@@ -879,6 +931,7 @@ impl DatastoreInstance {
             endtime_opt,
             limit_opt,
             false,
+            None,
         )
     }
 
@@ -1010,6 +1063,9 @@ impl DatastoreInstance {
                 }
             };
 
+        // LIKE also treats '_' as a wildcard, so the part of the pattern before the first '%'
+        // is checked again as a literal prefix.
+        let prefix = pattern.split('%').next().unwrap_or_default();
         let mut output = HashMap::<String, String>::new();
         // Rusqlite's get wants index and item type as parameters.
         let result = stmt.query_map([pattern], |row| {
@@ -1021,8 +1077,7 @@ impl DatastoreInstance {
                     // Unwrap to String or panic on SQL row if type is invalid. Can't happen with a
                     // properly initialized table.
                     let (key, value) = row.unwrap();
-                    // Only return keys starting with "settings.".
-                    if !key.starts_with("settings.") {
+                    if !key.starts_with(prefix) {
                         continue;
                     }
                     output.insert(key, value);
@@ -17,11 +17,13 @@ macro_rules! json_map {
     }};
 }
 
+mod changes;
 mod datastore;
 mod legacy_import;
 mod privacy_filter;
 mod worker;
 
+pub use self::changes::Change;
 pub use self::datastore::DatastoreInstance;
 pub use self::worker::Datastore;
 
@@ -29,6 +31,10 @@ pub use self::worker::Datastore;
 pub enum DatastoreMethod {
     Memory(),
     File(String),
+    /// SQLite file opened read-only, for inspecting the database of a running server.
+    /// Writes fail, the database is never migrated and buckets created after it was opened
+    /// are not seen.
+    FileReadOnly(String),
     /// Encrypted SQLite file using SQLCipher. Only available with the
     /// `encryption` or `encryption-vendored` feature flags.
     #[cfg(any(feature = "encryption", feature = "encryption-vendored"))]
@@ -40,6 +46,7 @@ impl fmt::Debug for DatastoreMethod {
         match self {
             DatastoreMethod::Memory() => write!(f, "Memory()"),
             DatastoreMethod::File(p) => write!(f, "File({p:?})"),
+            DatastoreMethod::FileReadOnly(p) => write!(f, "FileReadOnly({p:?})"),
             #[cfg(any(feature = "encryption", feature = "encryption-vendored"))]
             DatastoreMethod::FileEncrypted(p, _) => write!(f, "FileEncrypted({p:?}, <redacted>)"),
         }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use chrono::DateTime;
@@ -8,12 +9,14 @@ use chrono::Utc;
 
 use rusqlite::Connection;
 use rusqlite::DropBehavior;
+use rusqlite::OpenFlags;
 use rusqlite::Transaction;
 use rusqlite::TransactionBehavior;
 
 use aw_models::Bucket;
 use aw_models::Event;
 
+use crate::changes::{Change, ChangeLog};
 use crate::privacy_filter::PrivacyFilterEngine;
 use crate::DatastoreError;
 use crate::DatastoreInstance;
@@ -25,6 +28,7 @@ type RequestReceiver = mpsc_requests::RequestReceiver<Command, Result<Response,
 #[derive(Clone)]
 pub struct Datastore {
     requester: RequestSender,
+    changes: Arc<Mutex<ChangeLog>>,
 }
 
 impl fmt::Debug for Datastore {
@@ -70,6 +74,14 @@ pub enum Command {
         Option<u64>,
         bool,
     ),
+    GetEventsWithKeyvals(
+        String,
+        Option<DateTime<Utc>>,
+        Option<DateTime<Utc>>,
+        Option<u64>,
+        String,
+        Vec<String>,
+    ),
     GetEventCount(String, Option<DateTime<Utc>>, Option<DateTime<Utc>>),
     DeleteEventsById(String, Vec<i64>),
     ForceCommit(),
@@ -99,12 +111,14 @@ struct DatastoreWorker {
     commit: bool,
     last_heartbeat: HashMap<String, Option<Event>>,
     privacy_engine: PrivacyFilterEngine,
+    changes: Arc<Mutex<ChangeLog>>,
 }
 
 impl DatastoreWorker {
     pub fn new(
         responder: mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>,
         legacy_import: bool,
+        changes: Arc<Mutex<ChangeLog>>,
     ) -> Self {
         DatastoreWorker {
             responder,
@@ -114,9 +128,28 @@ impl DatastoreWorker {
             commit: false,
             last_heartbeat: HashMap::new(),
             privacy_engine: PrivacyFilterEngine::new(vec![]),
+            changes,
         }
     }
 
+    // Changes are recorded before the response is sent, so anyone who got the response
+    // also sees the change in the log
+    fn record_change(&self, change: Change) {
+        self.changes.lock().unwrap().push(change);
+    }
+
+    fn record_events_change(&self, bucket_id: &str, events: Option<&[Event]>) {
+        let range = events.and_then(|events| {
+            let start = events.iter().map(|e| e.timestamp).min()?;
+            let end = events.iter().map(|e| e.calculate_endtime()).max()?;
+            Some((start, end))
+        });
+        self.record_change(Change::Events {
+            bucket_id: bucket_id.to_string(),
+            range,
+        });
+    }
+
     fn work_loop(&mut self, method: DatastoreMethod) {
         // Open SQLite connection
         let mut conn = match &method {
@@ -126,6 +159,10 @@ impl DatastoreWorker {
             DatastoreMethod::File(path) => {
                 Connection::open(path).expect("Failed to create datastore")
             }
+            DatastoreMethod::FileReadOnly(path) => {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                Connection::open_with_flags(path, flags).expect("Failed to open datastore")
+            }
             #[cfg(any(feature = "encryption", feature = "encryption-vendored"))]
             DatastoreMethod::FileEncrypted(path, key) => {
                 let conn = Connection::open(path).expect("Failed to create encrypted datastore");
@@ -150,19 +187,23 @@ impl DatastoreWorker {
         // with NORMAL the WAL is only synced at checkpoints, which would
         // silently widen the loss window on power failure.
         // In-memory databases ignore the request (journal_mode stays "memory").
-        let journal_mode: String = conn
-            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))
-            .expect("Failed to query journal_mode");
-        if !matches!(&method, DatastoreMethod::Memory()) && journal_mode != "wal" {
-            warn!("Failed to enable WAL (journal_mode={journal_mode}), continuing without it");
+        // A read-only connection can't change the journal mode, and never commits anything
+        let read_only = matches!(&method, DatastoreMethod::FileReadOnly(_));
+        if !read_only {
+            let journal_mode: String = conn
+                .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))
+                .expect("Failed to query journal_mode");
+            if !matches!(&method, DatastoreMethod::Memory()) && journal_mode != "wal" {
+                warn!("Failed to enable WAL (journal_mode={journal_mode}), continuing without it");
+            }
+            conn.pragma_update(None, "synchronous", "FULL")
+                .expect("Failed to set synchronous=FULL");
         }
-        conn.pragma_update(None, "synchronous", "FULL")
-            .expect("Failed to set synchronous=FULL");
 
-        let mut ds = DatastoreInstance::new(&conn, true).unwrap();
+        let mut ds = DatastoreInstance::new(&conn, !read_only).unwrap();
 
         // Ensure legacy import
-        if self.legacy_import {
+        if self.legacy_import && !read_only {
             let transaction = match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
                 Ok(transaction) => transaction,
                 Err(err) => {
@@ -186,16 +227,20 @@ impl DatastoreWorker {
         // Start handling and respond to requests
         loop {
             let last_commit_time: DateTime<Utc> = Utc::now();
-            let mut tx: Transaction =
-                match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
-                    Ok(tx) => tx,
-                    Err(err) => {
-                        error!("Unable to start transaction! {:?}", err);
-                        // Wait 1s before retrying
-                        std::thread::sleep(std::time::Duration::from_millis(1000));
-                        continue;
-                    }
-                };
+            // Immediate transactions take the write lock, which a read-only connection can't
+            let behavior = match read_only {
+                true => TransactionBehavior::Deferred,
+                false => TransactionBehavior::Immediate,
+            };
+            let mut tx: Transaction = match conn.transaction_with_behavior(behavior) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    error!("Unable to start transaction! {:?}", err);
+                    // Wait 1s before retrying
+                    std::thread::sleep(std::time::Duration::from_millis(1000));
+                    continue;
+                }
+            };
             tx.set_drop_behavior(DropBehavior::Commit);
 
             self.uncommitted_events = 0;
@@ -230,7 +275,10 @@ impl DatastoreWorker {
 
                 let now: DateTime<Utc> = Utc::now();
                 let commit_interval_passed: bool = (now - last_commit_time) > Duration::seconds(15);
-                if self.commit
+                // Each read gets a transaction of its own, so that it sees what the server
+                // has written since the last one
+                if read_only
+                    || self.commit
                     || commit_interval_passed
                     || self.uncommitted_events > 100
                     || self.quit
@@ -283,6 +331,7 @@ impl DatastoreWorker {
             Command::CreateBucket(bucket) => match ds.create_bucket(tx, bucket) {
                 Ok(_) => {
                     self.commit = true;
+                    self.record_change(Change::Buckets);
                     Ok(Response::Empty())
                 }
                 Err(e) => Err(e),
@@ -290,6 +339,7 @@ impl DatastoreWorker {
             Command::DeleteBucket(bucketname) => match ds.delete_bucket(tx, &bucketname) {
                 Ok(_) => {
                     self.commit = true;
+                    self.record_change(Change::Buckets);
                     Ok(Response::Empty())
                 }
                 Err(e) => Err(e),
@@ -304,10 +354,14 @@ impl DatastoreWorker {
                 if filtered.is_empty() {
                     return Ok(Response::EventList(vec![]));
                 }
+                // Events with an id replace an existing event, which may have been anywhere
+                let replaces = filtered.iter().any(|e| e.id.is_some());
                 match ds.insert_events(tx, &bucketname, filtered) {
                     Ok(events) => {
                         self.uncommitted_events += events.len();
                         self.last_heartbeat.insert(bucketname.to_string(), None); // invalidate last_heartbeat cache
+                        let changed = if replaces { None } else { Some(&events[..]) };
+                        self.record_events_change(&bucketname, changed);
                         Ok(Response::EventList(events))
                     }
                     Err(e) => Err(e),
@@ -339,6 +393,9 @@ impl DatastoreWorker {
                 ) {
                     Ok(e) => {
                         self.uncommitted_events += 1;
+                        // Whether it was merged or inserted, the heartbeat only changed
+                        // the time covered by the event it ended up in
+                        self.record_events_change(&bucketname, Some(std::slice::from_ref(&e)));
                         Ok(Response::Event(e))
                     }
                     Err(e) => Err(e),
@@ -361,6 +418,27 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
+            Command::GetEventsWithKeyvals(
+                bucketname,
+                starttime_opt,
+                endtime_opt,
+                limit_opt,
+                key,
+                values,
+            ) => {
+                match ds.get_events_with_keyvals(
+                    tx,
+                    &bucketname,
+                    starttime_opt,
+                    endtime_opt,
+                    limit_opt,
+                    &key,
+                    &values,
+                ) {
+                    Ok(el) => Ok(Response::EventList(el)),
+                    Err(e) => Err(e),
+                }
+            }
             Command::GetEventCount(bucketname, starttime_opt, endtime_opt) => {
                 match ds.get_event_count(tx, &bucketname, starttime_opt, endtime_opt) {
                     Ok(n) => Ok(Response::Count(n)),
@@ -369,7 +447,10 @@ impl DatastoreWorker {
             }
             Command::DeleteEventsById(bucketname, event_ids) => {
                 match ds.delete_events_by_id(tx, &bucketname, event_ids) {
-                    Ok(()) => Ok(Response::Empty()),
+                    Ok(()) => {
+                        self.record_events_change(&bucketname, None);
+                        Ok(Response::Empty())
+                    }
                     Err(e) => Err(e),
                 }
             }
@@ -382,7 +463,10 @@ impl DatastoreWorker {
                 Err(e) => Err(e),
             },
             Command::SetKeyValue(key, data) => match ds.insert_key_value(tx, &key, &data) {
-                Ok(()) => Ok(Response::Empty()),
+                Ok(()) => {
+                    self.record_change(Change::KeyValue(key));
+                    Ok(Response::Empty())
+                }
                 Err(e) => Err(e),
             },
             Command::GetKeyValue(key) => match ds.get_key_value(tx, &key) {
@@ -390,7 +474,10 @@ impl DatastoreWorker {
                 Err(e) => Err(e),
             },
             Command::DeleteKeyValue(key) => match ds.delete_key_value(tx, &key) {
-                Ok(()) => Ok(Response::Empty()),
+                Ok(()) => {
+                    self.record_change(Change::KeyValue(key));
+                    Ok(Response::Empty())
+                }
                 Err(e) => Err(e),
             },
             Command::RefreshPrivacyFilter() => {
@@ -410,6 +497,7 @@ impl DatastoreWorker {
             Command::RenameBucket(old_id, new_id) => match ds.rename_bucket(tx, &old_id, &new_id) {
                 Ok(()) => {
                     self.commit = true;
+                    self.record_change(Change::Buckets);
                     Ok(Response::Empty())
                 }
                 Err(e) => Err(e),
@@ -419,6 +507,7 @@ impl DatastoreWorker {
                     Ok(count) => {
                         if count > 0 {
                             self.commit = true;
+                            self.record_change(Change::Buckets);
                         }
                         Ok(Response::Count(count as i64))
                     }
@@ -429,6 +518,7 @@ impl DatastoreWorker {
                 Ok(count) => {
                     if count > 0 {
                         self.commit = true;
+                        self.record_change(Change::Buckets);
                     }
                     Ok(Response::Count(count as i64))
                 }
@@ -448,6 +538,12 @@ impl Datastore {
         Datastore::_new_internal(method, legacy_import)
     }
 
+    /// Opens an existing database file read-only, such as the one of a running server
+    pub fn new_read_only(dbpath: String) -> Self {
+        let method = DatastoreMethod::FileReadOnly(dbpath);
+        Datastore::_new_internal(method, false)
+    }
+
     pub fn new_in_memory(legacy_import: bool) -> Self {
         let method = DatastoreMethod::Memory();
         Datastore::_new_internal(method, legacy_import)
@@ -466,11 +562,13 @@ impl Datastore {
     fn _new_internal(method: DatastoreMethod, legacy_import: bool) -> Self {
         let (requester, responder) =
             mpsc_requests::channel::<Command, Result<Response, DatastoreError>>();
+        let changes = Arc::new(Mutex::new(ChangeLog::default()));
+        let worker_changes = Arc::clone(&changes);
         let _thread = thread::spawn(move || {
-            let mut di = DatastoreWorker::new(responder, legacy_import);
+            let mut di = DatastoreWorker::new(responder, legacy_import, worker_changes);
             di.work_loop(method);
         });
-        Datastore { requester }
+        Datastore { requester, changes }
     }
 
     /// Send a command to the worker thread and wait for its response.
@@ -593,6 +691,31 @@ impl Datastore {
         }
     }
 
+    /// Like get_events, but only returns the events which have a string value for the key
+    /// which is one of the values
+    pub fn get_events_with_keyvals(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        key: &str,
+        values: &[String],
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let cmd = Command::GetEventsWithKeyvals(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            key.to_string(),
+            values.to_vec(),
+        );
+        match self.request(cmd)? {
+            Response::EventList(el) => Ok(el),
+            _ => panic!("Invalid response"),
+        }
+    }
+
     pub fn get_event_count(
         &self,
         bucket_id: &str,
@@ -681,6 +804,19 @@ impl Datastore {
         }
     }
 
+    /// The number of changes made so far, pass it to changes_since later to get the
+    /// changes made in the meantime
+    pub fn change_counter(&self) -> u64 {
+        self.changes.lock().unwrap().counter()
+    }
+
+    /// The changes made after the change counter had the given value, in the order they
+    /// were made. Only the most recent changes are remembered, None means that some of the
+    /// changes asked for were already forgotten.
+    pub fn changes_since(&self, counter: u64) -> Option<Vec<Change>> {
+        self.changes.lock().unwrap().since(counter)
+    }
+
     // Should block until worker has stopped
     pub fn close(&self) {
         info!("Sending close request to database");
@@ -211,6 +211,55 @@ mod datastore_tests {
         assert_eq!(event_count, 2);
     }
 
+    #[test]
+    fn test_get_events_with_keyvals() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let datas = [
+            json_map! {"app": json!("a")},
+            json_map! {"app": json!("b"), "title": json!("a")},
+            json_map! {"app": json!(["a"])},
+            json_map! {"app": json!(1)},
+            json_map! {"app": json!("c")},
+            json_map! {"title": json!("a")},
+        ];
+        let events: Vec<Event> = datas
+            .iter()
+            .enumerate()
+            .map(|(i, data)| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i as i64),
+                duration: Duration::seconds(1),
+                data: data.clone(),
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        // Only string values which are equal match, like with serde_json
+        let values = ["a".to_string(), "c".to_string(), "1".to_string()];
+        let fetched = ds
+            .get_events_with_keyvals(&bucket.id, None, None, None, "app", &values)
+            .unwrap();
+        let fetched_data: Vec<_> = fetched.iter().map(|e| e.data.clone()).collect();
+        assert_eq!(fetched_data, vec![datas[4].clone(), datas[0].clone()]);
+
+        // The other filters still apply
+        let fetched = ds
+            .get_events_with_keyvals(&bucket.id, None, None, Some(1), "app", &values)
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        let fetched = ds
+            .get_events_with_keyvals(&bucket.id, None, Some(now), None, "app", &values)
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        let fetched = ds
+            .get_events_with_keyvals(&bucket.id, None, None, None, "app", &[])
+            .unwrap();
+        assert!(fetched.is_empty());
+    }
+
     /// Tests that events that cover a timeperiod get included when that timeperiod is queried.
     #[test]
     fn test_get_events_filters_cover() {
@@ -625,6 +674,42 @@ mod datastore_tests {
         }
     }
 
+    #[test]
+    fn test_datastore_read_only() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-read-only.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+        if db_path.exists() {
+            std::fs::remove_file(&db_path)
+                .expect("Failed to remove datastore-unittest-read-only.db file");
+        }
+
+        let bucket = test_bucket();
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let ds = Datastore::new(db_path_str.clone(), false);
+        ds.create_bucket(&bucket).unwrap();
+        ds.force_commit().unwrap();
+
+        let read_only = Datastore::new_read_only(db_path_str);
+        assert!(read_only.get_buckets().unwrap().contains_key(&bucket.id));
+        // Writes fail
+        assert!(read_only
+            .insert_events(&bucket.id, std::slice::from_ref(&e1))
+            .is_err());
+
+        // What's written after opening is seen as soon as it's committed
+        ds.insert_events(&bucket.id, std::slice::from_ref(&e1))
+            .unwrap();
+        ds.force_commit().unwrap();
+        let events = read_only.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
     /// Test that an encrypted datastore can be created, written to, and reopened with the same key
     /// with data intact.
     #[test]
@@ -31,7 +31,9 @@ pub use self::bucket::BucketsExport;
 pub use self::event::Event;
 pub use self::info::Info;
 pub use self::query::Query;
+pub use self::query::QueryLibrary;
 pub use self::settings::Settings;
+pub use self::settings::{settings_schema, validate_setting, SettingValidationError};
 pub use self::settings::{
     Class, ClassData, ClassRule, NewReleaseCheckData, UserSatisfactionPollData, View, ViewElement,
 };
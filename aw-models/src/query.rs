@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::TimeInterval;
 
@@ -9,3 +9,11 @@ pub struct Query {
     pub timeperiods: Vec<TimeInterval>,
     pub query: Vec<String>,
 }
+
+/// A named library of query functions, which queries can load with `import "name";`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueryLibrary {
+    #[serde(default)]
+    pub name: String,
+    pub code: String,
+}
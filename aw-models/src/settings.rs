@@ -1,5 +1,10 @@
-use schemars::JsonSchema;
+use std::fmt;
+
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct NewReleaseCheckData {
@@ -38,14 +43,64 @@ pub struct ClassData {
     pub score: Option<i32>,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone)]
-pub struct ClassRule {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ignore_case: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub regex: Option<String>,
-    #[serde(rename = "type")]
-    pub rule_type: String,
+/// Which events a class matches, as compiled by `aw_transform::classify::Rule`
+///
+/// This used to be a struct with `rule_type`, `regex` and `ignore_case` fields, so code
+/// constructing or reading those fields has to move to the variants. The JSON of the regex
+/// and none rules is unchanged, so saved classes still deserialize.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClassRule {
+    /// Matches no events, for classes which only group their subclasses
+    None,
+    /// Matches events with a string value in which the regex matches
+    Regex {
+        regex: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ignore_case: Option<bool>,
+        /// The keys of the values to match, all keys if not set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        select_keys: Option<Vec<String>>,
+    },
+    /// Matches events with a value equal to `value`
+    Exact {
+        value: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ignore_case: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        select_keys: Option<Vec<String>>,
+    },
+    /// Matches events with a string value matched as a whole by the glob pattern, in which
+    /// `*` matches any text, `?` any character and `[abc]` one of the characters
+    Glob {
+        pattern: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ignore_case: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        select_keys: Option<Vec<String>>,
+    },
+    /// Matches events with a number under the key between min and max, both included
+    Number {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+    },
+    /// Matches events starting from `start` until `end`, as "HH:MM" in the timezone, UTC if
+    /// not set. Past midnight if `end` is before `start`.
+    TimeOfDay {
+        start: String,
+        end: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timezone: Option<String>,
+    },
+    /// Matches events matched by all of the rules
+    And { rules: Vec<ClassRule> },
+    /// Matches events matched by any of the rules
+    Or { rules: Vec<ClassRule> },
+    /// Matches events not matched by the rule
+    Not { rule: Box<ClassRule> },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
@@ -74,3 +129,156 @@ pub struct Settings {
     pub classes: Vec<Class>,
     pub initial_timestamp: String,
 }
+
+/// Describes why a setting value was rejected by [`validate_setting`].
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SettingValidationError {
+    pub key: String,
+    /// Path to the offending value inside the setting, for example `[2].rule.type`.
+    /// Empty if the value itself has the wrong type.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SettingValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(
+                f,
+                "Invalid value for setting '{}': {}",
+                self.key, self.message
+            )
+        } else {
+            write!(
+                f,
+                "Invalid value for setting '{}' at '{}': {}",
+                self.key, self.path, self.message
+            )
+        }
+    }
+}
+
+fn check<T: DeserializeOwned>(key: &str, value: &Value) -> Result<(), SettingValidationError> {
+    match serde_path_to_error::deserialize::<_, T>(value) {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let path = err.path().to_string();
+            Err(SettingValidationError {
+                key: key.to_string(),
+                // serde_path_to_error uses "." for the root
+                path: if path == "." { String::new() } else { path },
+                message: err.into_inner().to_string(),
+            })
+        }
+    }
+}
+
+/// Validates a setting value against the type of the matching field in [`Settings`].
+///
+/// Keys which are not part of the model are accepted as-is, so that clients can store
+/// their own settings without the server having to know about them.
+pub fn validate_setting(key: &str, value: &Value) -> Result<(), SettingValidationError> {
+    match key {
+        "landing_page"
+        | "start_of_day"
+        | "always_active_pattern"
+        | "start_of_week"
+        | "theme"
+        | "initial_timestamp" => check::<String>(key, value),
+        "use_color_fallback" | "devmode" => check::<bool>(key, value),
+        "request_timeout" | "duration_default" => check::<i32>(key, value),
+        "new_release_check_data" => check::<NewReleaseCheckData>(key, value),
+        "user_satisfaction_poll_data" => check::<UserSatisfactionPollData>(key, value),
+        "views" => check::<Vec<View>>(key, value),
+        "classes" => check::<Vec<Class>>(key, value),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the JSON schema of the [`Settings`] model.
+pub fn settings_schema() -> RootSchema {
+    schema_for!(Settings)
+}
+
+#[test]
+fn test_validate_setting() {
+    use serde_json::json;
+
+    // Unknown keys are not validated
+    assert!(validate_setting("my_custom_key", &json!({"anything": [1, 2]})).is_ok());
+
+    assert!(validate_setting("theme", &json!("dark")).is_ok());
+    let err = validate_setting("theme", &json!(1)).unwrap_err();
+    assert_eq!(err.key, "theme");
+    assert_eq!(err.path, "");
+
+    let classes = json!([
+        {"id": 0, "name": ["Work"], "rule": {"type": "regex", "regex": "Google Docs"}},
+        {"id": 1, "name": ["Media"], "rule": {"type": "none"}, "data": {"color": "#fff"}},
+    ]);
+    assert!(validate_setting("classes", &classes).is_ok());
+
+    let classes = json!([
+        {"id": 0, "name": ["Work"], "rule": {"type": "regex", "regex": "Google Docs"}},
+        {"id": 1, "name": "Media", "rule": {"type": "none"}},
+    ]);
+    let err = validate_setting("classes", &classes).unwrap_err();
+    assert_eq!(err.path, "[1].name");
+
+    let classes = json!([
+        {"id": 0, "name": ["Work"], "rule": {"type": "and", "rules": [
+            {"type": "glob", "pattern": "*.rs", "select_keys": ["title"]},
+            {"type": "time_of_day", "start": "09:00", "end": "17:00", "timezone": "Europe/Stockholm"},
+            {"type": "not", "rule": {"type": "exact", "value": "slack", "ignore_case": true}},
+            {"type": "or", "rules": [{"type": "number", "key": "pid", "min": 10}]}
+        ]}},
+    ]);
+    assert!(validate_setting("classes", &classes).is_ok());
+
+    let classes = json!([
+        {"id": 0, "name": ["Work"], "rule": {"type": "not", "rule": {"type": "rgex"}}},
+    ]);
+    let err = validate_setting("classes", &classes).unwrap_err();
+    // The path ends at the rule, as the nested rules are parsed after their type
+    assert_eq!(err.path, "[0].rule");
+    assert!(
+        err.message.starts_with("unknown variant `rgex`"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn test_class_rule_compat() {
+    use serde_json::json;
+
+    // Rules saved before ClassRule had more types than regex and none
+    let rule: ClassRule =
+        serde_json::from_value(json!({"type": "regex", "regex": "x", "ignore_case": true}))
+            .unwrap();
+    assert_eq!(
+        rule,
+        ClassRule::Regex {
+            regex: "x".to_string(),
+            ignore_case: Some(true),
+            select_keys: None,
+        }
+    );
+    let rule: ClassRule = serde_json::from_value(json!({"type": "none"})).unwrap();
+    assert_eq!(rule, ClassRule::None);
+    // Fields of other types were allowed, and are still ignored
+    let rule: ClassRule =
+        serde_json::from_value(json!({"type": "none", "regex": "x", "ignore_case": null})).unwrap();
+    assert_eq!(rule, ClassRule::None);
+
+    // The regex rule is serialized like before
+    let rule = ClassRule::Regex {
+        regex: "x".to_string(),
+        ignore_case: None,
+        select_keys: None,
+    };
+    assert_eq!(
+        serde_json::to_value(&rule).unwrap(),
+        json!({"type": "regex", "regex": "x"})
+    );
+}
@@ -102,11 +102,40 @@ mod query_benchmarks {
             })
         });
     }
+
+    pub fn bench_explain(c: &mut Criterion) {
+        let ds = setup_datastore();
+        create_bucket(&ds, BUCKETNAME.to_string());
+        insert_events(&ds, BUCKETNAME, 5000);
+
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = String::from(
+            "
+            events = query_bucket(\"testbucket\");
+            events = merge_events_by_keys(events, [\"number\"]);
+            return sort_by_duration(events);
+            ",
+        );
+        let mut group = c.benchmark_group("bench explain");
+        for explain in [false, true] {
+            let options = aw_query::QueryOptions {
+                explain,
+                ..Default::default()
+            };
+            group.bench_function(format!("explain={explain}"), |b| {
+                b.iter(|| {
+                    aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+                })
+            });
+        }
+        group.finish();
+    }
 }
 
 criterion_group!(
     benches,
     query_benchmarks::bench_assign,
-    query_benchmarks::bench_many_events
+    query_benchmarks::bench_many_events,
+    query_benchmarks::bench_explain
 );
 criterion_main!(benches);
@@ -1,6 +1,7 @@
 use crate::lexer::Span;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Program {
@@ -22,12 +23,28 @@ pub enum Expr_ {
     Mod(Box<Expr>, Box<Expr>),
 
     Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    Less(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
+    Greater(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
 
     Var(String),
+    // x[i], an item of a list or string, or the value of a key in a dict or event
+    Index(Box<Expr>, Box<Expr>),
     Assign(String, Box<Expr>),
     Function(String, Box<Expr>),
     If(Vec<(Box<Expr>, Vec<Expr>)>),
+    For(String, Box<Expr>, Vec<Expr>),
     Return(Box<Expr>),
+    // The body is shared with the lambda values created from it
+    Lambda(Vec<String>, Arc<Vec<Expr>>),
+    Def(String, Vec<String>, Arc<Vec<Expr>>),
+    Import(String),
 
     Bool(bool),
     Number(f64),
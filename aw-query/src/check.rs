@@ -0,0 +1,631 @@
+//! Static checking of queries before they are run.
+//!
+//! Walks the AST once and infers a type for every expression, using the signatures of the
+//! builtin functions. It reports mistakes such as undefined variables, wrong parameter counts,
+//! type mismatches and unknown buckets without loading any events.
+//!
+//! The checker is deliberately lenient: whenever a type can't be known statically it falls back
+//! to `Type::Any`, which is compatible with everything, so valid queries are never rejected.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use aw_datastore::Datastore;
+use aw_models::Bucket;
+
+use crate::ast::{Expr, Expr_, Program};
+use crate::functions;
+use crate::lexer::Span;
+use crate::library;
+use crate::QueryError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// Could be anything, unknown until runtime
+    Any,
+    None,
+    Bool,
+    Number,
+    String,
+    DateTime,
+    Duration,
+    Event,
+    List(Box<Type>),
+    Dict,
+    Function(Option<Box<Signature>>),
+}
+
+impl Type {
+    pub fn list(t: Type) -> Type {
+        Type::List(Box::new(t))
+    }
+
+    pub fn events() -> Type {
+        Type::list(Type::Event)
+    }
+
+    /// Whether a value of the other type can be used where this type is expected
+    fn accepts(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::List(t1), Type::List(t2)) => t1.accepts(t2),
+            (Type::Function(_), Type::Function(_)) => true,
+            (t1, t2) => t1 == t2,
+        }
+    }
+
+    /// The most specific type which covers both types
+    fn join(self, other: Type) -> Type {
+        match (self, other) {
+            (Type::List(t1), Type::List(t2)) => Type::list(t1.join(*t2)),
+            (Type::Function(s1), Type::Function(s2)) if s1 == s2 => Type::Function(s1),
+            (Type::Function(_), Type::Function(_)) => Type::Function(None),
+            (t1, t2) if t1 == t2 => t1,
+            _ => Type::Any,
+        }
+    }
+
+    fn same_kind(&self, other: &Type) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// The type of the result of an arithmetic operator on datetimes, durations and
+    /// numbers, None if the operator can't be used on the types
+    fn arithmetic(op: &str, ta: &Type, tb: &Type) -> Option<Type> {
+        let t = match (op, ta, tb) {
+            (_, Type::Number, Type::Number) => Type::Number,
+            ("%", Type::Any, Type::Number | Type::Any) | ("%", Type::Number, Type::Any) => {
+                Type::Number
+            }
+            ("+" | "-", Type::DateTime, Type::Duration) | ("+", Type::Duration, Type::DateTime) => {
+                Type::DateTime
+            }
+            ("-", Type::DateTime, Type::DateTime) => Type::Duration,
+            ("+" | "-", Type::Duration, Type::Duration) => Type::Duration,
+            ("*", Type::Duration, Type::Number)
+            | ("*", Type::Number, Type::Duration)
+            | ("/", Type::Duration, Type::Number) => Type::Duration,
+            ("/", Type::Duration, Type::Duration) => Type::Number,
+            (
+                "+" | "-" | "*" | "/",
+                Type::Any,
+                Type::Any | Type::Number | Type::DateTime | Type::Duration,
+            )
+            | ("+" | "-" | "*" | "/", Type::Number | Type::DateTime | Type::Duration, Type::Any) => {
+                Type::Any
+            }
+            _ => return None,
+        };
+        Some(t)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::None => write!(f, "None"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::DateTime => write!(f, "DateTime"),
+            Type::Duration => write!(f, "Duration"),
+            Type::Event => write!(f, "Event"),
+            Type::List(t) if **t == Type::Any => write!(f, "List"),
+            Type::List(t) => write!(f, "List of {t}"),
+            Type::Dict => write!(f, "Dict"),
+            Type::Function(_) => write!(f, "Function"),
+        }
+    }
+}
+
+/// The parameters and return type of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    params: Vec<Type>,
+    /// How many of the last params may be left out
+    optional: usize,
+    /// Whether the last param may be repeated any number of times, including none
+    variadic: bool,
+    returns: Type,
+}
+
+impl Signature {
+    pub fn new(params: Vec<Type>, returns: Type) -> Signature {
+        Signature {
+            params,
+            optional: 0,
+            variadic: false,
+            returns,
+        }
+    }
+
+    pub fn optional(mut self, count: usize) -> Signature {
+        self.optional = count;
+        self
+    }
+
+    pub fn variadic(mut self) -> Signature {
+        self.variadic = true;
+        self
+    }
+
+    /// Signature of a lambda, which can be called with anything and return anything
+    fn lambda(param_count: usize) -> Signature {
+        Signature::new(vec![Type::Any; param_count], Type::Any)
+    }
+
+    fn min_args(&self) -> usize {
+        self.params.len() - self.optional - usize::from(self.variadic)
+    }
+
+    fn max_args(&self) -> Option<usize> {
+        match self.variadic {
+            true => None,
+            false => Some(self.params.len()),
+        }
+    }
+
+    /// The expected type of the parameter at the index, None if there are too many parameters
+    fn param(&self, i: usize) -> Option<&Type> {
+        match self.variadic && i >= self.params.len() {
+            true => self.params.last(),
+            false => self.params.get(i),
+        }
+    }
+}
+
+type Scope = HashMap<String, Type>;
+
+struct Checker<'a> {
+    ds: &'a Datastore,
+    scopes: Vec<Scope>,
+    /// All names which are assigned anywhere in the query. Lambdas read global variables
+    /// when they are called rather than when they are created, so a lambda body may use a
+    /// variable which is only assigned after the lambda itself.
+    assigned: HashSet<String>,
+    buckets: Option<HashMap<String, Bucket>>,
+    returns: bool,
+    errors: Vec<(QueryError, Option<Span>)>,
+}
+
+/// Checks a program and returns all errors found, together with the span which caused them
+pub fn check_prog(p: &Program, ds: &Datastore) -> Vec<(QueryError, Option<Span>)> {
+    let mut root = Scope::new();
+    root.insert("TIMEINTERVAL".to_string(), Type::String);
+    for builtin in functions::builtins() {
+        root.insert(
+            builtin.name.to_string(),
+            Type::Function(Some(Box::new(builtin.signature))),
+        );
+    }
+    let mut assigned = HashSet::new();
+    collect_assigned(&p.stmts, &mut assigned);
+    let mut checker = Checker {
+        ds,
+        scopes: vec![root],
+        assigned,
+        buckets: None,
+        returns: false,
+        errors: Vec::new(),
+    };
+    checker.check_block(&p.stmts);
+    if !checker.returns {
+        checker.errors.push((QueryError::EmptyQuery(), None));
+    }
+    checker.errors
+}
+
+fn collect_assigned(exprs: &[Expr], assigned: &mut HashSet<String>) {
+    for expr in exprs {
+        match &expr.node {
+            Expr_::Assign(var, e) => {
+                assigned.insert(var.clone());
+                collect_assigned(std::slice::from_ref(e), assigned);
+            }
+            Expr_::Return(e) => collect_assigned(std::slice::from_ref(e), assigned),
+            Expr_::Function(_, args) => collect_assigned(std::slice::from_ref(args), assigned),
+            Expr_::List(items) => collect_assigned(items, assigned),
+            Expr_::Def(name, _, body) => {
+                assigned.insert(name.clone());
+                collect_assigned(body, assigned);
+            }
+            Expr_::Lambda(_, body) => collect_assigned(body, assigned),
+            Expr_::For(var, _, body) => {
+                assigned.insert(var.clone());
+                collect_assigned(body, assigned);
+            }
+            Expr_::If(ifs) => {
+                for (_, block) in ifs {
+                    collect_assigned(block, assigned);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Checker<'_> {
+    fn error(&mut self, error: QueryError, span: Span) {
+        self.errors.push((error, Some(span)));
+    }
+
+    fn in_lambda(&self) -> bool {
+        self.scopes.len() > 1
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn lookup(&self, var: &str) -> Option<Type> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(t) = scope.get(var) {
+                return Some(t.clone());
+            }
+        }
+        if self.in_lambda() && self.assigned.contains(var) {
+            return Some(Type::Any);
+        }
+        None
+    }
+
+    fn check_block(&mut self, block: &[Expr]) {
+        for expr in block {
+            self.check_expr(expr);
+        }
+    }
+
+    /// Checks blocks of which at most one runs, such as the branches of an if. Afterwards a
+    /// variable counts as defined if any of the blocks defined it, since otherwise valid
+    /// queries which only use the variable when that block ran would be rejected.
+    fn check_branches(&mut self, blocks: &[&[Expr]]) {
+        let before = self.scope().clone();
+        let mut merged = before.clone();
+        for block in blocks {
+            *self.scope() = before.clone();
+            self.check_block(block);
+            for (var, t) in self.scope().drain() {
+                let t = match merged.remove(&var) {
+                    Some(prev) => prev.join(t),
+                    None => t,
+                };
+                merged.insert(var, t);
+            }
+        }
+        *self.scope() = merged;
+    }
+
+    fn check_lambda(&mut self, params: &[String], body: &[Expr]) {
+        let mut scope = Scope::new();
+        for param in params {
+            scope.insert(param.clone(), Type::Any);
+        }
+        self.scopes.push(scope);
+        // A return inside a lambda returns from the lambda, not from the query
+        let returns = self.returns;
+        self.check_block(body);
+        self.returns = returns;
+        self.scopes.pop();
+    }
+
+    fn expect(&mut self, expected: &Type, expr: &Expr, what: &str) -> Type {
+        let t = self.check_expr(expr);
+        if !expected.accepts(&t) {
+            self.error(
+                QueryError::InvalidType(format!("{what} expected {expected}, got {t}")),
+                expr.span,
+            );
+        }
+        t
+    }
+
+    fn check_operands(&mut self, op: &str, a: &Expr, b: &Expr, expr: &Expr) -> Type {
+        let (ta, tb) = (self.check_expr(a), self.check_expr(b));
+        self.check_arithmetic(op, ta, tb, expr)
+    }
+
+    fn check_arithmetic(&mut self, op: &str, ta: Type, tb: Type, expr: &Expr) -> Type {
+        match Type::arithmetic(op, &ta, &tb) {
+            Some(t) => t,
+            None => {
+                self.error(
+                    QueryError::InvalidType(format!("Cannot use {op} on {ta} and {tb}")),
+                    expr.span,
+                );
+                Type::Any
+            }
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Expr) -> Type {
+        use crate::ast::Expr_::*;
+        match &expr.node {
+            Add(a, b) => {
+                let (ta, tb) = (self.check_expr(a), self.check_expr(b));
+                if matches!(ta, Type::DateTime | Type::Duration)
+                    || matches!(tb, Type::DateTime | Type::Duration)
+                {
+                    return self.check_arithmetic("+", ta, tb, expr);
+                }
+                let addable = matches!(ta, Type::Any | Type::Number | Type::String | Type::List(_));
+                if !addable || !(ta.same_kind(&tb) || ta == Type::Any || tb == Type::Any) {
+                    self.error(
+                        QueryError::InvalidType(format!("Cannot use + on {ta} and {tb}")),
+                        expr.span,
+                    );
+                    return Type::Any;
+                }
+                match ta {
+                    Type::Any => tb,
+                    ta => ta.join(tb),
+                }
+            }
+            Sub(a, b) => self.check_operands("-", a, b, expr),
+            Mul(a, b) => self.check_operands("*", a, b, expr),
+            Div(a, b) => self.check_operands("/", a, b, expr),
+            Mod(a, b) => self.check_operands("%", a, b, expr),
+            Equal(a, b) | NotEqual(a, b) => {
+                let (ta, tb) = (self.check_expr(a), self.check_expr(b));
+                if !ta.accepts(&tb) && !ta.same_kind(&tb) {
+                    self.error(
+                        QueryError::InvalidType(format!(
+                            "Cannot compare values of different types {ta} and {tb}"
+                        )),
+                        expr.span,
+                    );
+                }
+                Type::Bool
+            }
+            Less(a, b) | LessEqual(a, b) | Greater(a, b) | GreaterEqual(a, b) => {
+                let (ta, tb) = (self.check_expr(a), self.check_expr(b));
+                let orderable = |t: &Type| {
+                    matches!(
+                        t,
+                        Type::Any
+                            | Type::Bool
+                            | Type::Number
+                            | Type::String
+                            | Type::DateTime
+                            | Type::Duration
+                    )
+                };
+                if !orderable(&ta) || !orderable(&tb) || !ta.accepts(&tb) {
+                    self.error(
+                        QueryError::InvalidType(format!("Cannot order {ta} and {tb}")),
+                        expr.span,
+                    );
+                }
+                Type::Bool
+            }
+            And(a, b) | Or(a, b) => {
+                self.expect(&Type::Bool, a, "Boolean operator");
+                self.expect(&Type::Bool, b, "Boolean operator");
+                Type::Bool
+            }
+            Not(a) => {
+                self.expect(&Type::Bool, a, "Boolean operator");
+                Type::Bool
+            }
+            Var(var) => match self.lookup(var) {
+                Some(t) => t,
+                None => {
+                    self.error(QueryError::VariableNotDefined(var.clone()), expr.span);
+                    Type::Any
+                }
+            },
+            Index(value, index) => {
+                let (tv, ti) = (self.check_expr(value), self.check_expr(index));
+                let (expected_index, t) = match tv {
+                    Type::List(t) => (Type::Number, *t),
+                    Type::String => (Type::Number, Type::String),
+                    Type::Dict | Type::Event => (Type::String, Type::Any),
+                    Type::Any => (Type::Any, Type::Any),
+                    tv => {
+                        self.error(
+                            QueryError::InvalidType(format!("Cannot index {tv}")),
+                            value.span,
+                        );
+                        return Type::Any;
+                    }
+                };
+                if !expected_index.accepts(&ti) {
+                    self.error(
+                        QueryError::InvalidType(format!(
+                            "Index expected {expected_index}, got {ti}"
+                        )),
+                        index.span,
+                    );
+                }
+                t
+            }
+            Assign(var, e) => {
+                let t = self.check_expr(e);
+                if var == "RETURN" && !self.in_lambda() {
+                    self.returns = true;
+                }
+                self.scope().insert(var.clone(), t);
+                Type::None
+            }
+            Function(fname, args) => self.check_call(fname, args, expr.span),
+            If(ifs) => {
+                let mut blocks: Vec<&[Expr]> = Vec::new();
+                for (cond, block) in ifs {
+                    self.expect(&Type::Bool, cond, "Condition");
+                    blocks.push(block);
+                }
+                // None of the branches may run
+                blocks.push(&[]);
+                self.check_branches(&blocks);
+                Type::None
+            }
+            For(var, list, block) => {
+                let t = self.expect(&Type::list(Type::Any), list, "For loop");
+                let item_type = match t {
+                    Type::List(t) => *t,
+                    _ => Type::Any,
+                };
+                self.scope().insert(var.clone(), item_type);
+                // The loop may also run zero times
+                self.check_branches(&[block, &[]]);
+                Type::None
+            }
+            Lambda(params, body) => {
+                self.check_lambda(params, body);
+                Type::Function(Some(Box::new(Signature::lambda(params.len()))))
+            }
+            Def(name, params, body) => {
+                let t = Type::Function(Some(Box::new(Signature::lambda(params.len()))));
+                self.scope().insert(name.clone(), t);
+                self.check_lambda(params, body);
+                Type::None
+            }
+            Import(name) => {
+                match library::load(name, self.ds) {
+                    Ok(defs) => {
+                        for def in defs {
+                            if let Def(name, params, _) = def.node {
+                                let t =
+                                    Type::Function(Some(Box::new(Signature::lambda(params.len()))));
+                                self.scope().insert(name, t);
+                            }
+                        }
+                    }
+                    Err(e) => self.error(e, expr.span),
+                }
+                Type::None
+            }
+            Return(e) => {
+                self.check_expr(e);
+                if !self.in_lambda() {
+                    self.returns = true;
+                }
+                Type::None
+            }
+            Bool(_) => Type::Bool,
+            Number(_) => Type::Number,
+            String(_) => Type::String,
+            List(items) => {
+                let mut item_type: Option<Type> = None;
+                for item in items {
+                    let t = self.check_expr(item);
+                    item_type = Some(match item_type {
+                        Some(prev) => prev.join(t),
+                        None => t,
+                    });
+                }
+                Type::list(item_type.unwrap_or(Type::Any))
+            }
+            Dict(d) => {
+                for val in d.values() {
+                    self.check_expr(val);
+                }
+                Type::Dict
+            }
+        }
+    }
+
+    fn check_call(&mut self, fname: &str, args: &Expr, span: Span) -> Type {
+        let args = match &args.node {
+            Expr_::List(args) => args,
+            _ => unreachable!(),
+        };
+        let signature = match self.lookup(fname) {
+            Some(Type::Function(signature)) => signature,
+            Some(Type::Any) => None,
+            Some(t) => {
+                self.error(
+                    QueryError::InvalidType(format!("{fname} is a {t}, not a function")),
+                    span,
+                );
+                None
+            }
+            None => {
+                self.error(QueryError::VariableNotDefined(fname.to_string()), span);
+                None
+            }
+        };
+        let signature = match signature {
+            Some(signature) => signature,
+            None => {
+                for arg in args {
+                    self.check_expr(arg);
+                }
+                return Type::Any;
+            }
+        };
+
+        let too_many = signature.max_args().is_some_and(|max| args.len() > max);
+        if args.len() < signature.min_args() || too_many {
+            let expected = match signature.max_args() {
+                Some(max) if max == signature.min_args() => max.to_string(),
+                Some(max) => format!("{} to {}", signature.min_args(), max),
+                None => format!("at least {}", signature.min_args()),
+            };
+            self.error(
+                QueryError::InvalidFunctionParameters(format!(
+                    "{fname} expected {expected} parameters, got {}",
+                    args.len()
+                )),
+                span,
+            );
+        }
+        for (i, arg) in args.iter().enumerate() {
+            match signature.param(i) {
+                Some(t) => {
+                    self.expect(t, arg, &format!("Parameter {} of {fname}", i + 1));
+                }
+                None => {
+                    self.check_expr(arg);
+                }
+            }
+        }
+        self.check_buckets(fname, args);
+        signature.returns
+    }
+
+    /// Checks that buckets referenced by name in the query exist
+    fn check_buckets(&mut self, fname: &str, args: &[Expr]) {
+        let literal = |i: usize| match args.get(i).map(|arg| &arg.node) {
+            Some(Expr_::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        let (Some(bucket_filter), Some(first)) = (literal(0), args.first()) else {
+            return;
+        };
+        let span = first.span;
+        if self.buckets.is_none() {
+            // Only the bucket metadata is read, never any events
+            match self.ds.get_buckets() {
+                Ok(buckets) => self.buckets = Some(buckets),
+                Err(_) => return,
+            }
+        }
+        let buckets = self.buckets.as_ref().unwrap();
+        match fname {
+            "query_bucket" if !buckets.contains_key(&bucket_filter) => {
+                self.error(
+                    QueryError::BucketQueryError(format!("There is no bucket '{bucket_filter}'")),
+                    span,
+                );
+            }
+            "find_bucket" => {
+                if args.len() == 2 && literal(1).is_none() {
+                    return;
+                }
+                let hostname_filter = literal(1);
+                let found =
+                    aw_transform::find_bucket(&bucket_filter, &hostname_filter, buckets.values());
+                if found.is_none() {
+                    self.error(
+                        QueryError::BucketQueryError(format!(
+                            "There is no bucket matching filter '{bucket_filter}'"
+                        )),
+                        span,
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use serde_json::Value;
+
+use aw_client_rust::blocking::AwClient;
+use aw_datastore::Datastore;
+use aw_models::TimeInterval;
+
+/// Where queries are run
+pub enum Backend {
+    /// A database file opened read-only, the queries run in this process
+    Datastore(Datastore),
+    /// A running aw-server, which the queries are sent to
+    Server(Box<AwClient>),
+}
+
+impl Backend {
+    /// Runs a query in a timeperiod and returns its result as JSON, or the error message
+    pub fn query(&self, code: &str, ti: &TimeInterval) -> Result<Value, String> {
+        match self {
+            Backend::Datastore(ds) => {
+                let result = aw_query::query_diagnostic(code, ti, ds).map_err(|d| d.to_string())?;
+                serde_json::to_value(&result)
+                    .map_err(|e| format!("Failed to serialize the result: {e}"))
+            }
+            Backend::Server(client) => {
+                let results = client
+                    .query(code, vec![(*ti.start(), *ti.end())])
+                    .map_err(|e| format!("Query failed: {e}"))?;
+                results
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| "The server returned no result".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! The parts of the aw-query command line tool
+
+pub mod backend;
+pub mod output;
+pub mod repl;
+pub mod timeperiod;
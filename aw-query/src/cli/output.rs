@@ -0,0 +1,184 @@
+use serde_json::{Map, Value};
+
+/// How results are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Lists of events and dicts as tables, anything else as JSON
+    Table,
+    Json,
+}
+
+/// Cells longer than this are cut off in tables
+const MAX_CELL_WIDTH: usize = 60;
+
+pub fn format(value: &Value, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(value).unwrap(),
+        Format::Table => match table(value) {
+            Some(rows) => render(&rows),
+            None => serde_json::to_string_pretty(value).unwrap(),
+        },
+    }
+}
+
+/// The header and rows of a table for the value, None if it's not shaped like a table
+fn table(value: &Value) -> Option<Vec<Vec<String>>> {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            if items.iter().all(is_event) {
+                Some(event_table(items))
+            } else if items.iter().all(Value::is_object) {
+                let rows: Vec<&Map<String, Value>> =
+                    items.iter().filter_map(Value::as_object).collect();
+                Some(object_table(&rows, &[]))
+            } else {
+                let mut rows = vec![vec!["value".to_string()]];
+                rows.extend(items.iter().map(|item| vec![cell(item)]));
+                Some(rows)
+            }
+        }
+        Value::Object(map) if !map.is_empty() => {
+            let mut rows = vec![vec!["key".to_string(), "value".to_string()]];
+            rows.extend(map.iter().map(|(k, v)| vec![k.clone(), cell(v)]));
+            Some(rows)
+        }
+        _ => None,
+    }
+}
+
+fn is_event(value: &Value) -> bool {
+    match value.as_object() {
+        Some(map) => {
+            map.contains_key("timestamp")
+                && map.contains_key("duration")
+                && map.get("data").is_some_and(Value::is_object)
+        }
+        None => false,
+    }
+}
+
+/// Events with their timestamp and duration first, followed by a column per data key
+fn event_table(events: &[Value]) -> Vec<Vec<String>> {
+    let flattened: Vec<Map<String, Value>> = events
+        .iter()
+        .filter_map(Value::as_object)
+        .map(|event| {
+            let mut row = Map::new();
+            row.insert("timestamp".to_string(), event["timestamp"].clone());
+            row.insert("duration".to_string(), event["duration"].clone());
+            if let Some(data) = event["data"].as_object() {
+                for (k, v) in data {
+                    row.insert(k.clone(), v.clone());
+                }
+            }
+            row
+        })
+        .collect();
+    let rows: Vec<&Map<String, Value>> = flattened.iter().collect();
+    object_table(&rows, &["timestamp", "duration"])
+}
+
+/// A column per key, the given keys first and then the others in alphabetical order
+fn object_table(objects: &[&Map<String, Value>], first: &[&str]) -> Vec<Vec<String>> {
+    let mut columns: Vec<String> = first.iter().map(|k| k.to_string()).collect();
+    let mut others: Vec<&String> = objects
+        .iter()
+        .flat_map(|map| map.keys())
+        .filter(|k| !first.contains(&k.as_str()))
+        .collect();
+    others.sort();
+    others.dedup();
+    columns.extend(others.into_iter().cloned());
+
+    let mut rows = vec![columns.clone()];
+    for map in objects {
+        rows.push(
+            columns
+                .iter()
+                .map(|k| map.get(k).map(cell).unwrap_or_default())
+                .collect(),
+        );
+    }
+    rows
+}
+
+/// Strings without quotes, anything else as compact JSON, on a single line
+fn cell(value: &Value) -> String {
+    let s = match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    let s = s.replace(['\n', '\r', '\t'], " ");
+    if s.chars().count() > MAX_CELL_WIDTH {
+        let cut: String = s.chars().take(MAX_CELL_WIDTH - 1).collect();
+        format!("{cut}…")
+    } else {
+        s
+    }
+}
+
+/// Renders the header and rows with the columns padded to the same width
+fn render(rows: &[Vec<String>]) -> String {
+    let columns = rows[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| rows.iter().map(|row| row[i].chars().count()).max().unwrap())
+        .collect();
+    let line = |row: &[String]| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        cells.join("  ").trim_end().to_string()
+    };
+    let mut lines = vec![line(&rows[0])];
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    lines.push(separator.join("  "));
+    lines.extend(rows[1..].iter().map(|row| line(row)));
+    lines.push(format!("({} rows)", rows.len() - 1));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{format, Format};
+
+    #[test]
+    fn test_format_table() {
+        let events = json!([
+            {"id": 1, "timestamp": "2000-01-01T00:00:00Z", "duration": 1.5, "data": {"app": "a", "title": "x"}},
+            {"id": 2, "timestamp": "2000-01-01T00:00:02Z", "duration": 10.0, "data": {"app": "bb"}},
+        ]);
+        assert_eq!(
+            format(&events, Format::Table),
+            [
+                "timestamp             duration  app  title",
+                "--------------------  --------  ---  -----",
+                "2000-01-01T00:00:00Z  1.5       a    x",
+                "2000-01-01T00:00:02Z  10.0      bb",
+                "(2 rows)",
+            ]
+            .join("\n")
+        );
+
+        let dict = json!({"a": 1, "b": [1, 2]});
+        assert_eq!(
+            format(&dict, Format::Table),
+            [
+                "key  value",
+                "---  -----",
+                "a    1",
+                "b    [1,2]",
+                "(2 rows)"
+            ]
+            .join("\n")
+        );
+
+        // Values which are not shaped like a table are printed as JSON
+        assert_eq!(format(&json!(1.5), Format::Table), "1.5");
+        assert_eq!(format(&json!([]), Format::Table), "[]");
+        assert_eq!(format(&json!([1]), Format::Json), "[\n  1\n]");
+    }
+}
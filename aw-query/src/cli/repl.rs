@@ -0,0 +1,221 @@
+use std::path::PathBuf;
+
+use chrono::Local;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::Value;
+
+use aw_models::TimeInterval;
+
+use super::backend::Backend;
+use super::output::{self, Format};
+use super::timeperiod::parse_timeperiod;
+
+const HELP: &str = "\
+Expressions are evaluated and printed, such as
+  query_bucket_names()
+Statements end with a semicolon or a block and are remembered for later input, such as
+  events = query_bucket(\"aw-watcher-window_host\");
+  def total(events) { return sum_durations(events); }
+Input continues on the next line while brackets or strings are open.
+
+Commands:
+  :timeperiod [PERIOD]  show or set the timeperiod, such as today or last-week
+  :format table|json    set how results are printed
+  :show                 show the statements remembered so far
+  :reset                forget the statements remembered so far
+  :help                 show this help
+  :quit                 exit, as does Ctrl-D";
+
+/// The state of an interactive session. The statements entered so far are kept as a prelude
+/// which is run again before each new input, since a query has no state between runs.
+pub struct Session {
+    backend: Backend,
+    pub timeperiod: TimeInterval,
+    pub format: Format,
+    prelude: String,
+}
+
+impl Session {
+    pub fn new(backend: Backend, timeperiod: TimeInterval, format: Format) -> Session {
+        Session {
+            backend,
+            timeperiod,
+            format,
+            prelude: String::new(),
+        }
+    }
+
+    /// Runs a whole query, such as the contents of a file, and prints its result
+    pub fn run_query(&self, code: &str) -> Result<(), String> {
+        let result = self.backend.query(code, &self.timeperiod)?;
+        println!("{}", output::format(&result, self.format));
+        Ok(())
+    }
+
+    /// Evaluates an input of the REPL, which is either an expression to print or statements
+    /// to remember
+    pub fn eval(&mut self, input: &str) -> Result<Option<Value>, String> {
+        let input = input.trim();
+        if let Some(expr) = input.strip_prefix("return ") {
+            return self.eval_expression(expr.trim_end_matches(';')).map(Some);
+        }
+        if is_statement(input) {
+            // Statements return nothing, so a return is added for the query to be valid
+            let code = format!("{}{input}\nreturn 0;", self.prelude);
+            self.backend.query(&code, &self.timeperiod)?;
+            self.prelude.push_str(input);
+            self.prelude.push('\n');
+            return Ok(None);
+        }
+        self.eval_expression(input).map(Some)
+    }
+
+    fn eval_expression(&self, expr: &str) -> Result<Value, String> {
+        let code = format!("{}return {expr};", self.prelude);
+        self.backend.query(&code, &self.timeperiod)
+    }
+
+    /// Runs a command starting with ':', returns false when the session should end
+    fn command(&mut self, line: &str) -> bool {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let arg = parts.next();
+        match (command, arg) {
+            (":quit" | ":q" | ":exit", _) => return false,
+            (":help", _) => println!("{HELP}"),
+            (":timeperiod", None) => println!("{}", self.timeperiod),
+            (":timeperiod", Some(period)) => match parse_timeperiod(period, Local::now()) {
+                Ok(ti) => {
+                    println!("{ti}");
+                    self.timeperiod = ti;
+                }
+                Err(e) => eprintln!("{e}"),
+            },
+            (":format", Some("table")) => self.format = Format::Table,
+            (":format", Some("json")) => self.format = Format::Json,
+            (":show", _) => print!("{}", self.prelude),
+            (":reset", _) => self.prelude.clear(),
+            _ => eprintln!("Unknown command {line}, see :help"),
+        }
+        true
+    }
+
+    /// Reads and evaluates input until the end of input, with the line history kept in a
+    /// file if there is one
+    pub fn run_repl(mut self, history: Option<PathBuf>) -> rustyline::Result<()> {
+        let mut editor = DefaultEditor::new()?;
+        if let Some(history) = &history {
+            // There is no history the first time
+            let _ = editor.load_history(history);
+        }
+        println!("Timeperiod {}, type :help for help", self.timeperiod);
+        let mut input = String::new();
+        loop {
+            let prompt = match input.is_empty() {
+                true => "aw> ",
+                false => "... ",
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C discards the current input
+                Err(ReadlineError::Interrupted) => {
+                    input.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            if input.is_empty() && line.trim().starts_with(':') {
+                editor.add_history_entry(line.as_str())?;
+                if !self.command(line.trim()) {
+                    break;
+                }
+                continue;
+            }
+            input.push_str(&line);
+            input.push('\n');
+            if is_incomplete(&input) {
+                continue;
+            }
+            let code = std::mem::take(&mut input);
+            if code.trim().is_empty() {
+                continue;
+            }
+            editor.add_history_entry(code.trim_end())?;
+            match self.eval(&code) {
+                Ok(Some(value)) => println!("{}", output::format(&value, self.format)),
+                Ok(None) => (),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        if let Some(history) = &history {
+            if let Err(e) = editor.save_history(history) {
+                eprintln!("Failed to save the history to {}: {e}", history.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the input is statements rather than an expression: assignments and calls
+/// ending in a semicolon, or definitions and blocks
+fn is_statement(input: &str) -> bool {
+    let keyword = input.split_whitespace().next().unwrap_or_default();
+    input.ends_with(';') || matches!(keyword, "def" | "if" | "for" | "import")
+}
+
+/// Whether the code has unclosed brackets or strings, so that more input is expected
+fn is_incomplete(code: &str) -> bool {
+    let mut depth: i64 = 0;
+    let mut chars = code.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some('"') => break,
+                    Some(_) => (),
+                    None => return true,
+                }
+            },
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    depth > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_incomplete, is_statement};
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(!is_incomplete("a = [1, 2];"));
+        assert!(is_incomplete("def f(x) {\n"));
+        assert!(is_incomplete("a = \"unclosed"));
+        assert!(!is_incomplete("a = \"(\\\"\"; # {"));
+        // Too many closing brackets are left for the parser to report
+        assert!(!is_incomplete("a = 1);"));
+    }
+
+    #[test]
+    fn test_is_statement() {
+        assert!(is_statement("a = 1;"));
+        assert!(is_statement("def f(x) { return x; }"));
+        assert!(is_statement("for x in [1] { print(x); }"));
+        assert!(!is_statement("{\"a\": 1}"));
+        assert!(!is_statement("a + 1"));
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+
+use aw_models::TimeInterval;
+
+/// Parses a timeperiod given on the command line, either a shortcut relative to now or an
+/// interval such as "2024-01-01T00:00:00Z/2024-01-02T00:00:00Z".
+///
+/// The shortcuts are "today", "yesterday", "this-week", "last-week", "this-month",
+/// "last-month" and "last-<n>-days"/"last-<n>-hours". Days start at local midnight and
+/// weeks on Monday.
+pub fn parse_timeperiod<Tz: TimeZone>(s: &str, now: DateTime<Tz>) -> Result<TimeInterval, String> {
+    let tz = now.timezone();
+    let today = now.date_naive();
+    let day_start = |day: NaiveDate| local_midnight(&tz, day);
+    let week_start = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let month_start = today.with_day(1).unwrap();
+    let (start, end) = match s {
+        "today" => (day_start(today), day_start(today + Duration::days(1))),
+        "yesterday" => (day_start(today - Duration::days(1)), day_start(today)),
+        "this-week" => (
+            day_start(week_start),
+            day_start(week_start + Duration::weeks(1)),
+        ),
+        "last-week" => (
+            day_start(week_start - Duration::weeks(1)),
+            day_start(week_start),
+        ),
+        "this-month" => (day_start(month_start), day_start(next_month(month_start))),
+        "last-month" => {
+            let last_month = (month_start - Duration::days(1)).with_day(1).unwrap();
+            (day_start(last_month), day_start(month_start))
+        }
+        _ => match parse_last(s) {
+            Some(duration) => {
+                let now = now.with_timezone(&Utc);
+                (now - duration, now)
+            }
+            None => {
+                return TimeInterval::new_from_string(s).map_err(|_| {
+                    format!(
+                        "Invalid timeperiod '{s}', expected today, yesterday, this-week, \
+                         last-week, this-month, last-month, last-<n>-days, last-<n>-hours \
+                         or an interval such as 2024-01-01T00:00:00Z/2024-01-02T00:00:00Z"
+                    )
+                })
+            }
+        },
+    };
+    Ok(TimeInterval::new(start, end))
+}
+
+/// Parses "last-<n>-days" and "last-<n>-hours"
+fn parse_last(s: &str) -> Option<Duration> {
+    let rest = s.strip_prefix("last-")?;
+    let (count, unit) = rest.split_once('-')?;
+    let count: i64 = count.parse().ok().filter(|n| *n > 0)?;
+    match unit {
+        "days" | "day" => Duration::try_days(count),
+        "hours" | "hour" => Duration::try_hours(count),
+        _ => None,
+    }
+}
+
+fn next_month(month_start: NaiveDate) -> NaiveDate {
+    match month_start.month() {
+        12 => NaiveDate::from_ymd_opt(month_start.year() + 1, 1, 1).unwrap(),
+        month => NaiveDate::from_ymd_opt(month_start.year(), month + 1, 1).unwrap(),
+    }
+}
+
+/// The start of a day in a timezone. Where the clocks skip midnight the day starts at the
+/// same time as midnight UTC would, which is close enough for picking a timeperiod.
+fn local_midnight<Tz: TimeZone>(tz: &Tz, day: NaiveDate) -> DateTime<Utc> {
+    let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+    match tz.from_local_datetime(&midnight).earliest() {
+        Some(t) => t.with_timezone(&Utc),
+        None => midnight.and_utc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset};
+
+    use super::parse_timeperiod;
+
+    fn period(s: &str) -> String {
+        // A Wednesday, at 01:30 local time in UTC+2
+        let now = DateTime::<FixedOffset>::parse_from_rfc3339("2024-02-14T01:30:00+02:00").unwrap();
+        parse_timeperiod(s, now).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_timeperiod() {
+        assert_eq!(
+            period("today"),
+            "2024-02-13T22:00:00+00:00/2024-02-14T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("yesterday"),
+            "2024-02-12T22:00:00+00:00/2024-02-13T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("this-week"),
+            "2024-02-11T22:00:00+00:00/2024-02-18T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("last-week"),
+            "2024-02-04T22:00:00+00:00/2024-02-11T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("this-month"),
+            "2024-01-31T22:00:00+00:00/2024-02-29T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("last-month"),
+            "2023-12-31T22:00:00+00:00/2024-01-31T22:00:00+00:00"
+        );
+        assert_eq!(
+            period("last-2-hours"),
+            "2024-02-13T21:30:00+00:00/2024-02-13T23:30:00+00:00"
+        );
+        assert_eq!(
+            period("2024-01-01T00:00:00Z/2024-01-02T00:00:00Z"),
+            "2024-01-01T00:00:00+00:00/2024-01-02T00:00:00+00:00"
+        );
+
+        let now = DateTime::<FixedOffset>::parse_from_rfc3339("2024-02-14T01:30:00+02:00").unwrap();
+        assert!(parse_timeperiod("last-0-days", now).is_err());
+        assert!(parse_timeperiod("tomorrow", now).is_err());
+    }
+}
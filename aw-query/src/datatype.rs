@@ -1,19 +1,21 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
+use super::ast::Expr;
 use super::functions;
 use super::QueryError;
-use aw_models::Event;
-use aw_transform::classify::{RegexRule, Rule};
+use aw_models::{ClassRule, Event};
+use aw_transform::classify::Rule;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
 
+use serde::ser::{Error, SerializeMap, SerializeSeq, SerializeStruct, SerializeTuple};
 use serde::{Serialize, Serializer};
 use serde_json::value::Value;
 use serde_json::Number;
 
-// TODO: greater/less comparisons
-
-#[derive(Clone, Serialize)]
-#[serde(untagged)]
+#[derive(Clone)]
 pub enum DataType {
     None(),
     Bool(bool),
@@ -22,21 +24,90 @@ pub enum DataType {
     Event(Event),
     List(Vec<DataType>),
     Dict(HashMap<String, DataType>),
-    #[serde(serialize_with = "serialize_function")]
+    DateTime(DateTime<Utc>),
+    /// Serialized as a number of seconds, like event durations
+    Duration(Duration),
     Function(String, functions::QueryFn),
+    Lambda(Lambda),
+}
+
+/// An anonymous function created with `lambda(params) { ... }`
+#[derive(Clone)]
+pub struct Lambda {
+    pub(crate) params: Vec<String>,
+    pub(crate) body: Arc<Vec<Expr>>,
+    /// The variables of the enclosing lambda calls which the body uses, as they were when
+    /// the lambda was created
+    pub(crate) captured: Arc<HashMap<String, DataType>>,
+    /// The name of a function created with `def`, under which the body can call itself
+    pub(crate) name: Option<String>,
+    /// Set when defined in a query library rather than in the query itself, the index of
+    /// the library's functions in the libraries imported by the query, see VarEnv
+    pub(crate) library: Option<usize>,
 }
 
-#[allow(clippy::trivially_copy_pass_by_ref)]
-fn serialize_function<S>(
-    _element: &str,
-    _fun: &functions::QueryFn,
-    _serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    panic!("Query function was unevaluated and was attempted to be serialized, panic!");
-    //element.id.serialize(serializer)
+/// The seconds of a duration, with the precision of the durations of events
+pub fn duration_seconds(duration: Duration) -> f64 {
+    (duration.num_milliseconds() as f64) / 1000.0
+}
+
+/// The seconds of an event duration, with the nanosecond precision events are stored with
+fn event_duration_seconds(duration: Duration) -> f64 {
+    match duration.num_nanoseconds() {
+        Some(nanos) => nanos as f64 / 1_000_000_000.0,
+        None => duration_seconds(duration),
+    }
+}
+
+/// Values are written to the serializer as they are visited, lists and dicts item by item,
+/// so that serializing to a writer doesn't need a copy of the whole value in memory.
+/// The keys of dicts and events are in alphabetical order, so that equal values serialize
+/// the same. Functions and lambdas can't be serialized and fail with an error.
+impl Serialize for DataType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            // An empty list, which is what clients have always been given
+            DataType::None() => serializer.serialize_tuple(0)?.end(),
+            DataType::Bool(b) => serializer.serialize_bool(*b),
+            DataType::Number(n) => serializer.serialize_f64(*n),
+            DataType::String(s) => serializer.serialize_str(s),
+            DataType::Event(e) => {
+                let mut event = serializer.serialize_struct("Event", 4)?;
+                event.serialize_field("data", &e.data)?;
+                event.serialize_field("duration", &event_duration_seconds(e.duration))?;
+                event.serialize_field("id", &e.id)?;
+                event.serialize_field("timestamp", &e.timestamp)?;
+                event.end()
+            }
+            DataType::List(l) => {
+                let mut seq = serializer.serialize_seq(Some(l.len()))?;
+                for item in l {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            DataType::Dict(d) => {
+                let mut entries: Vec<(&String, &DataType)> = d.iter().collect();
+                entries.sort_by_key(|(key, _)| *key);
+                let mut map = serializer.serialize_map(Some(d.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            DataType::DateTime(dt) => dt.serialize(serializer),
+            DataType::Duration(d) => serializer.serialize_f64(duration_seconds(*d)),
+            DataType::Function(name, _) => Err(S::Error::custom(format!(
+                "the function {name} cannot be serialized, call it to get a value"
+            ))),
+            DataType::Lambda(_) => Err(S::Error::custom(
+                "a lambda cannot be serialized, call it to get a value",
+            )),
+        }
+    }
 }
 
 // Needed because of a limitation in rust where you cannot derive(Debug) on a
@@ -52,7 +123,10 @@ impl fmt::Debug for DataType {
             DataType::Event(e) => write!(f, "Event({e:?})"),
             DataType::List(l) => write!(f, "List({l:?})"),
             DataType::Dict(d) => write!(f, "Dict({d:?})"),
+            DataType::DateTime(t) => write!(f, "DateTime({})", format_datetime(t)),
+            DataType::Duration(d) => write!(f, "Duration({}s)", duration_seconds(*d)),
             DataType::Function(name, _fun) => write!(f, "Function({name})"),
+            DataType::Lambda(lambda) => write!(f, "Lambda({})", lambda.params.join(", ")),
         }
     }
 }
@@ -70,12 +144,99 @@ impl DataType {
             (DataType::Event(e1), DataType::Event(e2)) => Ok(e1 == e2),
             (DataType::List(l1), DataType::List(l2)) => Ok(l1 == l2),
             (DataType::Dict(d1), DataType::Dict(d2)) => Ok(d1 == d2),
+            (DataType::DateTime(t1), DataType::DateTime(t2)) => Ok(t1 == t2),
+            (DataType::Duration(d1), DataType::Duration(d2)) => Ok(d1 == d2),
             // We do not care about comparing functions
             _ => Err(QueryError::InvalidType(format!(
                 "Cannot compare values of different types {self:?} and {other:?}"
             ))),
         }
     }
+
+    /* Ordering used by <, >, <= and >=. Only numbers, strings, bools, datetimes and
+     * durations are ordered, and only against a value of the same type. */
+    pub fn query_cmp(&self, other: &DataType) -> Result<Ordering, QueryError> {
+        match (self, other) {
+            (DataType::Bool(b1), DataType::Bool(b2)) => Ok(b1.cmp(b2)),
+            (DataType::Number(n1), DataType::Number(n2)) => match n1.partial_cmp(n2) {
+                Some(ordering) => Ok(ordering),
+                None => Err(QueryError::MathError(format!(
+                    "Cannot order the numbers {n1} and {n2}"
+                ))),
+            },
+            (DataType::String(s1), DataType::String(s2)) => Ok(s1.cmp(s2)),
+            (DataType::DateTime(t1), DataType::DateTime(t2)) => Ok(t1.cmp(t2)),
+            (DataType::Duration(d1), DataType::Duration(d2)) => Ok(d1.cmp(d2)),
+            _ if std::mem::discriminant(self) == std::mem::discriminant(other) => {
+                Err(QueryError::InvalidType(format!(
+                    "Cannot order values of type {self:?} and {other:?}, only numbers, strings, bools, datetimes and durations can be ordered"
+                )))
+            }
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot compare values of different types {self:?} and {other:?}"
+            ))),
+        }
+    }
+}
+
+impl DataType {
+    /* Indexing with x[i]. Lists and strings are indexed by position, counting from the end
+     * for negative numbers like in Python, while dicts and events are indexed by key. */
+    pub fn query_index(self, index: &DataType) -> Result<DataType, QueryError> {
+        match (self, index) {
+            (DataType::List(mut l), DataType::Number(n)) => {
+                let i = position(*n, l.len())?;
+                Ok(l.swap_remove(i))
+            }
+            (DataType::String(s), DataType::Number(n)) => {
+                let i = position(*n, s.chars().count())?;
+                Ok(DataType::String(s.chars().nth(i).unwrap().to_string()))
+            }
+            (DataType::Dict(mut d), DataType::String(key)) => match d.remove(key) {
+                Some(value) => Ok(value),
+                None => Err(QueryError::IndexError(format!(
+                    "Dict has no key '{key}'"
+                ))),
+            },
+            (DataType::Event(e), DataType::String(key)) => match key.as_str() {
+                "id" => Ok(e.id.map_or(DataType::None(), |id| DataType::Number(id as f64))),
+                "timestamp" => Ok(DataType::DateTime(e.timestamp)),
+                "duration" => Ok(DataType::Duration(e.duration)),
+                "data" => Ok(DataType::from(Value::Object(e.data))),
+                _ => Err(QueryError::IndexError(format!(
+                    "Event has no field '{key}', expected id, timestamp, duration or data"
+                ))),
+            },
+            (value, index) => Err(QueryError::InvalidType(format!(
+                "Cannot index {value:?} with {index:?}, expected a list or string indexed by number or a dict or event indexed by string"
+            ))),
+        }
+    }
+}
+
+/// Formats a datetime like the timestamps of events are serialized
+pub fn format_datetime(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// The position in a sequence of the given length which an index refers to
+fn position(index: f64, len: usize) -> Result<usize, QueryError> {
+    if index.fract() != 0.0 {
+        return Err(QueryError::IndexError(format!(
+            "Index {index} is not a whole number"
+        )));
+    }
+    let position = if index < 0.0 {
+        len as f64 + index
+    } else {
+        index
+    };
+    if position < 0.0 || position >= len as f64 {
+        return Err(QueryError::IndexError(format!(
+            "Index {index} is out of range for length {len}"
+        )));
+    }
+    Ok(position as usize)
 }
 
 /* Required for query_eq when comparing two dicts */
@@ -90,6 +251,8 @@ impl PartialEq for DataType {
             (DataType::Event(e1), DataType::Event(e2)) => e1 == e2,
             (DataType::List(l1), DataType::List(l2)) => l1 == l2,
             (DataType::Dict(d1), DataType::Dict(d2)) => d1 == d2,
+            (DataType::DateTime(t1), DataType::DateTime(t2)) => t1 == t2,
+            (DataType::Duration(d1), DataType::Duration(d2)) => d1 == d2,
             // We do not care about comparing functions
             _ => false,
         }
@@ -122,6 +285,18 @@ impl TryFrom<&DataType> for Vec<DataType> {
     }
 }
 
+impl TryFrom<DataType> for HashMap<String, DataType> {
+    type Error = QueryError;
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        match value {
+            DataType::Dict(d) => Ok(d),
+            invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected function parameter of type Dict, got {invalid_type:?}"
+            ))),
+        }
+    }
+}
+
 impl TryFrom<DataType> for String {
     type Error = QueryError;
     fn try_from(value: DataType) -> Result<Self, Self::Error> {
@@ -296,6 +471,30 @@ impl TryFrom<DataType> for usize {
     }
 }
 
+impl TryFrom<DataType> for DateTime<Utc> {
+    type Error = QueryError;
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        match value {
+            DataType::DateTime(t) => Ok(t),
+            ref invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected function parameter of type DateTime, got {invalid_type:?}"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<DataType> for Duration {
+    type Error = QueryError;
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        match value {
+            DataType::Duration(d) => Ok(d),
+            ref invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected function parameter of type Duration, got {invalid_type:?}"
+            ))),
+        }
+    }
+}
+
 impl TryFrom<DataType> for Value {
     type Error = QueryError;
     fn try_from(value: DataType) -> Result<Self, Self::Error> {
@@ -311,6 +510,17 @@ impl TryFrom<DataType> for Value {
                 }
                 Ok(Value::Array(values))
             }
+            DataType::DateTime(t) => Ok(Value::String(format_datetime(&t))),
+            DataType::Duration(d) => Ok(Value::Number(
+                Number::from_f64(duration_seconds(d)).unwrap(),
+            )),
+            DataType::Dict(d) => {
+                let mut map = serde_json::Map::with_capacity(d.len());
+                for (key, value) in d {
+                    map.insert(key, value.try_into()?);
+                }
+                Ok(Value::Object(map))
+            }
             invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
                 "Query2 support for parsing values is limited, does not support parsing {invalid_type:?}"
             ))),
@@ -344,99 +554,37 @@ impl TryFrom<&DataType> for Vec<Value> {
     }
 }
 
+impl From<Value> for DataType {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => DataType::None(),
+            Value::Bool(b) => DataType::Bool(b),
+            Value::Number(n) => DataType::Number(n.as_f64().unwrap()),
+            Value::String(s) => DataType::String(s),
+            Value::Array(values) => DataType::List(values.into_iter().map(Into::into).collect()),
+            Value::Object(map) => {
+                DataType::Dict(map.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
 impl TryFrom<&DataType> for Rule {
     type Error = QueryError;
 
+    /// Parses a rule dict in the same format as the rules of the classes setting
     fn try_from(data: &DataType) -> Result<Self, Self::Error> {
-        let obj = match data {
-            DataType::Dict(dict) => dict,
-            _ => {
-                return Err(QueryError::InvalidFunctionParameters(format!(
-                    "Expected rule dict, got {data:?}"
-                )))
-            }
-        };
-        let rtype_val = match obj.get("type") {
-            Some(rtype) => rtype,
-            None => {
-                return Err(QueryError::InvalidFunctionParameters(
-                    "rule does not have a type".to_string(),
-                ))
-            }
-        };
-        let rtype = match rtype_val {
-            DataType::String(s) => s,
-            _ => {
-                return Err(QueryError::InvalidFunctionParameters(
-                    "rule type is not a string".to_string(),
-                ))
-            }
-        };
-        if rtype == "none" {
-            Ok(Self::None)
-        } else if rtype == "regex" {
-            let regex_val = match obj.get("regex") {
-                Some(regex_val) => regex_val,
-                None => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "regex rule is missing the 'regex' field".to_string(),
-                    ))
-                }
-            };
-            let regex_str = match regex_val {
-                DataType::String(s) => s,
-                _ => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "the regex field of the regex rule is not a string".to_string(),
-                    ))
-                }
-            };
-            let ignore_case_val = match obj.get("ignore_case") {
-                Some(case_val) => case_val,
-                None => &DataType::Bool(false),
-            };
-            let ignore_case = match ignore_case_val {
-                DataType::Bool(b) => b,
-                _ => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "the ignore_case field of the regex rule is not a bool".to_string(),
-                    ))
-                }
-            };
-            let select_keys = match obj.get("select_keys") {
-                Some(DataType::List(keys)) => {
-                    let mut select_keys = Vec::with_capacity(keys.len());
-                    for key in keys {
-                        match key {
-                            DataType::String(key) => select_keys.push(key.clone()),
-                            _ => return Err(QueryError::InvalidFunctionParameters(
-                                "the select_keys field of the regex rule must contain only strings"
-                                    .to_string(),
-                            )),
-                        }
-                    }
-                    Some(select_keys)
-                }
-                Some(_) => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "the select_keys field of the regex rule is not a list".to_string(),
-                    ))
-                }
-                None => None,
-            };
-            let regex_rule = match RegexRule::new(regex_str, *ignore_case, select_keys) {
-                Ok(regex_rule) => regex_rule,
-                Err(err) => {
-                    return Err(QueryError::RegexCompileError(format!(
-                        "Failed to compile regex string '{regex_str}': '{err:?}"
-                    )))
-                }
-            };
-            Ok(Self::Regex(regex_rule))
-        } else {
-            Err(QueryError::InvalidFunctionParameters(format!(
-                "Unknown rule type '{rtype}'"
-            )))
+        if !matches!(data, DataType::Dict(_)) {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected rule dict, got {data:?}"
+            )));
         }
+        let value: Value = data.try_into()?;
+        let class_rule: ClassRule = serde_json::from_value(value)
+            .map_err(|e| QueryError::InvalidFunctionParameters(format!("Invalid rule: {e}")))?;
+        Rule::try_from(&class_rule).map_err(|e| match e.is_regex {
+            true => QueryError::RegexCompileError(e.to_string()),
+            false => QueryError::InvalidFunctionParameters(format!("Invalid rule: {e}")),
+        })
     }
 }
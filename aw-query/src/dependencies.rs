@@ -0,0 +1,51 @@
+//! What the result of a query depends on, so that it can be cached until that changes.
+
+use std::collections::BTreeSet;
+
+use aw_datastore::Change;
+use aw_models::TimeInterval;
+
+use crate::library;
+
+/// What a query run read from the datastore. Its result only changes if the query code,
+/// the timeperiod or one of these changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dependencies {
+    /// Buckets whose events in the timeperiod were read
+    pub buckets: BTreeSet<String>,
+    /// Whether the list of buckets was read, by query_bucket_names or find_bucket
+    pub bucket_list: bool,
+    /// Query libraries which were imported
+    pub libraries: BTreeSet<String>,
+    /// Settings which were read, such as the start of the day
+    pub settings: BTreeSet<String>,
+    /// Whether the current time was read by now(), the result may then differ between runs
+    /// even if nothing else changed
+    pub now: bool,
+}
+
+/// The prefix of the keys under which settings are stored in the key-value store
+pub(crate) const SETTINGS_KEY_PREFIX: &str = "settings.";
+
+impl Dependencies {
+    /// Whether a change to the datastore may change the result of the query run in the
+    /// timeperiod which these dependencies were recorded for
+    pub fn affected_by(&self, change: &Change, ti: &TimeInterval) -> bool {
+        match change {
+            Change::Buckets if self.bucket_list => true,
+            Change::KeyValue(key) => {
+                if let Some(name) = key.strip_prefix(library::KEY_PREFIX) {
+                    self.libraries.contains(name)
+                } else if let Some(name) = key.strip_prefix(SETTINGS_KEY_PREFIX) {
+                    self.settings.contains(name)
+                } else {
+                    false
+                }
+            }
+            change => self
+                .buckets
+                .iter()
+                .any(|bucket_id| change.affects_events(bucket_id, *ti.start(), *ti.end())),
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Prints queries in a canonical layout, so that queries built as one-liners can be read and
+//! queries can be diffed without the differences in whitespace.
+//!
+//! - one statement per line, blocks indented by four spaces
+//! - a space around binary operators and after commas and colons
+//! - parentheses only where the precedence requires them
+//! - dict keys in alphabetical order
+//! - lists, dicts and calls on a single line if they fit in `MAX_WIDTH` columns, otherwise
+//!   one item per line
+//!
+//! Comments are kept. A comment on the same line as the end of a statement stays there,
+//! other comments keep their place between statements, while the comments inside an
+//! expression, such as between the items of a list, are moved to before its statement.
+//! Up to one blank line between statements and comments is kept.
+
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Expr_, Program};
+use crate::lexer::{self, Lexer, Span, Token};
+
+const INDENT: &str = "    ";
+const MAX_WIDTH: usize = 100;
+
+// Precedences of the operators, from the loosest to the tightest binding
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const COMPARISON: u8 = 4;
+const SUM: u8 = 5;
+const PRODUCT: u8 = 6;
+const NEGATION: u8 = 7;
+const ATOM: u8 = 8;
+
+pub(crate) fn format_prog(code: &str, program: &Program) -> String {
+    let mut printer = Printer {
+        code,
+        braces: Lexer::new(code)
+            .filter(|(token, _)| matches!(token, Token::LBrace | Token::RBrace))
+            .collect(),
+        comments: lexer::comments(code),
+        next_comment: 0,
+        indent: 0,
+        hoisted: Vec::new(),
+        flat: false,
+    };
+    printer.block(&program.stmts, code.len())
+}
+
+struct Printer<'a> {
+    code: &'a str,
+    /// The braces in the code, to find where blocks start and end, as the AST doesn't say
+    braces: Vec<(Token, Span)>,
+    comments: Vec<(String, Span)>,
+    /// The comments before this one have been printed
+    next_comment: usize,
+    indent: usize,
+    /// Comments found inside the statement being printed, printed before it
+    hoisted: Vec<String>,
+    /// Print everything on a single line
+    flat: bool,
+}
+
+impl Printer<'_> {
+    /// Takes the comments which are not printed yet and start before the offset
+    fn take_comments(&mut self, before: usize) -> Vec<(String, Span)> {
+        let start = self.next_comment;
+        while self
+            .comments
+            .get(self.next_comment)
+            .is_some_and(|(_, span)| span.lo < before)
+        {
+            self.next_comment += 1;
+        }
+        self.comments[start..self.next_comment]
+            .iter()
+            .map(|(comment, span)| (comment.trim_end().to_string(), *span))
+            .collect()
+    }
+
+    fn line(&self, out: &mut String, text: &str) {
+        out.push_str(&INDENT.repeat(self.indent));
+        out.push_str(text);
+        out.push('\n');
+    }
+
+    /// Adds a blank line if there is one in the code between the previous line and the offset
+    fn blank_line(&self, out: &mut String, prev_end: Option<usize>, lo: usize) {
+        if let Some(prev_end) = prev_end {
+            if self.code[prev_end..lo].matches('\n').count() > 1 {
+                out.push('\n');
+            }
+        }
+    }
+
+    /// Prints the statements of a block which ends at the offset, with the comments in it
+    fn block(&mut self, stmts: &[Expr], end: usize) -> String {
+        let mut out = String::new();
+        let mut prev_end = None;
+        for (i, stmt) in stmts.iter().enumerate() {
+            for (comment, span) in self.take_comments(stmt.span.lo) {
+                self.blank_line(&mut out, prev_end, span.lo);
+                self.line(&mut out, &comment);
+                prev_end = Some(span.hi);
+            }
+            self.blank_line(&mut out, prev_end, stmt.span.lo);
+
+            let outer = std::mem::take(&mut self.hoisted);
+            let mut text = self.statement(stmt);
+            let inner = self.take_comments(stmt.span.hi);
+            let hoisted = std::mem::replace(&mut self.hoisted, outer);
+            for comment in hoisted.into_iter().chain(inner.into_iter().map(|(c, _)| c)) {
+                self.line(&mut out, &comment);
+            }
+
+            prev_end = Some(stmt.span.hi);
+            let limit = stmts.get(i + 1).map_or(end, |next| next.span.lo);
+            if let Some((comment, span)) = self.comments.get(self.next_comment) {
+                if span.lo < limit && !self.code[stmt.span.hi..span.lo].contains('\n') {
+                    text.push_str("  ");
+                    text.push_str(comment.trim_end());
+                    prev_end = Some(span.hi);
+                    self.next_comment += 1;
+                }
+            }
+            self.line(&mut out, &text);
+        }
+        for (comment, span) in self.take_comments(end) {
+            self.blank_line(&mut out, prev_end, span.lo);
+            self.line(&mut out, &comment);
+            prev_end = Some(span.hi);
+        }
+        out
+    }
+
+    /// Prints a block in braces, which start at the first brace after the offset. Returns the
+    /// offset just past the closing brace together with the text.
+    fn braced(&mut self, stmts: &[Expr], from: usize) -> (String, usize) {
+        let open = self
+            .braces
+            .iter()
+            .position(|(token, span)| matches!(token, Token::LBrace) && span.lo >= from)
+            .expect("a block starts with a brace");
+        let mut depth = 0;
+        let mut close = open;
+        for (i, (token, _)) in self.braces.iter().enumerate().skip(open) {
+            match token {
+                Token::LBrace => depth += 1,
+                _ => depth -= 1,
+            }
+            if depth == 0 {
+                close = i;
+                break;
+            }
+        }
+        let (open, close) = (self.braces[open].1, self.braces[close].1);
+
+        let before = self.take_comments(open.lo);
+        self.hoisted
+            .extend(before.into_iter().map(|(comment, _)| comment));
+        self.indent += 1;
+        let inner = self.block(stmts, close.lo);
+        self.indent -= 1;
+        let text = match inner.is_empty() {
+            true => "{}".to_string(),
+            false => format!("{{\n{inner}{}}}", INDENT.repeat(self.indent)),
+        };
+        (text, close.hi)
+    }
+
+    fn statement(&mut self, stmt: &Expr) -> String {
+        match &stmt.node {
+            Expr_::If(branches) => {
+                let mut text = String::new();
+                let mut pos = stmt.span.lo;
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    // The condition of an else is a true which spans the whole if
+                    let (head, from) = match i {
+                        0 => (format!("if {} ", self.expr(cond)), cond.span.hi),
+                        _ if cond.span.lo == stmt.span.lo => (" else ".to_string(), pos),
+                        _ => (format!(" elif {} ", self.expr(cond)), cond.span.hi),
+                    };
+                    let (block, end) = self.braced(body, from);
+                    text.push_str(&head);
+                    text.push_str(&block);
+                    pos = end;
+                }
+                text
+            }
+            Expr_::For(var, list, body) => {
+                let list_text = self.expr(list);
+                let (block, _) = self.braced(body, list.span.hi);
+                format!("for {var} in {list_text} {block}")
+            }
+            Expr_::Def(name, params, body) => {
+                let (block, _) = self.braced(body, stmt.span.lo);
+                format!("def {name}({}) {block}", params.join(", "))
+            }
+            Expr_::Import(name) => format!("import {};", quote(name)),
+            Expr_::Return(e) => format!("return {};", self.expr(e)),
+            _ => format!("{};", self.expr(stmt)),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> String {
+        use Expr_::*;
+        match &e.node {
+            Or(a, b) => self.binary(a, "or", b, OR),
+            And(a, b) => self.binary(a, "and", b, AND),
+            Not(a) => format!("not {}", self.operand(a, NOT)),
+            Equal(a, b) => self.binary(a, "==", b, COMPARISON),
+            NotEqual(a, b) => self.binary(a, "!=", b, COMPARISON),
+            Less(a, b) => self.binary(a, "<", b, COMPARISON),
+            LessEqual(a, b) => self.binary(a, "<=", b, COMPARISON),
+            Greater(a, b) => self.binary(a, ">", b, COMPARISON),
+            GreaterEqual(a, b) => self.binary(a, ">=", b, COMPARISON),
+            Add(a, b) => self.binary(a, "+", b, SUM),
+            Sub(_, b) if is_negation(e) => format!("-{}", self.operand(b, NEGATION)),
+            Sub(a, b) => self.binary(a, "-", b, SUM),
+            Mul(a, b) => self.binary(a, "*", b, PRODUCT),
+            Div(a, b) => self.binary(a, "/", b, PRODUCT),
+            Mod(a, b) => self.binary(a, "%", b, PRODUCT),
+            Index(a, i) => format!("{}[{}]", self.operand(a, ATOM), self.expr(i)),
+            Function(name, args) => self.call(name, args),
+            Lambda(params, body) => {
+                let (block, _) = self.braced(body, e.span.lo);
+                format!("lambda({}) {block}", params.join(", "))
+            }
+            Assign(var, value) => format!("{var} = {}", self.expr(value)),
+            Var(var) => var.clone(),
+            Bool(b) => b.to_string(),
+            Number(n) => n.to_string(),
+            String(s) => quote(s),
+            List(items) => self.list(items),
+            Dict(map) => self.dict(map),
+            If(_) | For(..) | Def(..) | Import(_) | Return(_) => {
+                unreachable!("{:?} is only parsed as a statement", e.node)
+            }
+        }
+    }
+
+    fn list(&mut self, items: &[Expr]) -> String {
+        let items: Vec<(String, &Expr)> = items.iter().map(|item| (String::new(), item)).collect();
+        self.items("[", &items, "]")
+    }
+
+    fn dict(&mut self, map: &HashMap<String, Expr>) -> String {
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+        let items: Vec<(String, &Expr)> = keys
+            .into_iter()
+            .map(|key| (format!("{}: ", quote(key)), &map[key]))
+            .collect();
+        self.items("{", &items, "}")
+    }
+
+    fn binary(&mut self, lhs: &Expr, op: &str, rhs: &Expr, prec: u8) -> String {
+        // All binary operators are left associative
+        format!(
+            "{} {op} {}",
+            self.operand(lhs, prec),
+            self.operand(rhs, prec + 1)
+        )
+    }
+
+    /// Prints an expression, in parentheses if it binds looser than the precedence
+    fn operand(&mut self, e: &Expr, prec: u8) -> String {
+        match precedence(e) < prec {
+            true => format!("({})", self.expr(e)),
+            false => self.expr(e),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &Expr) -> String {
+        let Expr_::List(args) = &args.node else {
+            unreachable!("the arguments of a call are a list");
+        };
+        // A lambda as the last argument starts on the line of the call
+        if let Some((last, rest)) = args.split_last() {
+            if matches!(last.node, Expr_::Lambda(..)) && !rest.iter().any(has_lambda) {
+                let mut head = format!("{name}(");
+                for arg in rest {
+                    head.push_str(&self.flat_expr(arg));
+                    head.push_str(", ");
+                }
+                if self.fits(&head) {
+                    return format!("{head}{})", self.expr(last));
+                }
+            }
+        }
+        let items: Vec<(String, &Expr)> = args.iter().map(|arg| (String::new(), arg)).collect();
+        format!("{name}{}", self.items("(", &items, ")"))
+    }
+
+    /// Prints items with their prefixes between the brackets, on a single line if they fit
+    fn items(&mut self, open: &str, items: &[(String, &Expr)], close: &str) -> String {
+        if self.flat || !items.iter().any(|(_, item)| has_lambda(item)) {
+            let items: Vec<String> = items
+                .iter()
+                .map(|(prefix, item)| format!("{prefix}{}", self.flat_expr(item)))
+                .collect();
+            let text = format!("{open}{}{close}", items.join(", "));
+            if self.flat || self.fits(&text) {
+                return text;
+            }
+        }
+        let mut text = format!("{open}\n");
+        self.indent += 1;
+        for (i, (prefix, item)) in items.iter().enumerate() {
+            text.push_str(&INDENT.repeat(self.indent));
+            text.push_str(prefix);
+            text.push_str(&self.expr(item));
+            if i + 1 < items.len() {
+                text.push(',');
+            }
+            text.push('\n');
+        }
+        self.indent -= 1;
+        text.push_str(&INDENT.repeat(self.indent));
+        text.push_str(close);
+        text
+    }
+
+    /// Prints an expression without lambdas on a single line
+    fn flat_expr(&mut self, e: &Expr) -> String {
+        let flat = std::mem::replace(&mut self.flat, true);
+        let text = self.expr(e);
+        self.flat = flat;
+        text
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        self.indent * INDENT.len() + text.chars().count() <= MAX_WIDTH
+    }
+}
+
+/// Unary minus is parsed as a subtraction from a zero which spans the whole expression
+fn is_negation(e: &Expr) -> bool {
+    match &e.node {
+        Expr_::Sub(zero, _) => {
+            matches!(zero.node, Expr_::Number(n) if n == 0.0)
+                && zero.span.lo == e.span.lo
+                && zero.span.hi == e.span.hi
+        }
+        _ => false,
+    }
+}
+
+fn precedence(e: &Expr) -> u8 {
+    use Expr_::*;
+    match &e.node {
+        Or(..) => OR,
+        And(..) => AND,
+        Not(_) => NOT,
+        Equal(..) | NotEqual(..) | Less(..) | LessEqual(..) | Greater(..) | GreaterEqual(..) => {
+            COMPARISON
+        }
+        Sub(..) if is_negation(e) => NEGATION,
+        Add(..) | Sub(..) => SUM,
+        Mul(..) | Div(..) | Mod(..) => PRODUCT,
+        _ => ATOM,
+    }
+}
+
+/// Whether a lambda is part of the expression, these are never printed on a single line
+fn has_lambda(e: &Expr) -> bool {
+    use Expr_::*;
+    match &e.node {
+        Lambda(..) => true,
+        Add(a, b)
+        | Sub(a, b)
+        | Mul(a, b)
+        | Div(a, b)
+        | Mod(a, b)
+        | Equal(a, b)
+        | NotEqual(a, b)
+        | Less(a, b)
+        | LessEqual(a, b)
+        | Greater(a, b)
+        | GreaterEqual(a, b)
+        | And(a, b)
+        | Or(a, b)
+        | Index(a, b) => has_lambda(a) || has_lambda(b),
+        Not(a) | Function(_, a) | Assign(_, a) | Return(a) => has_lambda(a),
+        List(items) => items.iter().any(has_lambda),
+        Dict(map) => map.values().any(has_lambda),
+        Var(_) | Bool(_) | Number(_) | String(_) | Import(_) => false,
+        If(_) | For(..) | Def(..) => true,
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
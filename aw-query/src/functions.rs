@@ -1,3 +1,5 @@
+use crate::check::{Signature, Type};
+use crate::optimize;
 use crate::DataType;
 use crate::QueryError;
 use crate::VarEnv;
@@ -6,121 +8,414 @@ use aw_datastore::Datastore;
 pub type QueryFn =
     fn(args: Vec<DataType>, env: &VarEnv, ds: &Datastore) -> Result<DataType, QueryError>;
 
-pub fn fill_env(env: &mut VarEnv) {
-    env.insert(
-        "print".to_string(),
-        DataType::Function("print".to_string(), qfunctions::print),
-    );
-    env.insert(
-        "query_bucket".to_string(),
-        DataType::Function("query_bucket".to_string(), qfunctions::query_bucket),
-    );
-    env.insert(
-        "query_bucket_names".to_string(),
-        DataType::Function(
-            "query_bucket_names".to_string(),
+/// A builtin function, with the signature the static checker validates calls against
+pub struct Builtin {
+    pub name: &'static str,
+    pub fun: QueryFn,
+    pub signature: Signature,
+}
+
+fn builtin(name: &'static str, fun: QueryFn, signature: Signature) -> Builtin {
+    Builtin {
+        name,
+        fun,
+        signature,
+    }
+}
+
+pub fn builtins() -> Vec<Builtin> {
+    use crate::check::Type::{Any, Bool, Number};
+    let events = Type::events;
+    let list = || Type::list(Any);
+    vec![
+        builtin(
+            "print",
+            qfunctions::print,
+            Signature::new(vec![Any], Type::None).variadic(),
+        ),
+        builtin(
+            "query_bucket",
+            qfunctions::query_bucket,
+            Signature::new(vec![Type::String], events()),
+        ),
+        builtin(
+            "query_bucket_names",
             qfunctions::query_bucket_names,
+            Signature::new(vec![], Type::list(Type::String)),
         ),
-    );
-    env.insert(
-        "sort_by_duration".to_string(),
-        DataType::Function("sort_by_duration".to_string(), qfunctions::sort_by_duration),
-    );
-    env.insert(
-        "sort_by_timestamp".to_string(),
-        DataType::Function(
-            "sort_by_timestamp".to_string(),
+        builtin(
+            "sort_by_duration",
+            qfunctions::sort_by_duration,
+            Signature::new(vec![events()], events()),
+        ),
+        builtin(
+            "sort_by_timestamp",
             qfunctions::sort_by_timestamp,
+            Signature::new(vec![events()], events()),
+        ),
+        builtin(
+            "sum_durations",
+            qfunctions::sum_durations,
+            Signature::new(vec![events()], Number),
+        ),
+        builtin(
+            "count",
+            qfunctions::count,
+            Signature::new(vec![list()], Number),
+        ),
+        builtin(
+            "mean_duration",
+            qfunctions::mean_duration,
+            Signature::new(vec![events()], Any),
+        ),
+        builtin(
+            "median_duration",
+            qfunctions::median_duration,
+            Signature::new(vec![events()], Any),
+        ),
+        builtin(
+            "percentile",
+            qfunctions::percentile,
+            Signature::new(vec![events(), Number], Any),
+        ),
+        builtin(
+            "min_timestamp",
+            qfunctions::min_timestamp,
+            Signature::new(vec![events()], Any),
+        ),
+        builtin(
+            "max_timestamp",
+            qfunctions::max_timestamp,
+            Signature::new(vec![events()], Any),
         ),
-    );
-    env.insert(
-        "sum_durations".to_string(),
-        DataType::Function("sum_durations".to_string(), qfunctions::sum_durations),
-    );
-    env.insert(
-        "limit_events".to_string(),
-        DataType::Function("limit_events".to_string(), qfunctions::limit_events),
-    );
-    env.insert(
-        "contains".to_string(),
-        DataType::Function("contains".to_string(), qfunctions::contains),
-    );
-    env.insert(
-        "flood".to_string(),
-        DataType::Function("flood".to_string(), qfunctions::flood),
-    );
-    env.insert(
-        "find_bucket".to_string(),
-        DataType::Function("find_bucket".to_string(), qfunctions::find_bucket),
-    );
-    env.insert(
-        "merge_events_by_keys".to_string(),
-        DataType::Function(
-            "merge_events_by_keys".to_string(),
+        builtin(
+            "top_n",
+            qfunctions::top_n,
+            Signature::new(vec![events(), Type::String, Number], Type::list(Type::Dict)),
+        ),
+        builtin(
+            "now",
+            qfunctions::now,
+            Signature::new(vec![], Type::DateTime),
+        ),
+        builtin(
+            "interval_start",
+            qfunctions::interval_start,
+            Signature::new(vec![], Type::DateTime),
+        ),
+        builtin(
+            "interval_end",
+            qfunctions::interval_end,
+            Signature::new(vec![], Type::DateTime),
+        ),
+        builtin(
+            "datetime",
+            qfunctions::datetime,
+            Signature::new(vec![Type::String], Type::DateTime),
+        ),
+        builtin(
+            "duration",
+            qfunctions::duration,
+            Signature::new(vec![Any], Type::Duration),
+        ),
+        builtin(
+            "seconds",
+            qfunctions::seconds_of,
+            Signature::new(vec![Type::Duration], Number),
+        ),
+        builtin(
+            "filter_period",
+            qfunctions::filter_period,
+            Signature::new(vec![events(), Type::DateTime, Type::DateTime], events()),
+        ),
+        builtin(
+            "sessionize",
+            qfunctions::sessionize,
+            Signature::new(vec![events(), Any, Type::String], events()),
+        ),
+        builtin(
+            "limit_events",
+            qfunctions::limit_events,
+            Signature::new(vec![events(), Number], events()),
+        ),
+        builtin(
+            "contains",
+            qfunctions::contains,
+            Signature::new(vec![Any, Any], Bool),
+        ),
+        builtin(
+            "flood",
+            qfunctions::flood,
+            Signature::new(vec![events()], events()),
+        ),
+        builtin(
+            "find_bucket",
+            qfunctions::find_bucket,
+            Signature::new(vec![Type::String, Type::String], Type::String).optional(1),
+        ),
+        builtin(
+            "merge_events_by_keys",
             qfunctions::merge_events_by_keys,
+            Signature::new(vec![events(), Type::list(Type::String)], events()),
         ),
-    );
-    env.insert(
-        "chunk_events_by_key".to_string(),
-        DataType::Function(
-            "chunk_events_by_key".to_string(),
+        builtin(
+            "chunk_events_by_key",
             qfunctions::chunk_events_by_key,
+            Signature::new(vec![events(), Type::String], events()),
+        ),
+        builtin(
+            "exclude_keyvals",
+            qfunctions::exclude_keyvals,
+            Signature::new(vec![events(), Type::String, list()], events()),
         ),
-    );
-    env.insert(
-        "exclude_keyvals".to_string(),
-        DataType::Function("exclude_keyvals".to_string(), qfunctions::exclude_keyvals),
-    );
-    env.insert(
-        "filter_keyvals".to_string(),
-        DataType::Function("filter_keyvals".to_string(), qfunctions::filter_keyvals),
-    );
-    env.insert(
-        "filter_keyvals_regex".to_string(),
-        DataType::Function(
-            "filter_keyvals_regex".to_string(),
+        builtin(
+            "filter_keyvals",
+            qfunctions::filter_keyvals,
+            Signature::new(vec![events(), Type::String, list()], events()),
+        ),
+        builtin(
+            "filter_keyvals_regex",
             qfunctions::filter_keyvals_regex,
+            Signature::new(vec![events(), Type::String, Type::String], events()),
         ),
-    );
-    env.insert(
-        "filter_period_intersect".to_string(),
-        DataType::Function(
-            "filter_period_intersect".to_string(),
+        builtin(
+            "filter_period_intersect",
             qfunctions::filter_period_intersect,
+            Signature::new(vec![events(), events()], events()),
+        ),
+        builtin(
+            "split_url_events",
+            qfunctions::split_url_events,
+            Signature::new(vec![events()], events()),
+        ),
+        builtin(
+            "map_data",
+            qfunctions::map_data,
+            Signature::new(vec![events(), Type::Function(None)], events()),
+        ),
+        builtin(
+            "set_key",
+            qfunctions::set_key,
+            Signature::new(vec![events(), Type::String, Any], events()),
+        ),
+        builtin(
+            "rename_key",
+            qfunctions::rename_key,
+            Signature::new(vec![events(), Type::String, Type::String], events()),
+        ),
+        builtin(
+            "drop_keys",
+            qfunctions::drop_keys,
+            Signature::new(vec![events(), Type::list(Type::String)], events()),
+        ),
+        builtin(
+            "extract_regex",
+            qfunctions::extract_regex,
+            Signature::new(
+                vec![events(), Type::String, Type::String, Type::String, Number],
+                events(),
+            )
+            .optional(1),
+        ),
+        builtin(
+            "map_values",
+            qfunctions::map_values,
+            Signature::new(vec![events(), Type::String, Type::Dict], events()),
+        ),
+        builtin(
+            "concat",
+            qfunctions::concat,
+            Signature::new(vec![events()], events()).variadic(),
+        ),
+        builtin(
+            "categorize",
+            qfunctions::categorize,
+            Signature::new(vec![events(), list()], events()),
+        ),
+        builtin(
+            "tag",
+            qfunctions::tag,
+            Signature::new(vec![events(), list()], events()),
+        ),
+        builtin(
+            "category_tree",
+            qfunctions::category_tree,
+            Signature::new(vec![events(), list()], Type::Dict).optional(1),
+        ),
+        builtin(
+            "period_union",
+            qfunctions::period_union,
+            Signature::new(vec![events(), events()], events()),
+        ),
+        builtin(
+            "union_no_overlap",
+            qfunctions::union_no_overlap,
+            Signature::new(vec![events(), events()], events()),
+        ),
+        builtin(
+            "bin_by_time",
+            qfunctions::bin_by_time,
+            Signature::new(vec![events(), Type::String, Type::String], Type::Dict).optional(1),
+        ),
+        builtin(
+            "group_by_hour_of_day",
+            qfunctions::group_by_hour_of_day,
+            Signature::new(vec![events(), Type::String], Type::Dict).optional(1),
+        ),
+        builtin(
+            "group_by_weekday",
+            qfunctions::group_by_weekday,
+            Signature::new(vec![events(), Type::String], Type::Dict).optional(1),
+        ),
+        builtin(
+            "focus_metrics",
+            qfunctions::focus_metrics,
+            Signature::new(vec![events(), Type::String, Type::String], Type::Dict).optional(1),
+        ),
+        builtin(
+            "split_by_day",
+            qfunctions::split_by_day,
+            Signature::new(vec![events(), Type::String, Type::String], events()).optional(1),
+        ),
+        builtin(
+            "map",
+            qfunctions::map,
+            Signature::new(vec![list(), Type::Function(None)], list()),
+        ),
+        builtin(
+            "filter",
+            qfunctions::filter,
+            Signature::new(vec![list(), Type::Function(None)], list()),
+        ),
+        builtin(
+            "reduce",
+            qfunctions::reduce,
+            Signature::new(vec![list(), Type::Function(None), Any], Any),
+        ),
+        builtin("len", qfunctions::len, Signature::new(vec![Any], Number)),
+        builtin(
+            "str",
+            qfunctions::str,
+            Signature::new(vec![Any], Type::String),
+        ),
+        builtin(
+            "lower",
+            qfunctions::lower,
+            Signature::new(vec![Type::String], Type::String),
+        ),
+        builtin(
+            "upper",
+            qfunctions::upper,
+            Signature::new(vec![Type::String], Type::String),
+        ),
+        builtin(
+            "strip",
+            qfunctions::strip,
+            Signature::new(vec![Type::String], Type::String),
+        ),
+        builtin(
+            "split",
+            qfunctions::split,
+            Signature::new(vec![Type::String, Type::String], Type::list(Type::String)),
+        ),
+        builtin(
+            "join",
+            qfunctions::join,
+            Signature::new(vec![Type::list(Type::String), Type::String], Type::String),
+        ),
+        builtin(
+            "replace",
+            qfunctions::replace,
+            Signature::new(vec![Type::String, Type::String, Type::String], Type::String),
+        ),
+        builtin(
+            "startswith",
+            qfunctions::startswith,
+            Signature::new(vec![Type::String, Type::String], Bool),
+        ),
+        builtin(
+            "endswith",
+            qfunctions::endswith,
+            Signature::new(vec![Type::String, Type::String], Bool),
+        ),
+        builtin(
+            "regex_group",
+            qfunctions::regex_group,
+            Signature::new(vec![Type::String, Type::String, Number], Any).optional(1),
+        ),
+        builtin(
+            "slice",
+            qfunctions::slice,
+            Signature::new(vec![Any, Number, Number], Any).optional(1),
+        ),
+        builtin(
+            "sorted",
+            qfunctions::sorted,
+            Signature::new(vec![list()], list()),
         ),
-    );
-    env.insert(
-        "split_url_events".to_string(),
-        DataType::Function("split_url_events".to_string(), qfunctions::split_url_events),
-    );
-    env.insert(
-        "concat".to_string(),
-        DataType::Function("concat".to_string(), qfunctions::concat),
-    );
-    env.insert(
-        "categorize".to_string(),
-        DataType::Function("categorize".into(), qfunctions::categorize),
-    );
-    env.insert(
-        "tag".to_string(),
-        DataType::Function("tag".into(), qfunctions::tag),
-    );
-    env.insert(
-        "period_union".to_string(),
-        DataType::Function("period_union".into(), qfunctions::period_union),
-    );
-    env.insert(
-        "union_no_overlap".to_string(),
-        DataType::Function("union_no_overlap".into(), qfunctions::union_no_overlap),
-    );
+        builtin(
+            "reversed",
+            qfunctions::reversed,
+            Signature::new(vec![list()], list()),
+        ),
+        builtin(
+            "keys",
+            qfunctions::keys,
+            Signature::new(vec![Type::Dict], Type::list(Type::String)),
+        ),
+        builtin(
+            "values",
+            qfunctions::values,
+            Signature::new(vec![Type::Dict], list()),
+        ),
+        builtin(
+            "get",
+            qfunctions::get,
+            Signature::new(vec![Type::Dict, Type::String, Any], Any).optional(1),
+        ),
+        builtin(
+            "merge",
+            qfunctions::merge,
+            Signature::new(vec![Type::Dict, Type::Dict], Type::Dict),
+        ),
+    ]
+}
+
+pub fn fill_env(env: &mut VarEnv) {
+    for builtin in builtins() {
+        env.insert(
+            builtin.name.to_string(),
+            DataType::Function(builtin.name.to_string(), builtin.fun),
+        );
+    }
+    // The functions which the optimizer rewrites calls to, their names can't be written in
+    // a query so they can't be called or overwritten directly
+    let rewritten: [(&str, QueryFn); 2] = [
+        (
+            optimize::QUERY_BUCKET_FILTER_KEYVALS,
+            qfunctions::query_bucket_filter_keyvals,
+        ),
+        (
+            optimize::QUERY_BUCKET_FILTER_PERIOD_INTERSECT,
+            qfunctions::query_bucket_filter_period_intersect,
+        ),
+    ];
+    for (name, fun) in rewritten {
+        env.insert(name.to_string(), DataType::Function(name.to_string(), fun));
+    }
 }
 
 mod qfunctions {
-    use aw_datastore::Datastore;
-    use aw_models::Event;
+    use std::collections::HashMap;
+
+    use aw_datastore::{Datastore, DatastoreError};
+    use aw_models::{Class, Event};
     use aw_transform::classify::Rule;
+    use chrono::{SecondsFormat, Weekday};
 
     use super::validate;
+    use crate::datatype::format_datetime;
+    use crate::interpret::call_function;
     use crate::DataType;
     use crate::QueryError;
     use crate::VarEnv;
@@ -146,13 +441,111 @@ mod qfunctions {
 
         let bucket_id: String = args.into_iter().next().unwrap().try_into()?;
         let interval = validate::get_timeinterval(env)?;
+        let events = load_events(&bucket_id, env, |limit| {
+            ds.get_events(
+                &bucket_id,
+                Some(*interval.start()),
+                Some(*interval.end()),
+                limit,
+            )
+        })?;
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
 
-        let events = match ds.get_events(
-            bucket_id.as_str(),
-            Some(*interval.start()),
-            Some(*interval.end()),
-            None,
-        ) {
+    /// `filter_keyvals(query_bucket(bucket), key, values)` with string values, as rewritten by
+    /// the optimizer. The datastore only returns the events which match.
+    pub fn query_bucket_filter_keyvals(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let bucket_id: String = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let values: Vec<String> = args.next().unwrap().try_into()?;
+        let interval = validate::get_timeinterval(env)?;
+        let events = load_events(&bucket_id, env, |limit| {
+            ds.get_events_with_keyvals(
+                &bucket_id,
+                Some(*interval.start()),
+                Some(*interval.end()),
+                limit,
+                &key,
+                &values,
+            )
+        })?;
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    /// `filter_period_intersect(query_bucket(bucket), filter_events)`, as rewritten by the
+    /// optimizer. Only the events in the time spanned by the filter events are loaded.
+    pub fn query_bucket_filter_period_intersect(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let bucket_id: String = args.next().unwrap().try_into()?;
+        let filter_events: Vec<Event> = args.next().unwrap().try_into()?;
+        let interval = validate::get_timeinterval(env)?;
+        let (start, end) = (*interval.start(), *interval.end());
+
+        // The part of the timeperiod spanned by the filter events
+        let window_start = filter_events.iter().map(|e| e.timestamp).min();
+        let window_end = filter_events.iter().map(|e| e.calculate_endtime()).max();
+        let window = match (window_start, window_end) {
+            (Some(window_start), Some(window_end)) => {
+                Some((window_start.max(start), window_end.min(end)))
+            }
+            _ => None,
+        };
+        let events = match window.filter(|(window_start, window_end)| window_start <= window_end) {
+            Some((window_start, window_end)) => {
+                let events = load_events(&bucket_id, env, |limit| {
+                    ds.get_events_unclipped(&bucket_id, Some(window_start), Some(window_end), limit)
+                })?;
+                // Clipped to the timeperiod like query_bucket does, rather than to the
+                // window, so that events are sorted in the same order as without the rewrite
+                events
+                    .into_iter()
+                    .map(|mut e| {
+                        let event_end = e.calculate_endtime().min(end);
+                        e.timestamp = e.timestamp.max(start);
+                        e.duration = event_end - e.timestamp;
+                        e
+                    })
+                    .collect()
+            }
+            // Nothing can intersect, but the bucket must still exist
+            _ => load_events(&bucket_id, env, |_| {
+                ds.get_events(&bucket_id, Some(start), Some(end), Some(0))
+            })?,
+        };
+
+        let filtered_events = aw_transform::filter_period_intersect(events, filter_events);
+        Ok(DataType::List(
+            filtered_events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    /// Loads events of a bucket with a fetch function which is given the max number of
+    /// events to load, and fails if the query's limit of events per bucket is exceeded
+    fn load_events(
+        bucket_id: &str,
+        env: &VarEnv,
+        fetch: impl FnOnce(Option<u64>) -> Result<Vec<Event>, DatastoreError>,
+    ) -> Result<Vec<Event>, QueryError> {
+        env.dependencies().buckets.insert(bucket_id.to_string());
+
+        // Loads one event more than the limit, to tell whether it was exceeded
+        let max_events = env.options().max_bucket_events;
+        let events = match fetch(max_events.map(|max| max as u64 + 1)) {
             Ok(events) => events,
             Err(e) => {
                 return Err(QueryError::BucketQueryError(format!(
@@ -160,19 +553,23 @@ mod qfunctions {
                 )))
             }
         };
-        let mut ret = Vec::new();
-        for event in events {
-            ret.push(DataType::Event(event));
+        if let Some(max) = max_events {
+            if events.len() > max {
+                return Err(QueryError::EventLimitError(format!(
+                    "Bucket {bucket_id} has more than the limit of {max} events in the timeperiod"
+                )));
+            }
         }
-        Ok(DataType::List(ret))
+        Ok(events)
     }
 
     pub fn query_bucket_names(
         args: Vec<DataType>,
-        _env: &VarEnv,
+        env: &VarEnv,
         ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         validate::args_length(&args, 0)?;
+        env.dependencies().bucket_list = true;
         let mut bucketnames: Vec<DataType> = Vec::new();
         let buckets = match ds.get_buckets() {
             Ok(buckets) => buckets,
@@ -190,7 +587,7 @@ mod qfunctions {
 
     pub fn find_bucket(
         args: Vec<DataType>,
-        _env: &VarEnv,
+        env: &VarEnv,
         ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         validate::args_length(&args, 1).or_else(|_| validate::args_length(&args, 2))?;
@@ -202,6 +599,7 @@ mod qfunctions {
             None => None,
         };
 
+        env.dependencies().bucket_list = true;
         let buckets = match ds.get_buckets() {
             Ok(buckets) => buckets,
             Err(e) => {
@@ -316,6 +714,69 @@ mod qfunctions {
         Ok(DataType::List(tagged_flooded_events))
     }
 
+    /// The time of categorized events summed per category, with the time of subcategories
+    /// included in their parents. Takes the classes in the format of the classes setting to
+    /// also sum the time weighted by the scores of the categories.
+    pub fn category_tree(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1).or_else(|_| validate::args_length(&args, 2))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let classes: Option<Vec<Class>> = match args.next() {
+            Some(classes) => {
+                let classes: serde_json::Value = classes.try_into()?;
+                Some(
+                    serde_json::from_value(integral_numbers(classes)).map_err(|e| {
+                        QueryError::InvalidFunctionParameters(format!("Invalid classes: {e}"))
+                    })?,
+                )
+            }
+            None => None,
+        };
+        let tree = aw_transform::category_tree(&events, classes.as_deref());
+        Ok(category_node(tree))
+    }
+
+    /// Numbers in queries are floats, which serde won't deserialize into integers such as the
+    /// ids and scores of classes, so the whole ones are turned into integers
+    fn integral_numbers(value: serde_json::Value) -> serde_json::Value {
+        use serde_json::Value;
+        match value {
+            Value::Number(n) => match n.as_f64() {
+                Some(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Value::from(f as i64),
+                _ => Value::Number(n),
+            },
+            Value::Array(values) => {
+                Value::Array(values.into_iter().map(integral_numbers).collect())
+            }
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(k, v)| (k, integral_numbers(v)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    fn category_node(node: aw_transform::CategoryNode) -> DataType {
+        let mut dict = HashMap::new();
+        dict.insert("duration".to_string(), seconds(node.duration));
+        if let Some(score) = node.score {
+            dict.insert("score".to_string(), DataType::Number(score));
+        }
+        let children = node
+            .children
+            .into_iter()
+            .map(|(name, child)| (name, category_node(child)))
+            .collect();
+        dict.insert("children".to_string(), DataType::Dict(children));
+        DataType::Dict(dict)
+    }
+
     pub fn sort_by_duration(
         args: Vec<DataType>,
         _env: &VarEnv,
@@ -394,151 +855,509 @@ mod qfunctions {
         ))
     }
 
-    pub fn merge_events_by_keys(
+    pub fn count(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 2)?;
-        let mut args = args.into_iter();
-        let events: Vec<Event> = args.next().unwrap().try_into()?;
-        let keys: Vec<String> = args.next().unwrap().try_into()?;
+        validate::args_length(&args, 1)?;
+        let list: Vec<DataType> = args.into_iter().next().unwrap().try_into()?;
+        Ok(DataType::Number(list.len() as f64))
+    }
 
-        let mut merged_events = aw_transform::merge_events_by_keys(events, keys);
-        let mut merged_tagged_events = Vec::new();
-        for event in merged_events.drain(..) {
-            merged_tagged_events.push(DataType::Event(event));
+    /// Seconds of a duration, or None for the statistics of no events
+    fn optional_seconds(duration: Option<chrono::Duration>) -> DataType {
+        match duration {
+            Some(duration) => seconds(duration),
+            None => DataType::None(),
         }
-        Ok(DataType::List(merged_tagged_events))
     }
 
-    pub fn chunk_events_by_key(
+    pub fn mean_duration(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 2)?;
-        let mut args = args.into_iter();
-        let events: Vec<Event> = args.next().unwrap().try_into()?;
-        let key: String = args.next().unwrap().try_into()?;
-
-        let mut merged_events = aw_transform::chunk_events_by_key(events, &key);
-        let mut merged_tagged_events = Vec::new();
-        for event in merged_events.drain(..) {
-            merged_tagged_events.push(DataType::Event(event));
-        }
-        Ok(DataType::List(merged_tagged_events))
+        validate::args_length(&args, 1)?;
+        let events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+        Ok(optional_seconds(aw_transform::mean_duration(&events)))
     }
 
-    pub fn filter_keyvals(
+    pub fn median_duration(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 3)?;
-        let mut args = args.into_iter();
-        let events = args.next().unwrap().try_into()?;
-        let key: String = args.next().unwrap().try_into()?;
-        let vals: Vec<_> = args.next().unwrap().try_into()?;
-
-        let mut filtered_events = aw_transform::filter_keyvals(events, &key, &vals);
-        let mut filtered_tagged_events = Vec::new();
-        for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
-        }
-        Ok(DataType::List(filtered_tagged_events))
+        validate::args_length(&args, 1)?;
+        let events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+        Ok(optional_seconds(aw_transform::median_duration(&events)))
     }
 
-    use fancy_regex::RegexBuilder;
-
-    pub fn filter_keyvals_regex(
+    pub fn percentile(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 3)?;
+        validate::args_length(&args, 2)?;
         let mut args = args.into_iter();
-        let events = args.next().unwrap().try_into()?;
-        let key: String = args.next().unwrap().try_into()?;
-        let regex_str: String = args.next().unwrap().try_into()?;
-        let regex = match RegexBuilder::new(&regex_str).build() {
-            Ok(regex) => regex,
-            Err(e) => {
-                return Err(QueryError::RegexCompileError(format!(
-                    "Failed to compile regex string '{regex_str}': {e}"
-                )))
-            }
-        };
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let percentile: f64 = args.next().unwrap().try_into()?;
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "percentile has to be between 0 and 100, got {percentile}"
+            )));
+        }
+        Ok(optional_seconds(aw_transform::percentile_duration(
+            &events, percentile,
+        )))
+    }
 
-        let mut filtered_events = aw_transform::filter_keyvals_regex(events, &key, &regex);
-        let mut filtered_tagged_events = Vec::new();
-        for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
+    fn optional_timestamp(timestamp: Option<chrono::DateTime<chrono::Utc>>) -> DataType {
+        match timestamp {
+            Some(timestamp) => DataType::DateTime(timestamp),
+            None => DataType::None(),
         }
-        Ok(DataType::List(filtered_tagged_events))
     }
 
-    pub fn exclude_keyvals(
+    pub fn min_timestamp(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 3)?;
-        let mut args = args.into_iter();
-        let events = args.next().unwrap().try_into()?;
-        let key: String = args.next().unwrap().try_into()?;
-        let vals: Vec<_> = args.next().unwrap().try_into()?;
-
-        let mut filtered_events = aw_transform::exclude_keyvals(events, &key, &vals);
-        let mut filtered_tagged_events = Vec::new();
-        for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
-        }
-        Ok(DataType::List(filtered_tagged_events))
+        validate::args_length(&args, 1)?;
+        let events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+        Ok(optional_timestamp(aw_transform::min_timestamp(&events)))
     }
 
-    pub fn filter_period_intersect(
+    pub fn max_timestamp(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 2)?;
-        let mut args = args.into_iter();
-        let events: Vec<Event> = args.next().unwrap().try_into()?;
-        let filter_events: Vec<Event> = args.next().unwrap().try_into()?;
+        validate::args_length(&args, 1)?;
+        let events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+        Ok(optional_timestamp(aw_transform::max_timestamp(&events)))
+    }
 
-        let mut filtered_events = aw_transform::filter_period_intersect(events, filter_events);
-        let mut filtered_tagged_events = Vec::new();
-        for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
-        }
-        Ok(DataType::List(filtered_tagged_events))
+    pub fn now(args: Vec<DataType>, env: &VarEnv, _ds: &Datastore) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 0)?;
+        env.dependencies().now = true;
+        Ok(DataType::DateTime(chrono::Utc::now()))
     }
 
-    pub fn split_url_events(
+    pub fn interval_start(
         args: Vec<DataType>,
-        _env: &VarEnv,
+        env: &VarEnv,
         _ds: &Datastore,
     ) -> Result<DataType, QueryError> {
         // typecheck
-        validate::args_length(&args, 1)?;
-        let mut events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+        validate::args_length(&args, 0)?;
+        let interval = validate::get_timeinterval(env)?;
+        Ok(DataType::DateTime(*interval.start()))
+    }
 
-        let mut tagged_split_url_events = Vec::new();
-        for mut event in events.drain(..) {
-            aw_transform::split_url_event(&mut event);
-            tagged_split_url_events.push(DataType::Event(event));
-        }
-        Ok(DataType::List(tagged_split_url_events))
+    pub fn interval_end(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 0)?;
+        let interval = validate::get_timeinterval(env)?;
+        Ok(DataType::DateTime(*interval.end()))
     }
 
-    pub fn concat(
+    /// Parses an RFC3339 timestamp such as "2000-01-01T00:00:00+01:00"
+    pub fn datetime(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let s: String = args.into_iter().next().unwrap().try_into()?;
+        match chrono::DateTime::parse_from_rfc3339(&s) {
+            Ok(t) => Ok(DataType::DateTime(t.with_timezone(&chrono::Utc))),
+            Err(e) => Err(QueryError::InvalidFunctionParameters(format!(
+                "Invalid datetime '{s}', expected an RFC3339 timestamp: {e}"
+            ))),
+        }
+    }
+
+    /// A duration from a string such as "2h" or from a number of seconds
+    pub fn duration(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let duration = validate::get_duration(args.into_iter().next().unwrap())?;
+        Ok(DataType::Duration(duration))
+    }
+
+    pub fn seconds_of(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let duration: chrono::Duration = args.into_iter().next().unwrap().try_into()?;
+        Ok(seconds(duration))
+    }
+
+    pub fn filter_period(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let start: chrono::DateTime<chrono::Utc> = args.next().unwrap().try_into()?;
+        let end: chrono::DateTime<chrono::Utc> = args.next().unwrap().try_into()?;
+        let events = aw_transform::filter_period(events, start, end);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    /// One event per session of activity, see aw_transform::sessionize
+    pub fn sessionize(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let gap = validate::get_duration(args.next().unwrap())?;
+        let key: String = args.next().unwrap().try_into()?;
+        if gap < chrono::Duration::zero() {
+            return Err(QueryError::InvalidFunctionParameters(
+                "The gap between sessions can't be negative".to_string(),
+            ));
+        }
+        let sessions = aw_transform::sessionize(events, gap, &key);
+        Ok(DataType::List(
+            sessions.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn top_n(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let n: usize = args.next().unwrap().try_into()?;
+
+        let top = aw_transform::top_n(&events, &key, n)
+            .into_iter()
+            .map(|(value, duration)| {
+                let mut entry = HashMap::new();
+                entry.insert("value".to_string(), DataType::from(value));
+                entry.insert("duration".to_string(), seconds(duration));
+                DataType::Dict(entry)
+            })
+            .collect();
+        Ok(DataType::List(top))
+    }
+
+    pub fn merge_events_by_keys(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let keys: Vec<String> = args.next().unwrap().try_into()?;
+
+        let mut merged_events = aw_transform::merge_events_by_keys(events, keys);
+        let mut merged_tagged_events = Vec::new();
+        for event in merged_events.drain(..) {
+            merged_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(merged_tagged_events))
+    }
+
+    pub fn chunk_events_by_key(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+
+        let mut merged_events = aw_transform::chunk_events_by_key(events, &key);
+        let mut merged_tagged_events = Vec::new();
+        for event in merged_events.drain(..) {
+            merged_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(merged_tagged_events))
+    }
+
+    pub fn filter_keyvals(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let vals: Vec<_> = args.next().unwrap().try_into()?;
+
+        let mut filtered_events = aw_transform::filter_keyvals(events, &key, &vals);
+        let mut filtered_tagged_events = Vec::new();
+        for event in filtered_events.drain(..) {
+            filtered_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(filtered_tagged_events))
+    }
+
+    use fancy_regex::RegexBuilder;
+
+    pub fn filter_keyvals_regex(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let regex_str: String = args.next().unwrap().try_into()?;
+        let regex = match RegexBuilder::new(&regex_str).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                return Err(QueryError::RegexCompileError(format!(
+                    "Failed to compile regex string '{regex_str}': {e}"
+                )))
+            }
+        };
+
+        let mut filtered_events = aw_transform::filter_keyvals_regex(events, &key, &regex);
+        let mut filtered_tagged_events = Vec::new();
+        for event in filtered_events.drain(..) {
+            filtered_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(filtered_tagged_events))
+    }
+
+    pub fn exclude_keyvals(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let vals: Vec<_> = args.next().unwrap().try_into()?;
+
+        let mut filtered_events = aw_transform::exclude_keyvals(events, &key, &vals);
+        let mut filtered_tagged_events = Vec::new();
+        for event in filtered_events.drain(..) {
+            filtered_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(filtered_tagged_events))
+    }
+
+    pub fn filter_period_intersect(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let filter_events: Vec<Event> = args.next().unwrap().try_into()?;
+
+        let mut filtered_events = aw_transform::filter_period_intersect(events, filter_events);
+        let mut filtered_tagged_events = Vec::new();
+        for event in filtered_events.drain(..) {
+            filtered_tagged_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(filtered_tagged_events))
+    }
+
+    pub fn split_url_events(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let mut events: Vec<Event> = args.into_iter().next().unwrap().try_into()?;
+
+        let mut tagged_split_url_events = Vec::new();
+        for mut event in events.drain(..) {
+            aw_transform::split_url_event(&mut event);
+            tagged_split_url_events.push(DataType::Event(event));
+        }
+        Ok(DataType::List(tagged_split_url_events))
+    }
+
+    /// Replaces the data of each event with what the function returns when called with it
+    pub fn map_data(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let fun = args.next().unwrap();
+
+        let events = aw_transform::map_data(events, |data| {
+            let data = DataType::from(serde_json::Value::Object(data));
+            match call_function(&fun, vec![data], env, ds)? {
+                DataType::Dict(d) => d
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, value.try_into()?)))
+                    .collect(),
+                invalid_type => Err(QueryError::InvalidType(format!(
+                    "function passed to map_data must return a dict, got {invalid_type:?}"
+                ))),
+            }
+        })?;
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn set_key(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let value: serde_json::Value = args.next().unwrap().try_into()?;
+
+        let events = aw_transform::set_key(events, &key, &value);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn rename_key(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let from: String = args.next().unwrap().try_into()?;
+        let to: String = args.next().unwrap().try_into()?;
+
+        let events = aw_transform::rename_key(events, &from, &to);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn drop_keys(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let keys: Vec<String> = args.next().unwrap().try_into()?;
+
+        let events = aw_transform::drop_keys(events, &keys);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn extract_regex(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 4).or_else(|_| validate::args_length(&args, 5))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let source: String = args.next().unwrap().try_into()?;
+        let regex_str: String = args.next().unwrap().try_into()?;
+        let target: String = args.next().unwrap().try_into()?;
+        let group: usize = match args.next() {
+            Some(group) => group.try_into()?,
+            None => 0,
+        };
+        let regex = match RegexBuilder::new(&regex_str).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                return Err(QueryError::RegexCompileError(format!(
+                    "Failed to compile regex string '{regex_str}': {e}"
+                )))
+            }
+        };
+
+        let events = aw_transform::extract_regex(events, &source, &regex, &target, group);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn map_values(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let mapping: HashMap<String, DataType> = args.next().unwrap().try_into()?;
+        let mut json_mapping = HashMap::with_capacity(mapping.len());
+        for (from, to) in mapping {
+            json_mapping.insert(from, to.try_into()?);
+        }
+
+        let events = aw_transform::map_values(events, &key, &json_mapping);
+        Ok(DataType::List(
+            events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn concat(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
@@ -550,10 +1369,355 @@ mod qfunctions {
                 event_list.push(DataType::Event(event));
             }
         }
-        Ok(DataType::List(event_list))
+        Ok(DataType::List(event_list))
+    }
+
+    pub fn period_union(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events1: Vec<Event> = args.next().unwrap().try_into()?;
+        let events2: Vec<Event> = args.next().unwrap().try_into()?;
+
+        let mut result = aw_transform::period_union(&events1, &events2);
+        let mut result_tagged = Vec::new();
+        for event in result.drain(..) {
+            result_tagged.push(DataType::Event(event));
+        }
+        Ok(DataType::List(result_tagged))
+    }
+
+    pub fn union_no_overlap(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let events1: Vec<Event> = args.next().unwrap().try_into()?;
+        let events2: Vec<Event> = args.next().unwrap().try_into()?;
+
+        let mut result = aw_transform::union_no_overlap(events1, events2);
+        let mut result_tagged = Vec::new();
+        for event in result.drain(..) {
+            result_tagged.push(DataType::Event(event));
+        }
+        Ok(DataType::List(result_tagged))
+    }
+
+    /// The most bins bin_by_time returns, so that a small bin size can't use up all memory
+    const MAX_BINS: i64 = 100_000;
+
+    fn seconds(duration: chrono::Duration) -> DataType {
+        DataType::Number((duration.num_milliseconds() as f64) / 1000.0)
+    }
+
+    pub fn bin_by_time(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let size: String = args.next().unwrap().try_into()?;
+        let size = validate::parse_duration(&size)?;
+        let start_of_day = validate::get_start_of_day(args.next(), env, ds)?;
+        let interval = validate::get_timeinterval(env)?;
+
+        let bin_count = interval.duration().num_seconds() / size.num_seconds().max(1);
+        if bin_count > MAX_BINS {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "bin_by_time would return {bin_count} bins, more than the limit of {MAX_BINS}"
+            )));
+        }
+        // Every bin of the timeperiod is included, also the ones without any events
+        let period = Event {
+            id: None,
+            timestamp: *interval.start(),
+            duration: interval.duration(),
+            data: serde_json::Map::new(),
+        };
+        let mut bins = aw_transform::bin_by_time(&[period], size, start_of_day);
+        bins.values_mut()
+            .for_each(|duration| *duration = chrono::Duration::zero());
+        bins.extend(aw_transform::bin_by_time(&events, size, start_of_day));
+
+        let bins: HashMap<String, DataType> = bins
+            .into_iter()
+            .map(|(start, duration)| {
+                (
+                    start.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    seconds(duration),
+                )
+            })
+            .collect();
+        Ok(DataType::Dict(bins))
+    }
+
+    pub fn group_by_hour_of_day(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1).or_else(|_| validate::args_length(&args, 2))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let start_of_day = validate::get_start_of_day(args.next(), env, ds)?;
+
+        let hours = aw_transform::group_by_hour_of_day(&events, start_of_day);
+        let hours: HashMap<String, DataType> = hours
+            .into_iter()
+            .enumerate()
+            .map(|(hour, duration)| (hour.to_string(), seconds(duration)))
+            .collect();
+        Ok(DataType::Dict(hours))
+    }
+
+    pub fn group_by_weekday(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1).or_else(|_| validate::args_length(&args, 2))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let start_of_day = validate::get_start_of_day(args.next(), env, ds)?;
+
+        let days = aw_transform::group_by_weekday(&events, start_of_day);
+        let mut weekday = Weekday::Mon;
+        let mut result = HashMap::new();
+        for duration in days {
+            result.insert(weekday.to_string(), seconds(duration));
+            weekday = weekday.succ();
+        }
+        Ok(DataType::Dict(result))
+    }
+
+    /// How focused the events are on the values of a key: the switches between values in
+    /// total and per hour, the longest focus period of each value and how many focus periods
+    /// lasted how long, in bins starting at the `from` seconds
+    pub fn focus_metrics(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let start_of_day = validate::get_start_of_day(args.next(), env, ds)?;
+
+        let switches = aw_transform::switches_per_hour(&events, &key, start_of_day);
+        let total: usize = switches.values().sum();
+        let switches: HashMap<String, DataType> = switches
+            .into_iter()
+            .map(|(hour, count)| {
+                (
+                    hour.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    DataType::Number(count as f64),
+                )
+            })
+            .collect();
+        let longest = aw_transform::longest_focus(&events, &key)
+            .into_iter()
+            .map(|mut period| {
+                let mut entry = HashMap::new();
+                let value = period.data.remove(&key).unwrap_or_default();
+                entry.insert("value".to_string(), DataType::from(value));
+                entry.insert(
+                    "timestamp".to_string(),
+                    DataType::DateTime(period.timestamp),
+                );
+                entry.insert("duration".to_string(), seconds(period.duration));
+                DataType::Dict(entry)
+            })
+            .collect();
+        let dwell_times = aw_transform::dwell_time_distribution(&events, &key)
+            .into_iter()
+            .zip(aw_transform::DWELL_TIME_BINS)
+            .map(|(count, from)| {
+                let mut bin = HashMap::new();
+                bin.insert("from".to_string(), DataType::Number(from as f64));
+                bin.insert("count".to_string(), DataType::Number(count as f64));
+                DataType::Dict(bin)
+            })
+            .collect();
+
+        let mut result = HashMap::new();
+        result.insert("switches".to_string(), DataType::Number(total as f64));
+        result.insert("switches_per_hour".to_string(), DataType::Dict(switches));
+        result.insert("longest_focus".to_string(), DataType::List(longest));
+        result.insert("dwell_times".to_string(), DataType::List(dwell_times));
+        Ok(DataType::Dict(result))
+    }
+
+    pub fn split_by_day(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let events: Vec<Event> = args.next().unwrap().try_into()?;
+        let tz: String = args.next().unwrap().try_into()?;
+        let tz = validate::parse_timezone(&tz)?;
+        let start_of_day = validate::get_start_of_day(args.next(), env, ds)?;
+
+        let split_events = aw_transform::split_by_day(events, tz, start_of_day);
+        Ok(DataType::List(
+            split_events.into_iter().map(DataType::Event).collect(),
+        ))
+    }
+
+    pub fn map(args: Vec<DataType>, env: &VarEnv, ds: &Datastore) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let list: Vec<DataType> = args.next().unwrap().try_into()?;
+        let fun = args.next().unwrap();
+
+        let mut mapped = Vec::with_capacity(list.len());
+        for item in list {
+            mapped.push(call_function(&fun, vec![item], env, ds)?);
+        }
+        Ok(DataType::List(mapped))
+    }
+
+    pub fn filter(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let list: Vec<DataType> = args.next().unwrap().try_into()?;
+        let fun = args.next().unwrap();
+
+        let mut filtered = Vec::new();
+        for item in list {
+            // The item is cloned so it can be kept if the predicate matches
+            match call_function(&fun, vec![item.clone()], env, ds)? {
+                DataType::Bool(true) => filtered.push(item),
+                DataType::Bool(false) => (),
+                invalid_type => {
+                    return Err(QueryError::InvalidType(format!(
+                        "function passed to filter must return a bool, got {invalid_type:?}"
+                    )))
+                }
+            }
+        }
+        Ok(DataType::List(filtered))
     }
 
-    pub fn period_union(
+    pub fn reduce(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let list: Vec<DataType> = args.next().unwrap().try_into()?;
+        let fun = args.next().unwrap();
+        let mut acc = args.next().unwrap();
+
+        for item in list {
+            acc = call_function(&fun, vec![acc, item], env, ds)?;
+        }
+        Ok(acc)
+    }
+
+    pub fn len(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let len = match args.first().unwrap() {
+            DataType::String(s) => s.chars().count(),
+            DataType::List(l) => l.len(),
+            DataType::Dict(d) => d.len(),
+            invalid_type => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "function len got {invalid_type:?}, expected type String, List or Dict"
+                )))
+            }
+        };
+        Ok(DataType::Number(len as f64))
+    }
+
+    /// Strings are returned as they are, anything else is converted to JSON
+    pub fn str(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        match args.into_iter().next().unwrap() {
+            DataType::String(s) => Ok(DataType::String(s)),
+            DataType::DateTime(t) => Ok(DataType::String(format_datetime(&t))),
+            // Whole numbers without the trailing .0
+            DataType::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => {
+                Ok(DataType::String((n as i64).to_string()))
+            }
+            value => match serde_json::to_string(&value) {
+                Ok(s) => Ok(DataType::String(s)),
+                Err(e) => Err(QueryError::InvalidFunctionParameters(format!(
+                    "function str can't convert {value:?}: {e}"
+                ))),
+            },
+        }
+    }
+
+    /// Applies a function to the string argument of a function taking a single string
+    fn map_string(
+        args: Vec<DataType>,
+        f: impl FnOnce(&str) -> String,
+    ) -> Result<DataType, QueryError> {
+        validate::args_length(&args, 1)?;
+        let s: String = args.into_iter().next().unwrap().try_into()?;
+        Ok(DataType::String(f(&s)))
+    }
+
+    pub fn lower(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        map_string(args, str::to_lowercase)
+    }
+
+    pub fn upper(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        map_string(args, str::to_uppercase)
+    }
+
+    pub fn strip(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        map_string(args, |s| s.trim().to_string())
+    }
+
+    pub fn split(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
@@ -561,18 +1725,18 @@ mod qfunctions {
         // typecheck
         validate::args_length(&args, 2)?;
         let mut args = args.into_iter();
-        let events1: Vec<Event> = args.next().unwrap().try_into()?;
-        let events2: Vec<Event> = args.next().unwrap().try_into()?;
-
-        let mut result = aw_transform::period_union(&events1, &events2);
-        let mut result_tagged = Vec::new();
-        for event in result.drain(..) {
-            result_tagged.push(DataType::Event(event));
+        let s: String = args.next().unwrap().try_into()?;
+        let sep: String = args.next().unwrap().try_into()?;
+        if sep.is_empty() {
+            return Err(QueryError::InvalidFunctionParameters(
+                "function split got an empty separator".to_string(),
+            ));
         }
-        Ok(DataType::List(result_tagged))
+        let parts = s.split(&sep).map(|part| DataType::String(part.to_string()));
+        Ok(DataType::List(parts.collect()))
     }
 
-    pub fn union_no_overlap(
+    pub fn join(
         args: Vec<DataType>,
         _env: &VarEnv,
         _ds: &Datastore,
@@ -580,21 +1744,236 @@ mod qfunctions {
         // typecheck
         validate::args_length(&args, 2)?;
         let mut args = args.into_iter();
-        let events1: Vec<Event> = args.next().unwrap().try_into()?;
-        let events2: Vec<Event> = args.next().unwrap().try_into()?;
+        let parts: Vec<String> = args.next().unwrap().try_into()?;
+        let sep: String = args.next().unwrap().try_into()?;
+        Ok(DataType::String(parts.join(&sep)))
+    }
 
-        let mut result = aw_transform::union_no_overlap(events1, events2);
-        let mut result_tagged = Vec::new();
-        for event in result.drain(..) {
-            result_tagged.push(DataType::Event(event));
+    pub fn replace(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args.into_iter();
+        let s: String = args.next().unwrap().try_into()?;
+        let from: String = args.next().unwrap().try_into()?;
+        let to: String = args.next().unwrap().try_into()?;
+        Ok(DataType::String(s.replace(&from, &to)))
+    }
+
+    pub fn startswith(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let s: String = args.next().unwrap().try_into()?;
+        let prefix: String = args.next().unwrap().try_into()?;
+        Ok(DataType::Bool(s.starts_with(&prefix)))
+    }
+
+    pub fn endswith(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let s: String = args.next().unwrap().try_into()?;
+        let suffix: String = args.next().unwrap().try_into()?;
+        Ok(DataType::Bool(s.ends_with(&suffix)))
+    }
+
+    /// The text matched by a group of a regex, the whole match by default. None if the regex
+    /// or the group did not match.
+    pub fn regex_group(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let s: String = args.next().unwrap().try_into()?;
+        let regex_str: String = args.next().unwrap().try_into()?;
+        let group: usize = match args.next() {
+            Some(group) => group.try_into()?,
+            None => 0,
+        };
+        let regex = match RegexBuilder::new(&regex_str).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                return Err(QueryError::RegexCompileError(format!(
+                    "Failed to compile regex string '{regex_str}': {e}"
+                )))
+            }
+        };
+        let captures = match regex.captures(&s) {
+            Ok(captures) => captures,
+            Err(e) => {
+                return Err(QueryError::RegexCompileError(format!(
+                    "Failed to run regex '{regex_str}': {e}"
+                )))
+            }
+        };
+        match captures.and_then(|c| c.get(group)) {
+            Some(m) => Ok(DataType::String(m.as_str().to_string())),
+            None => Ok(DataType::None()),
         }
-        Ok(DataType::List(result_tagged))
+    }
+
+    /// The items of a list or the characters of a string from start up to but not including
+    /// end. Like in Python, negative positions count from the end and positions past the end
+    /// are clamped.
+    pub fn slice(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let value = args.next().unwrap();
+        let start: f64 = args.next().unwrap().try_into()?;
+        let end: Option<f64> = match args.next() {
+            Some(end) => Some(end.try_into()?),
+            None => None,
+        };
+        let clamp = |pos: f64, len: usize| {
+            let pos = if pos < 0.0 { len as f64 + pos } else { pos };
+            pos.clamp(0.0, len as f64) as usize
+        };
+        match value {
+            DataType::List(l) => {
+                let (start, end) = (
+                    clamp(start, l.len()),
+                    clamp(end.unwrap_or(f64::MAX), l.len()),
+                );
+                Ok(DataType::List(
+                    l.into_iter().take(end).skip(start).collect(),
+                ))
+            }
+            DataType::String(s) => {
+                let len = s.chars().count();
+                let (start, end) = (clamp(start, len), clamp(end.unwrap_or(f64::MAX), len));
+                Ok(DataType::String(s.chars().take(end).skip(start).collect()))
+            }
+            invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
+                "function slice got {invalid_type:?}, expected type List or String"
+            ))),
+        }
+    }
+
+    /// Sorts a list of numbers, strings or bools in ascending order
+    pub fn sorted(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let mut list: Vec<DataType> = args.into_iter().next().unwrap().try_into()?;
+        // Fails on the first pair of items which can't be ordered
+        let mut error = None;
+        list.sort_by(|a, b| match a.query_cmp(b) {
+            Ok(ordering) => ordering,
+            Err(e) => {
+                error.get_or_insert(e);
+                std::cmp::Ordering::Equal
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(DataType::List(list)),
+        }
+    }
+
+    pub fn reversed(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let mut list: Vec<DataType> = args.into_iter().next().unwrap().try_into()?;
+        list.reverse();
+        Ok(DataType::List(list))
+    }
+
+    /// The keys of a dict in sorted order
+    pub fn keys(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let dict: HashMap<String, DataType> = args.into_iter().next().unwrap().try_into()?;
+        let mut keys: Vec<String> = dict.into_keys().collect();
+        keys.sort();
+        Ok(DataType::List(
+            keys.into_iter().map(DataType::String).collect(),
+        ))
+    }
+
+    /// The values of a dict, in the sorted order of their keys
+    pub fn values(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let dict: HashMap<String, DataType> = args.into_iter().next().unwrap().try_into()?;
+        let mut entries: Vec<(String, DataType)> = dict.into_iter().collect();
+        entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        Ok(DataType::List(
+            entries.into_iter().map(|(_, v)| v).collect(),
+        ))
+    }
+
+    /// The value of a key in a dict, or the default if it's missing, which is None unless given
+    pub fn get(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2).or_else(|_| validate::args_length(&args, 3))?;
+        let mut args = args.into_iter();
+        let mut dict: HashMap<String, DataType> = args.next().unwrap().try_into()?;
+        let key: String = args.next().unwrap().try_into()?;
+        let default = args.next().unwrap_or(DataType::None());
+        Ok(dict.remove(&key).unwrap_or(default))
+    }
+
+    /// A new dict with the keys of both dicts, the second one's values take precedence
+    pub fn merge(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let mut args = args.into_iter();
+        let mut dict: HashMap<String, DataType> = args.next().unwrap().try_into()?;
+        let other: HashMap<String, DataType> = args.next().unwrap().try_into()?;
+        dict.extend(other);
+        Ok(DataType::Dict(dict))
     }
 }
 
 mod validate {
+    use crate::dependencies::SETTINGS_KEY_PREFIX;
     use crate::{DataType, QueryError, VarEnv};
+    use aw_datastore::{Datastore, DatastoreError};
     use aw_models::TimeInterval;
+    use chrono::Duration;
 
     pub fn args_length(args: &[DataType], len: usize) -> Result<(), QueryError> {
         if args.len() != len {
@@ -630,4 +2009,100 @@ mod validate {
             ))),
         }
     }
+
+    /// A duration from a duration, a string such as "15m" or a number of seconds
+    pub fn get_duration(arg: DataType) -> Result<Duration, QueryError> {
+        match arg {
+            DataType::String(s) => parse_duration(&s),
+            DataType::Number(n) => {
+                let ms = (n * 1000.0).round();
+                match ms.is_finite() && ms.abs() < i64::MAX as f64 {
+                    true => Ok(Duration::milliseconds(ms as i64)),
+                    false => Err(QueryError::InvalidFunctionParameters(format!(
+                        "Invalid duration of {n} seconds"
+                    ))),
+                }
+            }
+            DataType::Duration(d) => Ok(d),
+            invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected a duration, a string or a number of seconds, got {invalid_type:?}"
+            ))),
+        }
+    }
+
+    /// Parses a duration such as "30s", "15m", "1h", "1d" or "1w"
+    pub fn parse_duration(s: &str) -> Result<Duration, QueryError> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (count, unit) = s.split_at(split);
+        let duration = match (count.parse::<i64>(), unit) {
+            (Ok(n), "s") => Duration::try_seconds(n),
+            (Ok(n), "m" | "min") => Duration::try_minutes(n),
+            (Ok(n), "h") => Duration::try_hours(n),
+            (Ok(n), "d") => Duration::try_days(n),
+            (Ok(n), "w") => Duration::try_weeks(n),
+            _ => None,
+        };
+        match duration {
+            Some(duration) if duration > Duration::zero() => Ok(duration),
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Invalid duration '{s}', expected a positive number followed by s, m, h, d or w"
+            ))),
+        }
+    }
+
+    /// Parses a time of day in the "HH:MM" format of the start_of_day setting, as the
+    /// duration since midnight
+    pub fn parse_time_of_day(s: &str) -> Result<Duration, QueryError> {
+        let parsed = s
+            .split_once(':')
+            .and_then(|(h, m)| Some((h.parse::<i64>().ok()?, m.parse::<i64>().ok()?)));
+        match parsed {
+            Some((h, m)) if (0..24).contains(&h) && (0..60).contains(&m) => {
+                Ok(Duration::hours(h) + Duration::minutes(m))
+            }
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Invalid time of day '{s}', expected HH:MM"
+            ))),
+        }
+    }
+
+    /// Parses an IANA timezone name such as "Europe/Stockholm"
+    pub fn parse_timezone(s: &str) -> Result<chrono_tz::Tz, QueryError> {
+        s.parse().map_err(|_| {
+            QueryError::InvalidFunctionParameters(format!(
+                "Unknown timezone '{s}', expected an IANA timezone name such as 'Europe/Stockholm'"
+            ))
+        })
+    }
+
+    /// The start of the day from an optional argument, or else from the start_of_day
+    /// setting, which is midnight if it's not set
+    pub fn get_start_of_day(
+        arg: Option<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<Duration, QueryError> {
+        if let Some(arg) = arg {
+            let start_of_day: String = arg.try_into()?;
+            return parse_time_of_day(&start_of_day);
+        }
+        env.dependencies()
+            .settings
+            .insert("start_of_day".to_string());
+        let value = match ds.get_key_value(&format!("{SETTINGS_KEY_PREFIX}start_of_day")) {
+            Ok(value) => value,
+            Err(DatastoreError::NoSuchKey(_)) => return Ok(Duration::zero()),
+            Err(e) => {
+                return Err(QueryError::BucketQueryError(format!(
+                    "Failed to get the start_of_day setting: {e:?}"
+                )))
+            }
+        };
+        match serde_json::from_str::<String>(&value) {
+            Ok(start_of_day) => parse_time_of_day(&start_of_day),
+            Err(e) => Err(QueryError::InvalidFunctionParameters(format!(
+                "Invalid start_of_day setting: {e}"
+            ))),
+        }
+    }
 }
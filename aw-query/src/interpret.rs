@@ -1,219 +1,787 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
 
 use crate::functions;
+use crate::library;
 
 use aw_datastore::Datastore;
 use aw_models::TimeInterval;
 
 use crate::ast::*;
+use crate::datatype::{duration_seconds, Lambda};
+use crate::dependencies::Dependencies;
+use crate::lexer::Span;
+use crate::profile::{self, DataTypeStats, Measurement, Profiler};
 use crate::DataType;
 use crate::QueryError;
+use crate::QueryOptions;
+
+/// Max number of loop iterations and lambda calls in a single query
+const MAX_ITERATIONS: usize = 10_000_000;
+/// Max number of nested lambda calls, stops runaway recursion before the stack overflows
+const MAX_CALL_DEPTH: usize = 32;
+
+/// The variables visible to the code being interpreted.
+///
+/// Calling a lambda creates a new scope on top of the global scope, which also holds the
+/// variables the lambda captured where it was created. Variables are looked up in the
+/// scope, then in the captured variables and then in the global scope, while assignments
+/// always go to the scope, so a lambda can read but never overwrite other variables.
+/// Like in Python global variables are read when the lambda is called, so a lambda can use
+/// functions defined after it, while the variables of enclosing calls are captured when
+/// the lambda is created. `for` loops do not create a scope, the loop variable and
+/// assignments in the loop body are still visible after the loop.
+pub struct VarEnv<'a> {
+    vars: HashMap<String, DataType>,
+    captured: Option<Arc<HashMap<String, DataType>>>,
+    parent: Option<&'a VarEnv<'a>>,
+    depth: usize,
+    // Only used in the root scope, shared by all scopes through root()
+    iterations: Cell<usize>,
+    error_span: Cell<Option<Span>>,
+    profiler: Option<RefCell<Profiler>>,
+    options: QueryOptions,
+    dependencies: RefCell<Dependencies>,
+    // The functions of each imported library, which the functions of the library see instead
+    // of the variables of the query they were called from
+    libraries: RefCell<Vec<Arc<HashMap<String, DataType>>>>,
+}
+
+impl VarEnv<'static> {
+    pub fn new() -> VarEnv<'static> {
+        VarEnv {
+            vars: HashMap::new(),
+            captured: None,
+            parent: None,
+            depth: 0,
+            iterations: Cell::new(0),
+            error_span: Cell::new(None),
+            profiler: None,
+            options: QueryOptions::default(),
+            dependencies: RefCell::default(),
+            libraries: RefCell::default(),
+        }
+    }
+}
+
+impl<'a> VarEnv<'a> {
+    /// The scope of a lambda call from a scope at the depth of the caller
+    fn new_scope(
+        globals: &'a VarEnv<'a>,
+        caller_depth: usize,
+        captured: Arc<HashMap<String, DataType>>,
+    ) -> Result<VarEnv<'a>, QueryError> {
+        if caller_depth >= MAX_CALL_DEPTH {
+            return Err(QueryError::RecursionLimitError(format!(
+                "Exceeded the max call depth of {MAX_CALL_DEPTH}"
+            )));
+        }
+        Ok(VarEnv {
+            vars: HashMap::new(),
+            captured: Some(captured),
+            parent: Some(globals),
+            depth: caller_depth + 1,
+            iterations: Cell::new(0),
+            error_span: Cell::new(None),
+            profiler: None,
+            options: QueryOptions::default(),
+            dependencies: RefCell::default(),
+            libraries: RefCell::default(),
+        })
+    }
+
+    pub fn get(&self, var: &str) -> Option<&DataType> {
+        self.vars
+            .get(var)
+            .or_else(|| self.captured.as_ref()?.get(var))
+            .or_else(|| self.parent?.get(var))
+    }
+
+    /// Looks up a variable like get, but not in the global scope. Only gives the variables
+    /// of lambda calls, which the lambdas created in them capture.
+    fn get_local(&self, var: &str) -> Option<&DataType> {
+        self.parent?;
+        self.vars
+            .get(var)
+            .or_else(|| self.captured.as_ref()?.get(var))
+    }
+
+    pub fn insert(&mut self, var: String, val: DataType) {
+        self.vars.insert(var, val);
+    }
+
+    fn remove(&mut self, var: &str) -> Option<DataType> {
+        self.vars.remove(var)
+    }
+
+    fn root(&self) -> &VarEnv<'a> {
+        let mut env = self;
+        while let Some(parent) = env.parent {
+            env = parent;
+        }
+        env
+    }
+
+    fn count_iteration(&self) -> Result<(), QueryError> {
+        let iterations = &self.root().iterations;
+        if iterations.get() >= MAX_ITERATIONS {
+            return Err(QueryError::IterationLimitError(format!(
+                "Exceeded the max of {MAX_ITERATIONS} loop iterations and function calls"
+            )));
+        }
+        iterations.set(iterations.get() + 1);
+        Ok(())
+    }
+
+    pub(crate) fn options(&self) -> &QueryOptions {
+        &self.root().options
+    }
+
+    /// Checks for cancellation and the time limit. The interpreter calls this before every
+    /// expression, but a single long running function call can't be interrupted.
+    fn check_limits(&self) -> Result<(), QueryError> {
+        let options = self.options();
+        if let Some(cancel) = &options.cancel {
+            if cancel.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(QueryError::Cancelled());
+            }
+        }
+        if let Some(deadline) = options.deadline {
+            if Instant::now() >= deadline {
+                return Err(QueryError::TimeLimitError(
+                    "The query did not finish within its time limit".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records what the query read from the datastore
+    pub(crate) fn dependencies(&self) -> std::cell::RefMut<'_, Dependencies> {
+        self.root().dependencies.borrow_mut()
+    }
+
+    fn profiler(&self) -> Option<&RefCell<Profiler>> {
+        self.root().profiler.as_ref()
+    }
+
+    /// Remembers the span of the innermost expression which failed, the expressions
+    /// enclosing it fail as well when the error propagates but should not overwrite it
+    fn mark_error(&self, span: Span) {
+        let error_span = &self.root().error_span;
+        if error_span.get().is_none() {
+            error_span.set(Some(span));
+        }
+    }
+}
 
-pub type VarEnv = HashMap<String, DataType>;
+impl Default for VarEnv<'static> {
+    fn default() -> Self {
+        VarEnv::new()
+    }
+}
 
-fn init_env(ti: &TimeInterval) -> VarEnv {
-    let mut env = HashMap::new();
+fn init_env(ti: &TimeInterval) -> VarEnv<'static> {
+    let mut env = VarEnv::new();
     env.insert("TIMEINTERVAL".to_string(), DataType::String(ti.to_string()));
     functions::fill_env(&mut env);
     env
 }
 
+/// Calls a builtin function or a lambda with already evaluated arguments
+pub(crate) fn call_function(
+    fun: &DataType,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ds: &Datastore,
+) -> Result<DataType, QueryError> {
+    match fun {
+        DataType::Function(_name, fun) => fun(args, env, ds),
+        DataType::Lambda(lambda) => call_lambda(lambda, args, env, ds),
+        invalid_type => Err(QueryError::InvalidType(format!(
+            "Expected a function, got {invalid_type:?}"
+        ))),
+    }
+}
+
+fn call_lambda(
+    lambda: &Lambda,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ds: &Datastore,
+) -> Result<DataType, QueryError> {
+    if args.len() != lambda.params.len() {
+        return Err(QueryError::InvalidFunctionParameters(format!(
+            "Expected {} parameters in lambda, got {}",
+            lambda.params.len(),
+            args.len()
+        )));
+    }
+    env.count_iteration()?;
+    let captured = match lambda.library {
+        Some(i) => Arc::clone(&env.root().libraries.borrow()[i]),
+        None => Arc::clone(&lambda.captured),
+    };
+    let mut scope = VarEnv::new_scope(env.root(), env.depth, captured)?;
+    if let Some(name) = &lambda.name {
+        if scope.get(name).is_none() {
+            scope.insert(name.clone(), DataType::Lambda(lambda.clone()));
+        }
+    }
+    for (param, arg) in lambda.params.iter().zip(args) {
+        scope.insert(param.clone(), arg);
+    }
+    let profiler = env.profiler().filter(|_| lambda.library.is_some());
+    if let Some(profiler) = profiler {
+        profiler.borrow_mut().suspend();
+    }
+    let res = interpret_block(&mut scope, ds, &lambda.body);
+    if let Some(profiler) = profiler {
+        profiler.borrow_mut().resume();
+    }
+    if let Err(e) = res {
+        // Spans in an imported library point into the code of the library rather than
+        // the query, so the error is reported at the call site in the query instead
+        if lambda.library.is_some() {
+            env.root().error_span.set(None);
+        }
+        return Err(e);
+    }
+    Ok(scope.remove("RETURN").unwrap_or(DataType::None()))
+}
+
+/// Creates a lambda which captures the variables of the enclosing lambda calls which its
+/// body uses
+fn new_lambda(
+    env: &VarEnv,
+    params: &[String],
+    body: &Arc<Vec<Expr>>,
+    name: Option<&String>,
+) -> Lambda {
+    let mut names = HashSet::new();
+    for expr in body.iter() {
+        collect_names(expr, &mut names);
+    }
+    let captured = names
+        .into_iter()
+        .filter(|name| !params.contains(name))
+        .filter_map(|name| {
+            let value = env.get_local(name)?.clone();
+            Some((name.clone(), value))
+        })
+        .collect();
+    Lambda {
+        params: params.to_vec(),
+        body: Arc::clone(body),
+        captured: Arc::new(captured),
+        name: name.cloned(),
+        library: None,
+    }
+}
+
+/// The names of the variables and functions used in an expression
+fn collect_names<'e>(expr: &'e Expr, names: &mut HashSet<&'e String>) {
+    use Expr_::*;
+    match &expr.node {
+        Var(name) => {
+            names.insert(name);
+        }
+        Function(name, args) => {
+            names.insert(name);
+            collect_names(args, names);
+        }
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b) => {
+            collect_names(a, names);
+            collect_names(b, names);
+        }
+        Equal(a, b) | NotEqual(a, b) | Less(a, b) | LessEqual(a, b) => {
+            collect_names(a, names);
+            collect_names(b, names);
+        }
+        Greater(a, b) | GreaterEqual(a, b) | And(a, b) | Or(a, b) | Index(a, b) => {
+            collect_names(a, names);
+            collect_names(b, names);
+        }
+        Not(e) | Assign(_, e) | Return(e) => collect_names(e, names),
+        For(_, list, block) => {
+            collect_names(list, names);
+            block.iter().for_each(|e| collect_names(e, names));
+        }
+        If(ifs) => {
+            for (cond, block) in ifs {
+                collect_names(cond, names);
+                block.iter().for_each(|e| collect_names(e, names));
+            }
+        }
+        Lambda(_, body) | Def(_, _, body) => body.iter().for_each(|e| collect_names(e, names)),
+        List(items) => items.iter().for_each(|e| collect_names(e, names)),
+        Dict(d) => d.values().for_each(|e| collect_names(e, names)),
+        Import(_) | Bool(_) | Number(_) | String(_) => (),
+    }
+}
+
+/// The result of a program, together with its profile and what it read from the datastore
+pub type Interpreted = (DataType, Option<Profiler>, Dependencies);
+
+/// Runs a program, on failure the error is returned together with the span of the
+/// expression which caused it. When profiling, the profiler is returned on success.
 pub fn interpret_prog(
-    p: Program,
+    p: &Program,
     ti: &TimeInterval,
     ds: &Datastore,
-) -> Result<DataType, QueryError> {
+    options: &QueryOptions,
+) -> Result<Interpreted, (QueryError, Option<Span>)> {
     let mut env = init_env(ti);
-    for expr in p.stmts {
-        interpret_expr(&mut env, ds, expr)?;
+    env.options = options.clone();
+    if options.explain {
+        env.profiler = Some(RefCell::new(Profiler::default()));
+    }
+    if let Err(e) = interpret_block(&mut env, ds, &p.stmts) {
+        return Err((e, env.error_span.get()));
+    }
+    let ret = match env.remove("RETURN") {
+        Some(ret) => ret,
+        None => return Err((QueryError::EmptyQuery(), None)),
+    };
+    // Results are serialized while they are sent, by then it's too late to fail
+    if let Some(kind) = unserializable(&ret) {
+        return Err((
+            QueryError::InvalidType(format!(
+                "The query returned a {kind}, which cannot be serialized, call it to get a value"
+            )),
+            None,
+        ));
+    }
+    if let Some(max) = options.max_output_bytes {
+        if !fits_in_json(&ret, max) {
+            return Err((
+                QueryError::OutputLimitError(format!(
+                    "The result of the query is larger than the limit of {max} bytes"
+                )),
+                None,
+            ));
+        }
+    }
+    Ok((
+        ret,
+        env.profiler.map(RefCell::into_inner),
+        env.dependencies.into_inner(),
+    ))
+}
+
+/// What in the value can't be serialized, if anything
+fn unserializable(data: &DataType) -> Option<&'static str> {
+    match data {
+        DataType::Function(..) => Some("function"),
+        DataType::Lambda(_) => Some("lambda"),
+        DataType::List(l) => l.iter().find_map(unserializable),
+        DataType::Dict(d) => d.values().find_map(unserializable),
+        _ => None,
+    }
+}
+
+/// Whether the value serialized as JSON fits in max bytes. Serialization stops as soon as
+/// the limit is passed, so the check is cheap even for very large values.
+fn fits_in_json(data: &DataType, max: usize) -> bool {
+    struct Counter {
+        bytes: usize,
+        max: usize,
+    }
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.bytes += buf.len();
+            if self.bytes > self.max {
+                return Err(std::io::Error::other("output limit exceeded"));
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut counter = Counter { bytes: 0, max };
+    let _ = serde_json::to_writer(&mut counter, data);
+    counter.bytes <= max
+}
+
+fn interpret_block(env: &mut VarEnv, ds: &Datastore, block: &[Expr]) -> Result<(), QueryError> {
+    for expr in block {
+        match env.profiler().is_some() {
+            true => profile_statement(env, ds, expr)?,
+            false => {
+                interpret_expr(env, ds, expr)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn profile_statement(env: &mut VarEnv, ds: &Datastore, expr: &Expr) -> Result<(), QueryError> {
+    let mut vars = Vec::new();
+    profile::vars_read(expr, &mut vars);
+    let input = vars
+        .iter()
+        .filter_map(|var| env.get(var))
+        .map(profile::count_events)
+        .sum();
+    let start = Instant::now();
+    interpret_expr(env, ds, expr)?;
+    let time = start.elapsed();
+    let output = match &expr.node {
+        Expr_::Assign(var, _) => env.get(var),
+        Expr_::Return(_) => env.get("RETURN"),
+        _ => None,
+    };
+    let m = Measurement {
+        time,
+        input,
+        output: output.map(DataTypeStats::of),
+    };
+    if let Some(profiler) = env.profiler() {
+        profiler.borrow_mut().record_statement(expr.span, m);
+    }
+    Ok(())
+}
+
+fn profile_call(
+    name: &str,
+    fun: &DataType,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ds: &Datastore,
+    span: Span,
+) -> Result<DataType, QueryError> {
+    let input = args.iter().map(profile::count_events).sum();
+    let start = Instant::now();
+    let res = call_function(fun, args, env, ds)?;
+    let m = Measurement {
+        time: start.elapsed(),
+        input,
+        output: Some(DataTypeStats::of(&res)),
+    };
+    if let Some(profiler) = env.profiler() {
+        profiler.borrow_mut().record_call(name, span, m);
     }
-    match env.remove("RETURN") {
-        Some(ret) => Ok(ret),
-        None => Err(QueryError::EmptyQuery()),
+    Ok(res)
+}
+
+fn add(a_res: DataType, b_res: DataType) -> Result<DataType, QueryError> {
+    let res = match a_res {
+        DataType::DateTime(t) => match b_res {
+            DataType::Duration(d) => DataType::DateTime(add_duration(t, d)?),
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on a datetime with something that is not a duration!".to_string(),
+                ))
+            }
+        },
+        DataType::Duration(d1) => {
+            match b_res {
+                DataType::Duration(d2) => DataType::Duration(checked(d1.checked_add(&d2))?),
+                DataType::DateTime(t) => DataType::DateTime(add_duration(t, d1)?),
+                _ => return Err(QueryError::InvalidType(
+                    "Cannot use + on a duration with something that is not a duration or datetime!"
+                        .to_string(),
+                )),
+            }
+        }
+        DataType::Number(n1) => match b_res {
+            DataType::Number(n2) => DataType::Number(n1 + n2),
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a number with a number!".to_string(),
+                ))
+            }
+        },
+        DataType::List(mut l1) => match b_res {
+            DataType::List(mut l2) => {
+                l1.append(&mut l2);
+                DataType::List(l1)
+            }
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a list with a list!".to_string(),
+                ))
+            }
+        },
+        DataType::String(s1) => match b_res {
+            DataType::String(s2) => {
+                let mut new_string = s1;
+                new_string.push_str(&s2);
+                DataType::String(new_string)
+            }
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a list with a list!".to_string(),
+                ))
+            }
+        },
+        _ => return Err(QueryError::InvalidType(
+            "Cannot use + on something that is not a number, list, string, datetime or duration!"
+                .to_string(),
+        )),
+    };
+    Ok(res)
+}
+
+fn sub(a_res: DataType, b_res: DataType) -> Result<DataType, QueryError> {
+    let res = match (a_res, b_res) {
+        (DataType::Number(n1), DataType::Number(n2)) => DataType::Number(n1 - n2),
+        (DataType::DateTime(t), DataType::Duration(d)) => {
+            DataType::DateTime(checked(t.checked_sub_signed(d))?)
+        }
+        (DataType::DateTime(t1), DataType::DateTime(t2)) => DataType::Duration(t1 - t2),
+        (DataType::Duration(d1), DataType::Duration(d2)) => {
+            DataType::Duration(checked(d1.checked_sub(&d2))?)
+        }
+        (a, b) => {
+            return Err(QueryError::InvalidType(format!(
+                "Cannot use - on {a:?} and {b:?}"
+            )))
+        }
+    };
+    Ok(res)
+}
+
+fn mul(a_res: DataType, b_res: DataType) -> Result<DataType, QueryError> {
+    let res = match (a_res, b_res) {
+        (DataType::Number(n1), DataType::Number(n2)) => DataType::Number(n1 * n2),
+        (DataType::Duration(d), DataType::Number(n))
+        | (DataType::Number(n), DataType::Duration(d)) => DataType::Duration(scale_duration(d, n)?),
+        (a, b) => {
+            return Err(QueryError::InvalidType(format!(
+                "Cannot use * on {a:?} and {b:?}"
+            )))
+        }
+    };
+    Ok(res)
+}
+
+fn div(a_res: DataType, b_res: DataType) -> Result<DataType, QueryError> {
+    let res = match (a_res, b_res) {
+        (DataType::Number(_) | DataType::Duration(_), DataType::Number(0.0)) => {
+            return Err(QueryError::MathError(
+                "Tried to divide by zero!".to_string(),
+            ))
+        }
+        (DataType::Number(n1), DataType::Number(n2)) => DataType::Number(n1 / n2),
+        (DataType::Duration(d), DataType::Number(n)) => {
+            DataType::Duration(scale_duration(d, 1.0 / n)?)
+        }
+        (DataType::Duration(d1), DataType::Duration(d2)) => {
+            let divisor = duration_seconds(d2);
+            if divisor == 0.0 {
+                return Err(QueryError::MathError(
+                    "Tried to divide by zero!".to_string(),
+                ));
+            }
+            DataType::Number(duration_seconds(d1) / divisor)
+        }
+        (a, b) => {
+            return Err(QueryError::InvalidType(format!(
+                "Cannot use / on {a:?} and {b:?}"
+            )))
+        }
+    };
+    Ok(res)
+}
+
+fn checked<T>(res: Option<T>) -> Result<T, QueryError> {
+    res.ok_or_else(|| QueryError::MathError("Datetime or duration out of range!".to_string()))
+}
+
+fn add_duration(t: DateTime<Utc>, d: Duration) -> Result<DateTime<Utc>, QueryError> {
+    checked(t.checked_add_signed(d))
+}
+
+/* Durations are scaled in milliseconds, the precision of durations in queries */
+fn scale_duration(d: Duration, factor: f64) -> Result<Duration, QueryError> {
+    let ms = (d.num_milliseconds() as f64 * factor).round();
+    if !ms.is_finite() || ms.abs() >= i64::MAX as f64 {
+        return checked(None);
     }
+    checked(Duration::try_milliseconds(ms as i64))
 }
 
-fn interpret_expr(
-    env: &mut HashMap<String, DataType>,
+/* Evaluates both operands of a binary operator.
+ * Kept out of interpret_expr to keep its stack frame small, since it recurses. */
+fn interpret_operands(
+    env: &mut VarEnv,
     ds: &Datastore,
-    expr: Expr,
+    a: &Expr,
+    b: &Expr,
+) -> Result<(DataType, DataType), QueryError> {
+    let a_res = interpret_expr(env, ds, a)?;
+    let b_res = interpret_expr(env, ds, b)?;
+    Ok((a_res, b_res))
+}
+
+/* Evaluates both operands of an arithmetic operator which only works on numbers.
+ * Kept out of interpret_expr to keep its stack frame small, since it recurses. */
+fn interpret_numbers(
+    env: &mut VarEnv,
+    ds: &Datastore,
+    a: &Expr,
+    b: &Expr,
+) -> Result<(f64, f64), QueryError> {
+    let a_res = interpret_expr(env, ds, a)?;
+    let b_res = interpret_expr(env, ds, b)?;
+    let a_num = match a_res {
+        DataType::Number(n) => n,
+        _ => {
+            return Err(QueryError::InvalidType(
+                "Cannot sub something that is not a number!".to_string(),
+            ))
+        }
+    };
+    let b_num = match b_res {
+        DataType::Number(n) => n,
+        _ => {
+            return Err(QueryError::InvalidType(
+                "Cannot sub something that is not a number!".to_string(),
+            ))
+        }
+    };
+    Ok((a_num, b_num))
+}
+
+fn interpret_cmp(
+    env: &mut VarEnv,
+    ds: &Datastore,
+    lhs: &Expr,
+    rhs: &Expr,
+) -> Result<Ordering, QueryError> {
+    let lhs_res = interpret_expr(env, ds, lhs)?;
+    let rhs_res = interpret_expr(env, ds, rhs)?;
+    lhs_res.query_cmp(&rhs_res)
+}
+
+fn interpret_bool(
+    env: &mut VarEnv,
+    ds: &Datastore,
+    expr: &Expr,
+    op: &str,
+) -> Result<bool, QueryError> {
+    match interpret_expr(env, ds, expr)? {
+        DataType::Bool(b) => Ok(b),
+        other => Err(QueryError::InvalidType(format!(
+            "Cannot use {op} on something that is not a bool, got {other:?}"
+        ))),
+    }
+}
+
+fn interpret_index(
+    env: &mut VarEnv,
+    ds: &Datastore,
+    value: &Expr,
+    index: &Expr,
 ) -> Result<DataType, QueryError> {
+    let value = interpret_expr(env, ds, value)?;
+    let index = interpret_expr(env, ds, index)?;
+    value.query_index(&index)
+}
+
+fn interpret_expr(env: &mut VarEnv, ds: &Datastore, expr: &Expr) -> Result<DataType, QueryError> {
+    let res = env
+        .check_limits()
+        .and_then(|_| interpret_node(env, ds, expr));
+    if res.is_err() {
+        env.mark_error(expr.span);
+    }
+    res
+}
+
+fn interpret_node(env: &mut VarEnv, ds: &Datastore, expr: &Expr) -> Result<DataType, QueryError> {
     use crate::ast::Expr_::*;
-    match expr.node {
+    match &expr.node {
         Add(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let res = match a_res {
-                DataType::Number(n1) => match b_res {
-                    DataType::Number(n2) => DataType::Number(n1 + n2),
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a number with a number!"
-                                .to_string(),
-                        ))
-                    }
-                },
-                DataType::List(mut l1) => match b_res {
-                    DataType::List(mut l2) => {
-                        l1.append(&mut l2);
-                        DataType::List(l1)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                DataType::String(s1) => match b_res {
-                    DataType::String(s2) => {
-                        let mut new_string = s1;
-                        new_string.push_str(&s2);
-                        DataType::String(new_string)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot use + on something that is not a number, list or string!"
-                            .to_string(),
-                    ))
-                }
-            };
-            Ok(res)
+            let a_res = interpret_expr(env, ds, a)?;
+            let b_res = interpret_expr(env, ds, b)?;
+            add(a_res, b_res)
         }
         Sub(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num - b_num))
+            let (a_res, b_res) = interpret_operands(env, ds, a, b)?;
+            sub(a_res, b_res)
         }
         Mul(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num * b_num))
+            let (a_res, b_res) = interpret_operands(env, ds, a, b)?;
+            mul(a_res, b_res)
         }
         Div(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            if b_num == 0.0 {
-                return Err(QueryError::MathError(
-                    "Tried to divide by zero!".to_string(),
-                ));
-            }
-            Ok(DataType::Number(a_num / b_num))
+            let (a_res, b_res) = interpret_operands(env, ds, a, b)?;
+            div(a_res, b_res)
         }
         Mod(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
+            let (a_num, b_num) = interpret_numbers(env, ds, a, b)?;
             Ok(DataType::Number(a_num % b_num))
         }
         Equal(lhs, rhs) => {
-            let lhs_res = interpret_expr(env, ds, *lhs)?;
-            let rhs_res = interpret_expr(env, ds, *rhs)?;
+            let lhs_res = interpret_expr(env, ds, lhs)?;
+            let rhs_res = interpret_expr(env, ds, rhs)?;
             Ok(DataType::Bool(lhs_res.query_eq(&rhs_res)?))
         }
+        NotEqual(lhs, rhs) => {
+            let lhs_res = interpret_expr(env, ds, lhs)?;
+            let rhs_res = interpret_expr(env, ds, rhs)?;
+            Ok(DataType::Bool(!lhs_res.query_eq(&rhs_res)?))
+        }
+        Less(lhs, rhs) => Ok(DataType::Bool(interpret_cmp(env, ds, lhs, rhs)?.is_lt())),
+        LessEqual(lhs, rhs) => Ok(DataType::Bool(interpret_cmp(env, ds, lhs, rhs)?.is_le())),
+        Greater(lhs, rhs) => Ok(DataType::Bool(interpret_cmp(env, ds, lhs, rhs)?.is_gt())),
+        GreaterEqual(lhs, rhs) => Ok(DataType::Bool(interpret_cmp(env, ds, lhs, rhs)?.is_ge())),
+        // and/or short-circuit, so the right hand side is only evaluated when needed
+        And(lhs, rhs) => {
+            if !interpret_bool(env, ds, lhs, "and")? {
+                return Ok(DataType::Bool(false));
+            }
+            Ok(DataType::Bool(interpret_bool(env, ds, rhs, "and")?))
+        }
+        Or(lhs, rhs) => {
+            if interpret_bool(env, ds, lhs, "or")? {
+                return Ok(DataType::Bool(true));
+            }
+            Ok(DataType::Bool(interpret_bool(env, ds, rhs, "or")?))
+        }
+        Not(e) => Ok(DataType::Bool(!interpret_bool(env, ds, e, "not")?)),
         Assign(var, b) => {
-            let val = interpret_expr(env, ds, *b)?;
-            env.insert(var, val);
+            let val = interpret_expr(env, ds, b)?;
+            env.insert(var.clone(), val);
             Ok(DataType::None())
         }
         // This clone is the one necessary copy per variable reference: the env
         // must retain the value since it may be referenced again, while
         // function arguments and transforms need owned events.
-        Var(var) => match env.get(&var) {
+        Var(var) => match env.get(var) {
             Some(v) => Ok(v.clone()),
             None => Err(QueryError::VariableNotDefined(var.to_string())),
         },
-        Bool(lit) => Ok(DataType::Bool(lit)),
-        Number(lit) => Ok(DataType::Number(lit)),
-        String(litstr) => Ok(DataType::String(litstr)),
+        Index(value, index) => interpret_index(env, ds, value, index),
+        Bool(lit) => Ok(DataType::Bool(*lit)),
+        Number(lit) => Ok(DataType::Number(*lit)),
+        String(litstr) => Ok(DataType::String(litstr.clone())),
         Return(e) => {
-            let val = interpret_expr(env, ds, *e)?;
+            let val = interpret_expr(env, ds, e)?;
             // TODO: Once RETURN is deprecated we can fix this
             env.insert("RETURN".to_string(), val);
             Ok(DataType::None())
         }
         If(ifs) => {
             for (cond, block) in ifs {
-                let c = interpret_expr(env, ds, *cond)?;
+                let c = interpret_expr(env, ds, cond)?;
                 if c.query_eq(&DataType::Bool(true))? {
-                    for expr in block {
-                        interpret_expr(env, ds, expr)?;
-                    }
+                    interpret_block(env, ds, block)?;
                     break;
                 }
             }
             Ok(DataType::None())
         }
         Function(fname, e) => {
-            let args = match interpret_expr(env, ds, *e)? {
+            let args = match interpret_expr(env, ds, e)? {
                 DataType::List(l) => l,
                 _ => unreachable!(),
             };
@@ -221,11 +789,61 @@ fn interpret_expr(
                 Some(v) => v,
                 None => return Err(QueryError::VariableNotDefined(fname.clone())),
             };
-            let (_name, fun) = match var {
-                DataType::Function(name, fun) => (name, fun),
-                _data => return Err(QueryError::InvalidType(fname.to_string())),
+            match var {
+                DataType::Function(_, _) | DataType::Lambda(_) if env.profiler().is_some() => {
+                    profile_call(fname, var, args, env, ds, expr.span)
+                }
+                DataType::Function(_, _) | DataType::Lambda(_) => call_function(var, args, env, ds),
+                _data => Err(QueryError::InvalidType(fname.to_string())),
+            }
+        }
+        For(var, list, block) => {
+            let items = match interpret_expr(env, ds, list)? {
+                DataType::List(l) => l,
+                invalid_type => {
+                    return Err(QueryError::InvalidType(format!(
+                        "Cannot iterate over {invalid_type:?}, expected a list"
+                    )))
+                }
             };
-            fun(args, env, ds)
+            for item in items {
+                env.count_iteration()?;
+                env.insert(var.clone(), item);
+                interpret_block(env, ds, block)?;
+            }
+            Ok(DataType::None())
+        }
+        Lambda(params, body) => Ok(DataType::Lambda(new_lambda(env, params, body, None))),
+        Def(name, params, body) => {
+            let lambda = new_lambda(env, params, body, Some(name));
+            env.insert(name.clone(), DataType::Lambda(lambda));
+            Ok(DataType::None())
+        }
+        Import(name) => {
+            env.dependencies().libraries.insert(name.clone());
+            // The functions of a library call each other through the scope of the library,
+            // like a module with its own globals
+            let mut libraries = env.root().libraries.borrow_mut();
+            let mut functions = HashMap::new();
+            for def in library::load(name, ds)? {
+                // library::load only returns def statements
+                if let Def(name, params, body) = def.node {
+                    let lambda = crate::datatype::Lambda {
+                        params,
+                        body,
+                        captured: Arc::default(),
+                        name: Some(name.clone()),
+                        library: Some(libraries.len()),
+                    };
+                    functions.insert(name, DataType::Lambda(lambda));
+                }
+            }
+            libraries.push(Arc::new(functions.clone()));
+            drop(libraries);
+            for (name, function) in functions {
+                env.insert(name, function);
+            }
+            Ok(DataType::None())
         }
         List(list) => {
             let mut l = Vec::new();
@@ -239,7 +857,7 @@ fn interpret_expr(
             let mut dict = HashMap::new();
             for (key, val_uninterpreted) in d {
                 let val = interpret_expr(env, ds, val_uninterpreted)?;
-                dict.insert(key, val);
+                dict.insert(key.clone(), val);
             }
             Ok(DataType::Dict(dict))
         }
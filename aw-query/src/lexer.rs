@@ -7,6 +7,11 @@ pub enum Token {
     If,
     ElseIf,
     Else,
+    For,
+    In,
+    Lambda,
+    Def,
+    Import,
     Return,
 
     Bool(bool),
@@ -18,6 +23,14 @@ pub enum Token {
     Slash,
     Percent,
     Equals,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
+    And,
+    Or,
+    Not,
     Assign,
     LParen,
     RParen,
@@ -32,6 +45,8 @@ pub enum Token {
     Whitespace,
     Newline,
     Comment,
+    // Any character which is not part of the language, lets the parser report where it is
+    Unknown,
 }
 
 lexer! {
@@ -45,7 +60,15 @@ lexer! {
     r#"if"# => (Token::If, text),
     r#"elif"# => (Token::ElseIf, text),
     r#"else"# => (Token::Else, text),
+    r#"for"# => (Token::For, text),
+    r#"in"# => (Token::In, text),
+    r#"lambda"# => (Token::Lambda, text),
+    r#"def"# => (Token::Def, text),
+    r#"import"# => (Token::Import, text),
     r#"return"# => (Token::Return, text),
+    r#"and"# => (Token::And, text),
+    r#"or"# => (Token::Or, text),
+    r#"not"# => (Token::Not, text),
 
     r#"true"# => (Token::Bool(true), text),
     r#"false"# => (Token::Bool(false), text),
@@ -68,6 +91,11 @@ lexer! {
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Ident(text.to_owned()), text),
 
     r#"=="# => (Token::Equals, text),
+    r#"!="# => (Token::NotEquals, text),
+    r#"<="# => (Token::LessEquals, text),
+    r#">="# => (Token::GreaterEquals, text),
+    r#"<"# => (Token::Less, text),
+    r#">"# => (Token::Greater, text),
     r#"="# => (Token::Assign, text),
     r#"\+"# => (Token::Plus, text),
     r#"-"# => (Token::Minus, text),
@@ -83,6 +111,7 @@ lexer! {
     r#","# => (Token::Comma, text),
     r#":"# => (Token::Colon, text),
     r#";"# => (Token::Semi, text),
+    r#"."# => (Token::Unknown, text),
 }
 
 pub struct Lexer<'a> {
@@ -142,3 +171,19 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+/// The comments in the code, which the Lexer skips, each with the # it starts with
+pub fn comments(code: &str) -> Vec<(String, Span)> {
+    let mut comments = Vec::new();
+    let mut remaining = code;
+    let mut line = 1;
+    while let Some(((tok, text), new_remaining)) = next_token(remaining) {
+        remaining = new_remaining;
+        match tok {
+            Token::Newline => line += 1,
+            Token::Comment => comments.push((text.to_string(), span_in(text, code, line))),
+            _ => (),
+        }
+    }
+    comments
+}
@@ -4,29 +4,41 @@ extern crate serde;
 extern crate serde_json;
 
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
 
 use aw_models::TimeInterval;
 
 use aw_datastore::Datastore;
+use serde::Serialize;
 
 pub mod datatype;
+pub mod library;
 
 mod ast;
+mod check;
+mod dependencies;
+mod format;
 mod functions;
 mod interpret;
 mod lexer;
+mod optimize;
 #[allow(
     clippy::match_single_binding,
     clippy::redundant_closure_call,
     unused_braces
 )]
 mod parser;
+pub mod profile;
 
 pub use crate::datatype::DataType;
+pub use crate::dependencies::Dependencies;
 pub use crate::interpret::VarEnv;
+pub use crate::profile::Profile;
 
-// TODO: add line numbers to errors
-// (works during lexing, but not during parsing I believe)
+use crate::lexer::Span;
 
 #[derive(Debug)]
 pub enum QueryError {
@@ -42,6 +54,16 @@ pub enum QueryError {
     TimeIntervalError(String),
     BucketQueryError(String),
     RegexCompileError(String),
+    IterationLimitError(String),
+    RecursionLimitError(String),
+    ImportError(String),
+    IndexError(String),
+
+    // Limits
+    TimeLimitError(String),
+    EventLimitError(String),
+    OutputLimitError(String),
+    Cancelled(),
 }
 
 impl fmt::Display for QueryError {
@@ -50,15 +72,267 @@ impl fmt::Display for QueryError {
     }
 }
 
-pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, QueryError> {
+/// Where in the query code an error occurred, lines and columns start at 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    /// The end is exclusive, it points just past the last character of the failing code
+    pub end_line: usize,
+    pub end_column: usize,
+    /// The line of code in which the error starts
+    pub snippet: String,
+}
+
+impl Location {
+    pub(crate) fn from_span(code: &str, span: Span) -> Location {
+        let (line, column, line_start) = line_and_column(code, span.lo);
+        let (end_line, end_column, _) = line_and_column(code, span.hi.max(span.lo));
+        let snippet = code[line_start..].lines().next().unwrap_or("").to_string();
+        Location {
+            line,
+            column,
+            end_line,
+            end_column,
+            snippet,
+        }
+    }
+}
+
+/// Returns the line and column of a byte offset into the code, together with the offset of
+/// the start of that line
+fn line_and_column(code: &str, offset: usize) -> (usize, usize, usize) {
+    let before = &code[..offset.min(code.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column, line_start)
+}
+
+/// A QueryError together with the location in the query code which caused it, if known
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    #[serde(rename = "message", serialize_with = "serialize_error")]
+    pub error: QueryError,
+    #[serde(flatten)]
+    pub location: Option<Location>,
+}
+
+fn serialize_error<S>(error: &QueryError, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(error)
+}
+
+impl Diagnostic {
+    fn new(code: &str, error: QueryError, span: Option<Span>) -> Diagnostic {
+        Diagnostic {
+            error,
+            location: span.map(|span| Location::from_span(code, span)),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(loc) => write!(
+                f,
+                "{} at line {}, column {}",
+                self.error, loc.line, loc.column
+            ),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+fn parse(code: &str) -> Result<ast::Program, Diagnostic> {
     let lexer = lexer::Lexer::new(code);
-    let program = match parser::parse(lexer) {
-        Ok(p) => p,
-        Err(e) => {
-            // TODO: Improve parsing error message
-            warn!("ParsingError: {:?}", e);
-            return Err(QueryError::ParsingError(format!("{e:?}")));
+    match parser::parse(lexer) {
+        Ok(p) => Ok(p),
+        Err((Some((token, span)), _)) => {
+            let e = QueryError::ParsingError(format!("Unexpected token {token:?}"));
+            warn!("{:?}", e);
+            Err(Diagnostic::new(code, e, Some(span)))
         }
+        Err((None, _)) => {
+            let e = QueryError::ParsingError("Unexpected end of query".to_string());
+            warn!("{:?}", e);
+            let end = Span {
+                lo: code.len(),
+                hi: code.len(),
+                line: 0,
+            };
+            Err(Diagnostic::new(code, e, Some(end)))
+        }
+    }
+}
+
+/// Options for running a query
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Profile the query, which makes it a bit slower
+    pub explain: bool,
+    /// Fail with a TimeLimitError once this time has passed
+    pub deadline: Option<Instant>,
+    /// Fail with an EventLimitError if a query_bucket call would load more events than this
+    pub max_bucket_events: Option<usize>,
+    /// Fail with an OutputLimitError if the result is larger than this when serialized to JSON
+    pub max_output_bytes: Option<usize>,
+    /// Stops the query with a Cancelled error when set to true, for example from another thread
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Run the query as written, without rewriting it to load fewer events
+    pub skip_optimizer: bool,
+}
+
+#[derive(Debug)]
+pub struct QueryOutput {
+    pub result: DataType,
+    /// Only set when the query ran with QueryOptions::explain
+    pub profile: Option<Profile>,
+    pub dependencies: Dependencies,
+}
+
+pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, QueryError> {
+    query_diagnostic(code, ti, ds).map_err(|d| d.error)
+}
+
+/// Checks a query for mistakes without running it, such as undefined variables, calls with
+/// the wrong number or types of parameters and references to buckets which don't exist.
+/// Returns all problems found, an empty list means the query looks valid.
+pub fn check(code: &str, ds: &Datastore) -> Vec<Diagnostic> {
+    match parse(code) {
+        Ok(program) => check::check_prog(&program, ds)
+            .into_iter()
+            .map(|(e, span)| Diagnostic::new(code, e, span))
+            .collect(),
+        Err(d) => vec![d],
+    }
+}
+
+/// The code of a query without its comments and whitespace, queries with the same normalized
+/// code do exactly the same thing
+pub fn normalize(code: &str) -> String {
+    let tokens: Vec<String> = lexer::Lexer::new(code)
+        .map(|(token, _)| format!("{token:?}"))
+        .collect();
+    tokens.join(" ")
+}
+
+/// The code of a query in a canonical layout with its comments kept, see the format module.
+/// Fails if the code can't be parsed.
+pub fn format(code: &str) -> Result<String, Diagnostic> {
+    let program = parse(code)?;
+    Ok(format::format_prog(code, &program))
+}
+
+/// Same as query, but errors also tell where in the code they occurred
+pub fn query_diagnostic(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+) -> Result<DataType, Diagnostic> {
+    query_with_options(code, ti, ds, &QueryOptions::default()).map(|output| output.result)
+}
+
+pub fn query_with_options(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    options: &QueryOptions,
+) -> Result<QueryOutput, Diagnostic> {
+    let start = Instant::now();
+    let program = prepare(code, options)?;
+    run(code, &program, ti, ds, options, start)
+}
+
+/// Runs a query once for each of the timeperiods, on up to `threads` threads at the same time.
+/// The outputs are in the same order as the timeperiods. As soon as one of the runs fails the
+/// other runs are cancelled, and the error of the earliest timeperiod which failed is returned.
+pub fn query_timeperiods(
+    code: &str,
+    intervals: &[TimeInterval],
+    ds: &Datastore,
+    options: &QueryOptions,
+    threads: usize,
+) -> Result<Vec<QueryOutput>, Diagnostic> {
+    let program = prepare(code, options)?;
+    let threads = threads.clamp(1, intervals.len().max(1));
+    if threads == 1 {
+        return intervals
+            .iter()
+            .map(|ti| run(code, &program, ti, ds, options, Instant::now()))
+            .collect();
+    }
+
+    // Failing runs cancel the others through the same flag which cancels the whole query
+    let cancel = options.cancel.clone().unwrap_or_default();
+    let options = QueryOptions {
+        cancel: Some(Arc::clone(&cancel)),
+        ..options.clone()
     };
-    interpret::interpret_prog(program, ti, ds)
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<QueryOutput, Diagnostic>)> = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(ti) = intervals.get(i) else {
+                            break;
+                        };
+                        let res = run(code, &program, ti, ds, &options, Instant::now());
+                        if res.is_err() {
+                            cancel.store(true, Ordering::Relaxed);
+                        }
+                        results.push((i, res));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("query worker thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    // Runs cancelled because another run failed are not the error to report
+    let error = results
+        .iter()
+        .position(|(_, res)| matches!(res, Err(d) if !matches!(d.error, QueryError::Cancelled())))
+        .or_else(|| results.iter().position(|(_, res)| res.is_err()));
+    match error {
+        Some(i) => Err(results.swap_remove(i).1.err().unwrap()),
+        None => results.into_iter().map(|(_, res)| res).collect(),
+    }
+}
+
+fn prepare(code: &str, options: &QueryOptions) -> Result<ast::Program, Diagnostic> {
+    let mut program = parse(code)?;
+    if !options.skip_optimizer {
+        optimize::optimize(&mut program);
+    }
+    Ok(program)
+}
+
+fn run(
+    code: &str,
+    program: &ast::Program,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    options: &QueryOptions,
+    start: Instant,
+) -> Result<QueryOutput, Diagnostic> {
+    match interpret::interpret_prog(program, ti, ds, options) {
+        Ok((result, profiler, dependencies)) => Ok(QueryOutput {
+            result,
+            profile: profiler.map(|p| p.finish(code, start.elapsed())),
+            dependencies,
+        }),
+        Err((e, span)) => Err(Diagnostic::new(code, e, span)),
+    }
 }
@@ -0,0 +1,82 @@
+//! Named libraries of query functions, stored in the key-value store of the datastore.
+//!
+//! A library is query code which only contains `def` statements, queries load its
+//! functions into their own scope with `import "name";`.
+
+use aw_datastore::{Datastore, DatastoreError};
+use aw_models::QueryLibrary;
+
+use crate::ast::{Expr, Expr_};
+use crate::QueryError;
+
+pub const KEY_PREFIX: &str = "query_libraries.";
+
+/// The key under which the library with the given name is stored
+pub fn key(name: &str) -> String {
+    format!("{KEY_PREFIX}{name}")
+}
+
+pub fn validate_name(name: &str) -> Result<(), QueryError> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if name.is_empty() || name.len() > 100 || !valid_chars {
+        return Err(QueryError::ImportError(format!(
+            "Invalid query library name '{name}', only up to 100 letters, digits, '_' and '-' are allowed"
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that a library is valid before it is stored, so that importing it can't fail later
+pub fn validate(library: &QueryLibrary) -> Result<(), QueryError> {
+    validate_name(&library.name)?;
+    parse(&library.code)?;
+    Ok(())
+}
+
+fn parse(code: &str) -> Result<Vec<Expr>, QueryError> {
+    let program = crate::parse(code).map_err(|d| match d.location {
+        Some(loc) => QueryError::ImportError(format!(
+            "{:?} at line {}, column {}",
+            d.error, loc.line, loc.column
+        )),
+        None => d.error,
+    })?;
+    for stmt in program.stmts.iter() {
+        match stmt.node {
+            Expr_::Def(..) => (),
+            _ => {
+                return Err(QueryError::ImportError(
+                    "Query libraries may only contain def statements".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(program.stmts)
+}
+
+pub(crate) fn load(name: &str, ds: &Datastore) -> Result<Vec<Expr>, QueryError> {
+    let value = match ds.get_key_value(&key(name)) {
+        Ok(value) => value,
+        Err(DatastoreError::NoSuchKey(_)) => {
+            return Err(QueryError::ImportError(format!(
+                "No query library named '{name}'"
+            )))
+        }
+        Err(e) => {
+            return Err(QueryError::ImportError(format!(
+                "Failed to load query library '{name}': {e:?}"
+            )))
+        }
+    };
+    let library: QueryLibrary = match serde_json::from_str(&value) {
+        Ok(library) => library,
+        Err(e) => {
+            return Err(QueryError::ImportError(format!(
+                "Failed to parse stored query library '{name}': {e}"
+            )))
+        }
+    };
+    parse(&library.code)
+}
@@ -0,0 +1,154 @@
+//! Runs queries from files or interactively, against a database file or a running aw-server
+//!
+//! ```text
+//! aw-query --timeperiod yesterday report.aw
+//! aw-query --db ~/.local/share/activitywatch/aw-server-rust/sqlite.db
+//! ```
+
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chrono::Local;
+use clap::Parser;
+
+use aw_client_rust::blocking::AwClient;
+use aw_datastore::Datastore;
+
+mod cli;
+
+use cli::backend::Backend;
+use cli::output::Format;
+use cli::repl::Session;
+use cli::timeperiod::parse_timeperiod;
+
+#[derive(Parser)]
+#[clap(version = "0.1")]
+struct Opts {
+    /// Query files to run, each printed in turn. Without files the query is read from
+    /// stdin, or a REPL is started if stdin is a terminal.
+    files: Vec<PathBuf>,
+
+    /// Query to run instead of reading one from files or stdin
+    #[clap(short, long)]
+    eval: Option<String>,
+
+    /// Database file to open read-only, instead of connecting to a server
+    #[clap(long)]
+    db: Option<PathBuf>,
+
+    /// Host of the server to connect to
+    #[clap(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port of the server to connect to, 5600 by default
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Connect to the default port of a testing server
+    #[clap(long)]
+    testing: bool,
+
+    /// Timeperiod to query, such as today, yesterday, this-week, last-week, this-month,
+    /// last-month, last-7-days or an interval like 2024-01-01T00:00:00Z/2024-01-02T00:00:00Z
+    #[clap(short, long, default_value = "today")]
+    timeperiod: String,
+
+    /// How to print results
+    #[clap(short, long, value_enum, default_value = "table")]
+    format: Format,
+}
+
+fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let timeperiod = match parse_timeperiod(&opts.timeperiod, Local::now()) {
+        Ok(timeperiod) => timeperiod,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let backend = match connect(&opts) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let session = Session::new(backend, timeperiod, opts.format);
+
+    let mut queries = Vec::new();
+    if let Some(code) = &opts.eval {
+        queries.push(code.clone());
+    }
+    for file in &opts.files {
+        match std::fs::read_to_string(file) {
+            Ok(code) => queries.push(code),
+            Err(e) => {
+                eprintln!("Failed to read {}: {e}", file.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if queries.is_empty() {
+        if std::io::stdin().is_terminal() {
+            return match session.run_repl(history_path()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("{e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        let mut code = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut code) {
+            eprintln!("Failed to read the query from stdin: {e}");
+            return ExitCode::FAILURE;
+        }
+        queries.push(code);
+    }
+
+    for code in queries {
+        if let Err(e) = session.run_query(&code) {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn connect(opts: &Opts) -> Result<Backend, String> {
+    if let Some(db) = &opts.db {
+        if !db.is_file() {
+            return Err(format!("No database file at {}", db.display()));
+        }
+        let ds = Datastore::new_read_only(db.to_string_lossy().to_string());
+        // Opening happens on the worker thread, a failure only shows on the first request
+        ds.get_buckets()
+            .map_err(|e| format!("Failed to open {}: {e:?}", db.display()))?;
+        return Ok(Backend::Datastore(ds));
+    }
+    let port = match (opts.port, opts.testing) {
+        (Some(port), _) => port,
+        (None, true) => 5666,
+        (None, false) => 5600,
+    };
+    let client = AwClient::new(&opts.host, port, "aw-query")
+        .map_err(|e| format!("Failed to create a client for {}:{port}: {e}", opts.host))?;
+    client.get_info().map_err(|e| {
+        format!(
+            "Failed to connect to aw-server at {}:{port}: {e}",
+            opts.host
+        )
+    })?;
+    Ok(Backend::Server(Box::new(client)))
+}
+
+/// The file the REPL history is kept in, None if there is no data directory
+fn history_path() -> Option<PathBuf> {
+    let dir = dirs::data_local_dir()?
+        .join("activitywatch")
+        .join("aw-query");
+    std::fs::create_dir_all(&dir).ok()?;
+    Some(dir.join("history.txt"))
+}
@@ -0,0 +1,155 @@
+//! Rewrites queries into equivalent ones which load fewer events from the datastore.
+//!
+//! `query_bucket` loads all events of a bucket in the timeperiod, even when the query throws
+//! most of them away right after. The optimizer spots such patterns and rewrites them into
+//! calls which let the datastore leave out the events which would be thrown away:
+//!
+//! - `filter_keyvals(query_bucket(b), "key", ["a", "b"])`, with literal strings as key and
+//!   values, only loads the events with one of the values, as filtered in SQL
+//! - `filter_period_intersect(query_bucket(b), var)` only loads the events in the time
+//!   spanned by the events in `var`
+//!
+//! The rewritten queries give the same results. As they load fewer events they are less
+//! likely to hit the event limit, and a query with several mistakes may fail on another one
+//! first, as the arguments are evaluated before the bucket is loaded.
+
+use std::sync::Arc;
+
+use crate::ast::{Expr, Expr_, Program};
+
+pub(crate) const QUERY_BUCKET_FILTER_KEYVALS: &str = "query_bucket+filter_keyvals";
+pub(crate) const QUERY_BUCKET_FILTER_PERIOD_INTERSECT: &str =
+    "query_bucket+filter_period_intersect";
+
+/// The functions whose calls are rewritten, they must not be redefined by the query
+const REWRITTEN: [&str; 3] = ["query_bucket", "filter_keyvals", "filter_period_intersect"];
+
+pub(crate) fn optimize(program: &mut Program) {
+    if !program.stmts.iter().all(can_optimize) {
+        return;
+    }
+    for stmt in &mut program.stmts {
+        optimize_expr(stmt);
+    }
+}
+
+/// Whether the builtins which are rewritten can't be redefined anywhere in the code.
+/// Functions are looked up by name when they are called, so a variable or parameter with
+/// the same name anywhere could shadow them, as could a function in an imported library.
+fn can_optimize(expr: &Expr) -> bool {
+    use Expr_::*;
+    let redefines = |name: &str| REWRITTEN.contains(&name);
+    match &expr.node {
+        Import(_) => false,
+        Assign(name, e) => !redefines(name) && can_optimize(e),
+        For(var, list, block) => {
+            !redefines(var) && can_optimize(list) && block.iter().all(can_optimize)
+        }
+        Lambda(params, body) => {
+            !params.iter().any(|p| redefines(p)) && body.iter().all(can_optimize)
+        }
+        Def(name, params, body) => {
+            !redefines(name)
+                && !params.iter().any(|p| redefines(p))
+                && body.iter().all(can_optimize)
+        }
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b) => {
+            can_optimize(a) && can_optimize(b)
+        }
+        Equal(a, b) | NotEqual(a, b) | Less(a, b) | LessEqual(a, b) => {
+            can_optimize(a) && can_optimize(b)
+        }
+        Greater(a, b) | GreaterEqual(a, b) | And(a, b) | Or(a, b) | Index(a, b) => {
+            can_optimize(a) && can_optimize(b)
+        }
+        Not(e) | Return(e) | Function(_, e) => can_optimize(e),
+        If(ifs) => ifs
+            .iter()
+            .all(|(cond, block)| can_optimize(cond) && block.iter().all(can_optimize)),
+        List(items) => items.iter().all(can_optimize),
+        Dict(d) => d.values().all(can_optimize),
+        Var(_) | Bool(_) | Number(_) | String(_) => true,
+    }
+}
+
+fn optimize_expr(expr: &mut Expr) {
+    use Expr_::*;
+    // Inner expressions first, so rewritten calls can be part of larger patterns
+    match &mut expr.node {
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b) => {
+            optimize_expr(a);
+            optimize_expr(b);
+        }
+        Equal(a, b) | NotEqual(a, b) | Less(a, b) | LessEqual(a, b) => {
+            optimize_expr(a);
+            optimize_expr(b);
+        }
+        Greater(a, b) | GreaterEqual(a, b) | And(a, b) | Or(a, b) | Index(a, b) => {
+            optimize_expr(a);
+            optimize_expr(b);
+        }
+        Not(e) | Assign(_, e) | Return(e) | Function(_, e) => optimize_expr(e),
+        For(_, list, block) => {
+            optimize_expr(list);
+            block.iter_mut().for_each(optimize_expr);
+        }
+        If(ifs) => {
+            for (cond, block) in ifs {
+                optimize_expr(cond);
+                block.iter_mut().for_each(optimize_expr);
+            }
+        }
+        Lambda(_, body) | Def(_, _, body) => Arc::make_mut(body).iter_mut().for_each(optimize_expr),
+        List(items) => items.iter_mut().for_each(optimize_expr),
+        Dict(d) => d.values_mut().for_each(optimize_expr),
+        Var(_) | Import(_) | Bool(_) | Number(_) | String(_) => (),
+    }
+    if let Function(name, args) = &mut expr.node {
+        if let Some(rewritten) = rewrite_call(name, args) {
+            *name = rewritten.to_string();
+        }
+    }
+}
+
+/// Rewrites the arguments of a call matching one of the patterns, and returns the name of
+/// the function to call instead
+fn rewrite_call(name: &str, args: &mut Expr) -> Option<&'static str> {
+    let Expr_::List(items) = &mut args.node else {
+        return None;
+    };
+    let rewritten = match (name, &items[..]) {
+        ("filter_keyvals", [events, key, values])
+            if query_bucket_arg(events).is_some()
+                && is_string(key)
+                && matches!(&values.node, Expr_::List(values) if values.iter().all(is_string)) =>
+        {
+            QUERY_BUCKET_FILTER_KEYVALS
+        }
+        ("filter_period_intersect", [events, filter_events])
+            if query_bucket_arg(events).is_some()
+                && matches!(filter_events.node, Expr_::Var(_)) =>
+        {
+            QUERY_BUCKET_FILTER_PERIOD_INTERSECT
+        }
+        _ => return None,
+    };
+    // query_bucket(bucket) is replaced by just the bucket
+    let bucket = query_bucket_arg(&items[0]).unwrap().clone();
+    items[0] = bucket;
+    Some(rewritten)
+}
+
+/// The bucket argument of a `query_bucket(bucket)` call
+fn query_bucket_arg(expr: &Expr) -> Option<&Expr> {
+    match &expr.node {
+        Expr_::Function(name, args) if name == "query_bucket" => match &args.node {
+            Expr_::List(items) if items.len() == 1 => Some(&items[0]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_string(expr: &Expr) -> bool {
+    matches!(expr.node, Expr_::String(_))
+}
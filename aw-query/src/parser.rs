@@ -7,6 +7,7 @@ use crate::lexer::*;
 use plex::parser;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 fn merge_if_vecs(lhs: Expr_, rhs: Expr_) -> Expr_ {
     let mut ifs = match lhs {
@@ -47,9 +48,37 @@ parser! {
 
     statement: Expr {
         ifs[x] => x,
+        _for[x] => x,
+        _def[x] => x,
+        _import[x] Semi => x,
         ret[x] Semi => x,
     }
 
+    _def: Expr {
+        Def Ident(name) LParen _params[params] RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, params, Arc::new(body)),
+        },
+        Def Ident(name) LParen RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, Vec::new(), Arc::new(body)),
+        },
+    }
+
+    _import: Expr {
+        Import String(name) => Expr {
+            span: span!(),
+            node: Expr_::Import(name),
+        },
+    }
+
+    _for: Expr {
+        For Ident(var) In binop[list] LBrace statements[block] RBrace => Expr {
+            span: span!(),
+            node: Expr_::For(var, Box::new(list), block),
+        },
+    }
+
     ifs: Expr {
         _if[l_ifs] => l_ifs,
         _elif[l_ifs] => l_ifs,
@@ -129,30 +158,105 @@ parser! {
         binop[x] => x
     }
 
+    // Binary operators, from lowest to highest precedence:
+    // or, and, not, comparisons, + and -, and finally *, / and %.
+    // Negation with -x and indexing with x[i] bind tighter than all of them.
     binop: Expr {
-        binop[lhs] Plus func[rhs] => Expr {
+        binop[lhs] Or _and[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Or(Box::new(lhs), Box::new(rhs)),
+        },
+        _and[x] => x
+    }
+
+    _and: Expr {
+        _and[lhs] And _not[rhs] => Expr {
+            span: span!(),
+            node: Expr_::And(Box::new(lhs), Box::new(rhs)),
+        },
+        _not[x] => x
+    }
+
+    _not: Expr {
+        Not _not[x] => Expr {
+            span: span!(),
+            node: Expr_::Not(Box::new(x)),
+        },
+        _cmp[x] => x
+    }
+
+    _cmp: Expr {
+        _cmp[lhs] Equals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+        },
+        _cmp[lhs] NotEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::NotEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _cmp[lhs] Less _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Less(Box::new(lhs), Box::new(rhs)),
+        },
+        _cmp[lhs] LessEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::LessEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _cmp[lhs] Greater _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Greater(Box::new(lhs), Box::new(rhs)),
+        },
+        _cmp[lhs] GreaterEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::GreaterEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[x] => x
+    }
+
+    _sum: Expr {
+        _sum[lhs] Plus _product[rhs] => Expr {
             span: span!(),
             node: Expr_::Add(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Minus func[rhs] => Expr {
+        _sum[lhs] Minus _product[rhs] => Expr {
             span: span!(),
             node: Expr_::Sub(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Star func[rhs] => Expr {
+        _product[x] => x
+    }
+
+    _product: Expr {
+        _product[lhs] Star _neg[rhs] => Expr {
             span: span!(),
             node: Expr_::Mul(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Slash func[rhs] => Expr {
+        _product[lhs] Slash _neg[rhs] => Expr {
             span: span!(),
             node: Expr_::Div(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Percent func[rhs] => Expr {
+        _product[lhs] Percent _neg[rhs] => Expr {
             span: span!(),
             node: Expr_::Mod(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Equals func[rhs] => Expr {
+        _neg[x] => x
+    }
+
+    // -x is the same as 0 - x
+    _neg: Expr {
+        Minus _neg[x] => Expr {
             span: span!(),
-            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+            node: {
+                let zero = Expr { span: span!(), node: Expr_::Number(0.0) };
+                Expr_::Sub(Box::new(zero), Box::new(x))
+            },
+        },
+        _index[x] => x
+    }
+
+    _index: Expr {
+        _index[e] LBracket binop[i] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Index(Box::new(e), Box::new(i)),
         },
         func[x] => x
     }
@@ -243,7 +347,27 @@ parser! {
         },
     }
 
+    lambda: Expr {
+        Lambda LParen _params[params] RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Lambda(params, Arc::new(body)),
+        },
+        Lambda LParen RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Lambda(Vec::new(), Arc::new(body)),
+        },
+    }
+
+    _params: Vec<std::string::String> {
+        Ident(param) => vec![param],
+        _params[mut params] Comma Ident(param) => {
+            params.push(param);
+            params
+        },
+    }
+
     atom: Expr {
+        lambda[l] => l,
         // round brackets to destructure tokens
         Ident(v) => Expr {
             span: span!(),
@@ -0,0 +1,259 @@
+//! Profiling of queries, used to explain where the time of a slow query goes.
+//!
+//! When a query runs with `QueryOptions::explain`, every statement and function call is
+//! timed and the events going in and out of it are counted. Code which runs many times,
+//! such as the body of a loop, is reported once with the totals of all its runs.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::Duration;
+
+use aw_models::Event;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::ast::Expr;
+use crate::lexer::Span;
+use crate::{DataType, Location};
+
+/// Profiling results of a single query run
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub time_ms: f64,
+    pub statements: Vec<ProfileEntry>,
+    pub calls: Vec<ProfileEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileEntry {
+    /// The name of the called function, or the code of the statement
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+    /// How many times the code ran, the other numbers are totals over all runs
+    pub count: usize,
+    /// Includes the time of all nested statements and calls
+    pub time_ms: f64,
+    /// Events in the parameters of a call, or in the variables read by a statement
+    pub input_events: usize,
+    /// Events returned by a call, or assigned or returned by a statement
+    pub output_events: usize,
+    /// Estimate of the memory used by the largest value returned or assigned, in bytes
+    pub memory_bytes: usize,
+}
+
+struct Record {
+    name: String,
+    span: Span,
+    count: usize,
+    time: Duration,
+    input_events: usize,
+    output_events: usize,
+    memory_bytes: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Profiler {
+    // Keyed by the position of the code in the query
+    statements: HashMap<(usize, usize), Record>,
+    calls: HashMap<(usize, usize), Record>,
+    // Code in imported libraries is not part of the query, so it's only profiled as a
+    // whole through the call which ran it
+    suspended: usize,
+}
+
+/// The numbers measured for a single run of a statement or call
+pub(crate) struct Measurement {
+    pub time: Duration,
+    pub input: usize,
+    pub output: Option<DataTypeStats>,
+}
+
+pub(crate) struct DataTypeStats {
+    events: usize,
+    memory_bytes: usize,
+}
+
+impl DataTypeStats {
+    pub fn of(data: &DataType) -> DataTypeStats {
+        DataTypeStats {
+            events: count_events(data),
+            memory_bytes: estimate_memory(data),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn suspend(&mut self) {
+        self.suspended += 1;
+    }
+
+    pub fn resume(&mut self) {
+        self.suspended -= 1;
+    }
+
+    pub fn record_statement(&mut self, span: Span, m: Measurement) {
+        if self.suspended == 0 {
+            Self::record(&mut self.statements, String::new(), span, m);
+        }
+    }
+
+    pub fn record_call(&mut self, name: &str, span: Span, m: Measurement) {
+        if self.suspended == 0 {
+            Self::record(&mut self.calls, name.to_string(), span, m);
+        }
+    }
+
+    fn record(
+        records: &mut HashMap<(usize, usize), Record>,
+        name: String,
+        span: Span,
+        m: Measurement,
+    ) {
+        let record = records.entry((span.lo, span.hi)).or_insert(Record {
+            name,
+            span,
+            count: 0,
+            time: Duration::ZERO,
+            input_events: 0,
+            output_events: 0,
+            memory_bytes: 0,
+        });
+        record.count += 1;
+        record.time += m.time;
+        record.input_events += m.input;
+        if let Some(output) = m.output {
+            record.output_events += output.events;
+            record.memory_bytes = record.memory_bytes.max(output.memory_bytes);
+        }
+    }
+
+    pub fn finish(self, code: &str, time: Duration) -> Profile {
+        let entries = |records: HashMap<(usize, usize), Record>, is_statement: bool| {
+            let mut entries: Vec<ProfileEntry> = records
+                .into_values()
+                .map(|r| {
+                    let location = Location::from_span(code, r.span);
+                    let name = match is_statement {
+                        true => statement_name(&location),
+                        false => r.name,
+                    };
+                    ProfileEntry {
+                        name,
+                        line: location.line,
+                        column: location.column,
+                        count: r.count,
+                        time_ms: r.time.as_secs_f64() * 1000.0,
+                        input_events: r.input_events,
+                        output_events: r.output_events,
+                        memory_bytes: r.memory_bytes,
+                    }
+                })
+                .collect();
+            entries.sort_by_key(|e| (e.line, e.column));
+            entries
+        };
+        Profile {
+            time_ms: time.as_secs_f64() * 1000.0,
+            statements: entries(self.statements, true),
+            calls: entries(self.calls, false),
+        }
+    }
+}
+
+/// The code of a statement, shortened to its first line
+fn statement_name(location: &Location) -> String {
+    let code: String = location.snippet.chars().skip(location.column - 1).collect();
+    let code = code.trim();
+    match code.char_indices().nth(80) {
+        Some((i, _)) => format!("{}...", &code[..i]),
+        None => code.to_string(),
+    }
+}
+
+/// The variables a statement reads, not counting those only read inside lambdas
+pub(crate) fn vars_read<'a>(expr: &'a Expr, vars: &mut Vec<&'a str>) {
+    use crate::ast::Expr_::*;
+    match &expr.node {
+        Var(var) => {
+            if !vars.contains(&var.as_str()) {
+                vars.push(var);
+            }
+        }
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b) => {
+            vars_read(a, vars);
+            vars_read(b, vars);
+        }
+        Equal(a, b) | NotEqual(a, b) | Less(a, b) | LessEqual(a, b) => {
+            vars_read(a, vars);
+            vars_read(b, vars);
+        }
+        Greater(a, b) | GreaterEqual(a, b) | And(a, b) | Or(a, b) | Index(a, b) => {
+            vars_read(a, vars);
+            vars_read(b, vars);
+        }
+        Not(e) | Assign(_, e) | Return(e) | Function(_, e) => vars_read(e, vars),
+        For(_, list, _) => vars_read(list, vars),
+        If(ifs) => {
+            for (cond, _) in ifs {
+                vars_read(cond, vars);
+            }
+        }
+        List(items) => {
+            for item in items {
+                vars_read(item, vars);
+            }
+        }
+        Dict(d) => {
+            for val in d.values() {
+                vars_read(val, vars);
+            }
+        }
+        Lambda(..) | Def(..) | Import(_) | Bool(_) | Number(_) | String(_) => (),
+    }
+}
+
+pub fn count_events(data: &DataType) -> usize {
+    match data {
+        DataType::Event(_) => 1,
+        DataType::List(l) => l.iter().map(count_events).sum(),
+        DataType::Dict(d) => d.values().map(count_events).sum(),
+        _ => 0,
+    }
+}
+
+/// A rough estimate of how many bytes a value takes up in memory
+pub fn estimate_memory(data: &DataType) -> usize {
+    size_of::<DataType>()
+        + match data {
+            DataType::String(s) => s.capacity(),
+            DataType::Event(e) => estimate_event_memory(e),
+            DataType::List(l) => l.iter().map(estimate_memory).sum(),
+            DataType::Dict(d) => d
+                .iter()
+                .map(|(k, v)| k.capacity() + estimate_memory(v))
+                .sum(),
+            _ => 0,
+        }
+}
+
+fn estimate_event_memory(event: &Event) -> usize {
+    event
+        .data
+        .iter()
+        .map(|(k, v)| k.capacity() + estimate_json_memory(v))
+        .sum()
+}
+
+fn estimate_json_memory(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::String(s) => s.capacity(),
+            Value::Array(a) => a.iter().map(estimate_json_memory).sum(),
+            Value::Object(o) => o
+                .iter()
+                .map(|(k, v)| k.capacity() + estimate_json_memory(v))
+                .sum(),
+            _ => 0,
+        }
+}
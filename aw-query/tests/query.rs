@@ -172,6 +172,98 @@ mod query_tests {
         assert_err_type!(res, QueryError::InvalidType(_));
     }
 
+    #[test]
+    fn test_comparison() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return 1!=2;", true),
+            ("return 1!=1;", false),
+            (r#"return "a"!="b";"#, true),
+            ("return 1<2;", true),
+            ("return 2<1;", false),
+            ("return 2<=2;", true),
+            ("return 3>2;", true),
+            ("return 2>=3;", false),
+            (r#"return "abc"<"abd";"#, true),
+            (r#"return "b">="a";"#, true),
+            ("return False<True;", true),
+            ("duration = 7200; return duration > 3600;", true),
+        ];
+        for (code, expected) in cases {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                DataType::Bool(b) => assert_eq!(b, expected, "{code}"),
+                ref data => panic!("Wrong datatype for {code}, {data:?}"),
+            };
+        }
+
+        // different types comparison (should raise an error)
+        let res = aw_query::query("return 1<\"2\";", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return True!=1;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        // lists can be compared for equality, but are not ordered
+        let res = aw_query::query("return [1]<[2];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return True and True;", true),
+            ("return True and False;", false),
+            ("return False or True;", true),
+            ("return False or False;", false),
+            ("return not False;", true),
+            ("return not not True;", true),
+            // and binds tighter than or
+            ("return True or True and False;", true),
+            ("return (True or True) and False;", false),
+            // comparisons bind tighter than boolean operators
+            ("return 1 < 2 and 3 > 2;", true),
+            ("return not 1 == 2;", true),
+            ("n = 5; return n > 1 and n < 10 or n == 100;", true),
+        ];
+        for (code, expected) in cases {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                DataType::Bool(b) => assert_eq!(b, expected, "{code}"),
+                ref data => panic!("Wrong datatype for {code}, {data:?}"),
+            };
+        }
+
+        // The right hand side is not evaluated when the left hand side decides the result
+        let code = "return False and no_such_var;";
+        assert_eq!(
+            aw_query::query(code, &interval, &ds).unwrap(),
+            DataType::Bool(false)
+        );
+        let code = "return True or no_such_var;";
+        assert_eq!(
+            aw_query::query(code, &interval, &ds).unwrap(),
+            DataType::Bool(true)
+        );
+
+        let res = aw_query::query("return 1 and True;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return not 1;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        // Conditions in if statements
+        let code = "
+            duration = 4000; long = False;
+            if duration > 3600 and not long { long = True; }
+            return long;";
+        assert_eq!(
+            aw_query::query(code, &interval, &ds).unwrap(),
+            DataType::Bool(true)
+        );
+    }
+
     #[test]
     fn test_return() {
         let ds = setup_datastore_empty();
@@ -333,6 +425,1527 @@ mod query_tests {
         }
     }
 
+    #[test]
+    fn test_for() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            "
+            sum = 0;
+            for n in [1, 2, 3] { sum = sum + n; }
+            return sum;",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(6.0)
+        );
+
+        // Nested loops and conditions in the loop body
+        let code = String::from(
+            "
+            pairs = [];
+            for a in [1, 2] {
+                for b in [1, 2] {
+                    if a != b { pairs = pairs + [[a, b]]; }
+                }
+            }
+            return pairs;",
+        );
+        let expected: DataType = DataType::List(vec![
+            DataType::List(vec![DataType::Number(1.0), DataType::Number(2.0)]),
+            DataType::List(vec![DataType::Number(2.0), DataType::Number(1.0)]),
+        ]);
+        assert_eq!(aw_query::query(&code, &interval, &ds).unwrap(), expected);
+
+        // Loop over an empty list
+        let code = String::from("n = 0; for x in [] { n = 1; } return n;");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(0.0)
+        );
+
+        let code = String::from("for x in 1 { } return 1;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("double = lambda(x) { return x * 2; }; return double(4);");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(8.0)
+        );
+
+        let code = String::from("add = lambda(a, b) { return a + b; }; return add(1, 2);");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(3.0)
+        );
+
+        // Lambdas can read global variables, but assignments stay local to the call
+        let code = String::from(
+            "
+            offset = 10;
+            f = lambda(x) { offset = 0; return x + offset; };
+            res = f(1);
+            return [res, offset];",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::List(vec![DataType::Number(1.0), DataType::Number(10.0)])
+        );
+
+        // Parameters shadow outer variables without modifying them
+        let code = String::from("x = 1; f = lambda(x) { return x; }; f(2); return x;");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(1.0)
+        );
+
+        let code = String::from("f = lambda(x) { return x; }; return f(1, 2);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Endless recursion is stopped
+        let code = String::from("f = lambda(x) { return f(x); }; return f(1);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::RecursionLimitError(_));
+    }
+
+    #[test]
+    fn test_closure() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        // Lambdas capture the variables of the calls they are created in
+        let code = String::from(
+            "def adder(n) { return lambda(x) { return x + n; }; }
+            add1 = adder(1);
+            add10 = adder(10);
+            return [add1(2), add10(2)];",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::List(vec![DataType::Number(3.0), DataType::Number(12.0)])
+        );
+
+        // Nested closures, and a def in a def which calls itself
+        let code = String::from(
+            "def outer(a) {
+                def countdown(n) { if n > 0 { return countdown(n - 1); } return a; }
+                return lambda(b) { return lambda() { return [countdown(3), b]; }; };
+            }
+            f = outer(1);
+            g = f(2);
+            return g();",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::List(vec![DataType::Number(1.0), DataType::Number(2.0)])
+        );
+
+        // The variables of the caller are not visible
+        let code = String::from(
+            "def get() { return secret; }
+            def call() { secret = 1; return get(); }
+            return call();",
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+    }
+
+    #[test]
+    fn test_map_filter_reduce() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("return map([1, 2, 3], lambda(x) { return x * x; });");
+        let expected = DataType::List(vec![
+            DataType::Number(1.0),
+            DataType::Number(4.0),
+            DataType::Number(9.0),
+        ]);
+        assert_eq!(aw_query::query(&code, &interval, &ds).unwrap(), expected);
+
+        let code = String::from("return filter([1, 2, 3, 4], lambda(x) { return x % 2 == 0; });");
+        let expected = DataType::List(vec![DataType::Number(2.0), DataType::Number(4.0)]);
+        assert_eq!(aw_query::query(&code, &interval, &ds).unwrap(), expected);
+
+        let code =
+            String::from("return reduce([1, 2, 3], lambda(acc, x) { return acc + x; }, 10);");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(16.0)
+        );
+
+        // Builtin functions can be passed as well
+        let code = String::from("return map([[], []], sum_durations);");
+        let expected = DataType::List(vec![DataType::Number(0.0), DataType::Number(0.0)]);
+        assert_eq!(aw_query::query(&code, &interval, &ds).unwrap(), expected);
+
+        let code = String::from("return filter([1, 2], lambda(x) { return x; });");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return map([1, 2], 1);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_for_buckets() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            "
+            total = 0;
+            for bucket in query_bucket_names() {
+                total = total + sum_durations(query_bucket(bucket));
+            }
+            counts = map(query_bucket_names(), lambda(b) { return [b, sum_durations(query_bucket(b))]; });
+            return [total, counts];",
+        );
+        let expected = DataType::List(vec![
+            DataType::Number(0.0),
+            DataType::List(vec![DataType::List(vec![
+                DataType::String(BUCKET_ID.to_string()),
+                DataType::Number(0.0),
+            ])]),
+        ]);
+        assert_eq!(aw_query::query(&code, &interval, &ds).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_def() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            "
+            def square(x) { return x * x; }
+            def sum_squares(a, b) { return square(a) + square(b); }
+            return sum_squares(2, 3);",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(13.0)
+        );
+
+        // Recursion
+        let code = String::from(
+            "
+            def fact(n) {
+                if n <= 1 { return 1; }
+                else { return n * fact(n - 1); }
+            }
+            return fact(5);",
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(120.0)
+        );
+
+        let code = String::from("def one() { return 1; } return one();");
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(1.0)
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let library = aw_models::QueryLibrary {
+            name: "util".to_string(),
+            code: r#"
+                def events(bucket) { return query_bucket(bucket); }
+                def active_time(bucket) { return sum_durations(events(bucket)); }
+            "#
+            .to_string(),
+        };
+        aw_query::library::validate(&library).unwrap();
+        ds.set_key_value(
+            &aw_query::library::key("util"),
+            &serde_json::to_string(&library).unwrap(),
+        )
+        .unwrap();
+
+        let code = format!(r#"import "util"; return active_time("{BUCKET_ID}");"#);
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(0.0)
+        );
+
+        // Functions of the library call each other, not what the query gives their names
+        let code = format!(
+            r#"import "util"; events = query_bucket("{BUCKET_ID}"); return active_time("{BUCKET_ID}");"#
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(0.0)
+        );
+
+        // Functions imported inside a function can still call each other
+        let code = format!(
+            r#"def total(bucket) {{ import "util"; return active_time(bucket); }} return total("{BUCKET_ID}");"#
+        );
+        assert_eq!(
+            aw_query::query(&code, &interval, &ds).unwrap(),
+            DataType::Number(0.0)
+        );
+
+        // Changing the library changes the result of queries which imported it
+        let options = aw_query::QueryOptions::default();
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        let change = aw_datastore::Change::KeyValue(aw_query::library::key("util"));
+        assert!(output.dependencies.affected_by(&change, &interval));
+
+        let code = String::from(r#"import "no_such_library"; return 1;"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::ImportError(_));
+
+        // Libraries may only contain function definitions
+        let library = aw_models::QueryLibrary {
+            name: "invalid".to_string(),
+            code: "a = 1;".to_string(),
+        };
+        let res = aw_query::library::validate(&library);
+        assert_err_type!(res, QueryError::ImportError(_));
+
+        let library = aw_models::QueryLibrary {
+            name: "not a valid name".to_string(),
+            code: "".to_string(),
+        };
+        let res = aw_query::library::validate(&library);
+        assert_err_type!(res, QueryError::ImportError(_));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        // Runtime errors point at the innermost expression which failed
+        let code = String::from("a = 1;\nreturn a + (2 * \"b\");");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert!(matches!(d.error, QueryError::InvalidType(_)));
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column), (2, 13));
+        assert_eq!((loc.end_line, loc.end_column), (2, 20));
+        assert_eq!(loc.snippet, "return a + (2 * \"b\");");
+
+        let code = String::from("return no_such_var;");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column, loc.end_column), (1, 8, 19));
+
+        // Errors inside lambdas point into the lambda body
+        let code = String::from("f = lambda(x) {\n  return x / 0;\n};\nreturn f(1);");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert!(matches!(d.error, QueryError::MathError(_)));
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column), (2, 10));
+        assert_eq!(loc.snippet, "  return x / 0;");
+
+        // Parsing errors point at the unexpected token
+        let code = String::from("a = 1;\nreturn a +;");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert!(matches!(d.error, QueryError::ParsingError(_)));
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column), (2, 11));
+
+        let code = String::from("return 1 $ 2;");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert_eq!(d.location.unwrap().column, 10);
+
+        let code = String::from("return (1;\n");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column), (1, 10));
+
+        let code = String::from("return (1");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert_eq!(
+            d.to_string(),
+            "ParsingError(\"Unexpected end of query\") at line 1, column 10"
+        );
+
+        // Errors inside imported functions point at the call in the query
+        let library = aw_models::QueryLibrary {
+            name: "broken".to_string(),
+            code: "def half(x) { return x / 0; }".to_string(),
+        };
+        ds.set_key_value(
+            &aw_query::library::key("broken"),
+            &serde_json::to_string(&library).unwrap(),
+        )
+        .unwrap();
+        let code = String::from("import \"broken\";\nreturn half(1);");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        let loc = d.location.unwrap();
+        assert_eq!((loc.line, loc.column, loc.end_column), (2, 8, 15));
+
+        // Errors which are not caused by a specific expression have no location
+        let code = String::from("a = 1;");
+        let d = aw_query::query_diagnostic(&code, &interval, &ds).unwrap_err();
+        assert!(matches!(d.error, QueryError::EmptyQuery()));
+        assert!(d.location.is_none());
+    }
+
+    #[test]
+    fn test_check() {
+        let ds = setup_datastore_populated();
+        let check = |code: &str| -> Vec<String> {
+            aw_query::check(code, &ds)
+                .iter()
+                .map(|d| d.to_string())
+                .collect()
+        };
+
+        // Valid queries have no errors
+        let valid = [
+            r#"
+            events = flood(query_bucket(find_bucket("testid")));
+            events = filter_keyvals(events, "key", ["value"]);
+            events = merge_events_by_keys(events, ["key"]);
+            return {"events": sort_by_duration(events), "duration": sum_durations(events)};"#,
+            r#"
+            if 1 < 2 { a = 1; } else { b = 2; }
+            total = 0;
+            for n in [1, 2, 3] { total = total + n; }
+            double = lambda(x) { return x * y; };
+            y = 2;
+            def sum(l) { return reduce(l, lambda(a, b) { return a + b; }, 0); }
+            return [a, b, map([total], double), sum([1, 2]), TIMEINTERVAL];"#,
+            "RETURN = concat();",
+        ];
+        for code in valid {
+            assert_eq!(check(code), Vec::<String>::new(), "{code}");
+        }
+
+        assert_eq!(
+            check(r#"return flood("testid");"#),
+            vec![
+                r#"InvalidType("Parameter 1 of flood expected List of Event, got String") at line 1, column 14"#
+            ]
+        );
+        assert_eq!(
+            check("return flood(query_bucket(\"testid\"), 5);"),
+            vec![
+                r#"InvalidFunctionParameters("flood expected 1 parameters, got 2") at line 1, column 8"#
+            ]
+        );
+        assert_eq!(
+            check("return find_bucket();"),
+            vec![
+                r#"InvalidFunctionParameters("find_bucket expected 1 to 2 parameters, got 0") at line 1, column 8"#
+            ]
+        );
+        assert_eq!(
+            check("a = 1;\nreturn a + b;"),
+            vec![r#"VariableNotDefined("b") at line 2, column 12"#]
+        );
+        assert_eq!(
+            check("return no_such_function();"),
+            vec![r#"VariableNotDefined("no_such_function") at line 1, column 8"#]
+        );
+        assert_eq!(
+            check(r#"return query_bucket("no_such_bucket");"#),
+            vec![r#"BucketQueryError("There is no bucket 'no_such_bucket'") at line 1, column 21"#]
+        );
+        assert_eq!(
+            check(r#"return find_bucket("no_such_bucket");"#),
+            vec![
+                r#"BucketQueryError("There is no bucket matching filter 'no_such_bucket'") at line 1, column 20"#
+            ]
+        );
+        assert_eq!(
+            check("return 1 + \"a\";"),
+            vec![r#"InvalidType("Cannot use + on Number and String") at line 1, column 8"#]
+        );
+        assert_eq!(
+            check("a = 1; return a(2);"),
+            vec![r#"InvalidType("a is a Number, not a function") at line 1, column 15"#]
+        );
+        assert_eq!(
+            check("if 1 { a = 1; } return a;"),
+            vec![r#"InvalidType("Condition expected Bool, got Number") at line 1, column 4"#]
+        );
+        assert_eq!(check("a = 1;"), vec!["EmptyQuery"]);
+        // A return inside a lambda does not count as a return from the query
+        assert_eq!(check("f = lambda() { return 1; };"), vec!["EmptyQuery"]);
+
+        // All errors are reported, not just the first
+        assert_eq!(check("a = b; return c;").len(), 2);
+        // Parsing errors are reported as well
+        assert_eq!(check("return (1;").len(), 1);
+    }
+
+    #[test]
+    fn test_explain() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let options = aw_query::QueryOptions {
+            explain: true,
+            ..Default::default()
+        };
+
+        let code = format!(
+            r#"events = query_bucket("{BUCKET_ID}");
+            total = 0;
+            for e in [1, 2, 3] {{
+                total = total + sum_durations(events);
+            }}
+            return flood(events);"#
+        );
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        let profile = output.profile.unwrap();
+        assert!(profile.time_ms >= 0.0);
+
+        let names: Vec<&str> = profile.statements.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                r#"events = query_bucket("testid");"#,
+                "total = 0;",
+                "for e in [1, 2, 3] {",
+                "total = total + sum_durations(events);",
+                "return flood(events);",
+            ]
+        );
+        let assign = &profile.statements[0];
+        assert_eq!((assign.line, assign.column, assign.count), (1, 1, 1));
+        assert_eq!((assign.input_events, assign.output_events), (0, 2));
+        assert!(assign.memory_bytes > 0);
+        // Statements in loops are reported once with the totals of all runs
+        let in_loop = &profile.statements[3];
+        assert_eq!((in_loop.line, in_loop.count), (4, 3));
+        assert_eq!((in_loop.input_events, in_loop.output_events), (6, 0));
+
+        let calls: Vec<(&str, usize, usize, usize)> = profile
+            .calls
+            .iter()
+            .map(|e| (e.name.as_str(), e.count, e.input_events, e.output_events))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("query_bucket", 1, 0, 2),
+                ("sum_durations", 3, 6, 0),
+                ("flood", 1, 2, 1)
+            ]
+        );
+
+        // Without explain there is no profile
+        let options = aw_query::QueryOptions::default();
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        assert!(output.profile.is_none());
+    }
+
+    #[test]
+    fn test_limits() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str, options: &aw_query::QueryOptions| {
+            aw_query::query_with_options(code, &interval, &ds, options).map_err(|d| d.error)
+        };
+
+        // Time limit
+        let options = aw_query::QueryOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert_err_type!(run("return 1;", &options), QueryError::TimeLimitError(_));
+
+        // Events loaded per query_bucket, the bucket has two events
+        let code = format!(r#"return query_bucket("{BUCKET_ID}");"#);
+        let mut options = aw_query::QueryOptions {
+            max_bucket_events: Some(1),
+            ..Default::default()
+        };
+        assert_err_type!(run(&code, &options), QueryError::EventLimitError(_));
+        options.max_bucket_events = Some(2);
+        run(&code, &options).unwrap();
+
+        // Output size
+        let mut options = aw_query::QueryOptions {
+            max_output_bytes: Some(10),
+            ..Default::default()
+        };
+        assert_err_type!(
+            run(r#"return "more than ten bytes";"#, &options),
+            QueryError::OutputLimitError(_)
+        );
+        options.max_output_bytes = Some(10);
+        // The quotes count as well
+        run(r#"return "12345678";"#, &options).unwrap();
+
+        // Cancelling from another thread stops a running query
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = aw_query::QueryOptions {
+            cancel: Some(Arc::clone(&cancel)),
+            ..Default::default()
+        };
+        let list = format!("[{}]", vec!["1"; 100].join(", "));
+        let code = format!(
+            "n = 0; for a in {list} {{ for b in {list} {{ for c in {list} {{ for d in {list} {{ n = n + 1; }} }} }} }} return n;"
+        );
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        assert_err_type!(run(&code, &options), QueryError::Cancelled());
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_query_timeperiods() {
+        let ds = setup_datastore_populated();
+        let intervals: Vec<TimeInterval> = (0..10)
+            .map(|i| {
+                let ti = format!("20{i:02}-01-01T00:00:00Z/20{i:02}-01-02T00:00:00Z");
+                TimeInterval::new_from_string(&ti).unwrap()
+            })
+            .collect();
+        let options = aw_query::QueryOptions::default();
+
+        // The results are in the order of the timeperiods, however many threads are used
+        for threads in [1, 3, 16] {
+            let outputs = aw_query::query_timeperiods(
+                "return TIMEINTERVAL;",
+                &intervals,
+                &ds,
+                &options,
+                threads,
+            )
+            .unwrap();
+            let results: Vec<DataType> = outputs.into_iter().map(|o| o.result).collect();
+            let expected: Vec<DataType> = intervals
+                .iter()
+                .map(|ti| DataType::String(ti.to_string()))
+                .collect();
+            assert_eq!(results, expected);
+        }
+
+        // A failing timeperiod fails the whole query, with its own error rather than the
+        // cancellation of the others
+        let code = format!(
+            r#"
+            if TIMEINTERVAL == "{}" {{ return 1 / 0; }}
+            n = 0;
+            l = [{}];
+            for a in l {{ for b in l {{ n = n + 1; }} }}
+            return n;"#,
+            intervals[4],
+            vec!["1"; 300].join(", ")
+        );
+        for threads in [1, 4] {
+            let res = aw_query::query_timeperiods(&code, &intervals, &ds, &options, threads)
+                .map_err(|d| d.error);
+            assert_err_type!(res, QueryError::MathError(_));
+        }
+
+        let outputs = aw_query::query_timeperiods("return 1;", &[], &ds, &options, 4).unwrap();
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let options = aw_query::QueryOptions::default();
+
+        let output = aw_query::query_with_options("return 1;", &interval, &ds, &options).unwrap();
+        assert_eq!(output.dependencies, aw_query::Dependencies::default());
+
+        let code = format!(
+            r#"
+            events = query_bucket(find_bucket("{BUCKET_ID}"));
+            return events;"#
+        );
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        let deps = output.dependencies;
+        assert_eq!(deps.buckets.iter().collect::<Vec<_>>(), vec![BUCKET_ID]);
+        assert!(deps.bucket_list);
+        assert!(deps.libraries.is_empty());
+
+        // Changes affect the result only if the query read what they changed
+        use aw_datastore::Change;
+        let later =
+            TimeInterval::new_from_string("2090-01-01T00:00:00Z/2090-01-02T00:00:00Z").unwrap();
+        let change = Change::Events {
+            bucket_id: BUCKET_ID.to_string(),
+            range: Some((*later.start(), *later.end())),
+        };
+        assert!(deps.affected_by(&change, &later));
+        assert!(!deps.affected_by(&change, &interval));
+        assert!(deps.affected_by(&Change::Buckets, &interval));
+        let library_change = Change::KeyValue("query_libraries.lib".to_string());
+        assert!(!deps.affected_by(&library_change, &interval));
+    }
+
+    #[test]
+    fn test_bin_by_time() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-03T00:00:00Z/2000-01-04T00:00:00Z").unwrap();
+        // 2000-01-03 was a Monday
+        let e = Event {
+            id: None,
+            timestamp: chrono::DateTime::parse_from_rfc3339("2000-01-03T03:30:00Z")
+                .unwrap()
+                .into(),
+            duration: Duration::minutes(60),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.insert_events(BUCKET_ID, &[e]).unwrap();
+        let run = |code: &str| match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Dict(d) => d,
+            data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Every bin of the timeperiod is returned, and the event is split between two
+        let code = format!(r#"return bin_by_time(query_bucket("{BUCKET_ID}"), "1h");"#);
+        let bins = run(&code);
+        assert_eq!(bins.len(), 24);
+        assert_eq!(bins["2000-01-03T03:00:00Z"], DataType::Number(1800.0));
+        assert_eq!(bins["2000-01-03T04:00:00Z"], DataType::Number(1800.0));
+        assert_eq!(bins["2000-01-03T05:00:00Z"], DataType::Number(0.0));
+
+        let code = format!(r#"return group_by_hour_of_day(query_bucket("{BUCKET_ID}"));"#);
+        let hours = run(&code);
+        assert_eq!(hours.len(), 24);
+        assert_eq!(hours["3"], DataType::Number(1800.0));
+        assert_eq!(hours["4"], DataType::Number(1800.0));
+
+        // Before the start of the day the event counts for the day before, Sunday
+        let code = format!(r#"return group_by_weekday(query_bucket("{BUCKET_ID}"), "04:00");"#);
+        let days = run(&code);
+        assert_eq!(days.len(), 7);
+        assert_eq!(days["Sun"], DataType::Number(1800.0));
+        assert_eq!(days["Mon"], DataType::Number(1800.0));
+
+        // Without an argument the start of the day is read from the settings
+        ds.set_key_value("settings.start_of_day", r#""04:00""#)
+            .unwrap();
+        let code = format!(r#"return group_by_weekday(query_bucket("{BUCKET_ID}"));"#);
+        assert_eq!(run(&code), days);
+        let options = aw_query::QueryOptions::default();
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        assert!(output.dependencies.settings.contains("start_of_day"));
+        let change = aw_datastore::Change::KeyValue("settings.start_of_day".to_string());
+        assert!(output.dependencies.affected_by(&change, &interval));
+
+        let code = format!(r#"return bin_by_time(query_bucket("{BUCKET_ID}"), "1x");"#);
+        assert_err_type!(
+            aw_query::query(&code, &interval, &ds),
+            QueryError::InvalidFunctionParameters(_)
+        );
+        // Too many bins
+        let long_interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = format!(r#"return bin_by_time(query_bucket("{BUCKET_ID}"), "1h");"#);
+        assert_err_type!(
+            aw_query::query(&code, &long_interval, &ds),
+            QueryError::InvalidFunctionParameters(_)
+        );
+        let code = format!(r#"return group_by_weekday(query_bucket("{BUCKET_ID}"), "25:00");"#);
+        assert_err_type!(
+            aw_query::query(&code, &interval, &ds),
+            QueryError::InvalidFunctionParameters(_)
+        );
+    }
+
+    #[test]
+    fn test_split_by_day() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-03T00:00:00Z").unwrap();
+        let e = Event {
+            id: None,
+            timestamp: chrono::DateTime::parse_from_rfc3339("2000-01-01T20:00:00Z")
+                .unwrap()
+                .into(),
+            duration: Duration::hours(10),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.insert_events(BUCKET_ID, &[e]).unwrap();
+
+        // Days start at 04:00 in UTC+1, at 03:00 UTC
+        let code = format!(
+            r#"return split_by_day(query_bucket("{BUCKET_ID}"), "Europe/Stockholm", "04:00");"#
+        );
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = Vec::try_from(&result).unwrap();
+        let durations: Vec<_> = events.iter().map(|e| e.duration).collect();
+        assert_eq!(durations, vec![Duration::hours(7), Duration::hours(3)]);
+
+        // Without a start of day the setting is used, which is midnight if unset
+        let code = format!(r#"return split_by_day(query_bucket("{BUCKET_ID}"), "UTC");"#);
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = Vec::try_from(&result).unwrap();
+        let durations: Vec<_> = events.iter().map(|e| e.duration).collect();
+        assert_eq!(durations, vec![Duration::hours(4), Duration::hours(6)]);
+
+        let code = format!(r#"return split_by_day(query_bucket("{BUCKET_ID}"), "Mars/Base");"#);
+        assert_err_type!(
+            aw_query::query(&code, &interval, &ds),
+            QueryError::InvalidFunctionParameters(_)
+        );
+    }
+
+    #[test]
+    fn test_statistics() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let events: Vec<Event> = [(0, 1, "a"), (10, 4, "b"), (20, 2, "a"), (30, 1, "c")]
+            .into_iter()
+            .map(|(start, duration, value)| Event {
+                id: None,
+                timestamp: *interval.start() + Duration::seconds(start),
+                duration: Duration::seconds(duration),
+                data: json_map! {"key": json!(value)},
+            })
+            .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(
+            r#"
+            events = query_bucket("{BUCKET_ID}");
+            return {{
+                "count": count(events),
+                "mean": mean_duration(events),
+                "median": median_duration(events),
+                "p100": percentile(events, 100),
+                "first": min_timestamp(events),
+                "last": max_timestamp(events),
+                "top": top_n(events, "key", 2)
+            }};"#
+        );
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({
+                "count": 4.0,
+                "mean": 2.0,
+                "median": 1.5,
+                "p100": 4.0,
+                "first": "2000-01-01T00:00:00Z",
+                "last": "2000-01-01T00:00:30Z",
+                "top": [
+                    {"value": "b", "duration": 4.0},
+                    {"value": "a", "duration": 3.0},
+                ],
+            })
+        );
+
+        // Statistics of no events are None
+        let result = aw_query::query("return mean_duration([]);", &interval, &ds).unwrap();
+        assert_eq!(result, DataType::None());
+
+        let code = format!(r#"return percentile(query_bucket("{BUCKET_ID}"), 101);"#);
+        assert_err_type!(
+            aw_query::query(&code, &interval, &ds),
+            QueryError::InvalidFunctionParameters(_)
+        );
+    }
+
+    #[test]
+    fn test_sessionize() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let events: Vec<Event> = [(0, 60, "a"), (120, 600, "b"), (3600, 60, "c")]
+            .into_iter()
+            .map(|(start, duration, value)| Event {
+                id: None,
+                timestamp: *interval.start() + Duration::seconds(start),
+                duration: Duration::seconds(duration),
+                data: json_map! {"app": json!(value)},
+            })
+            .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+        let run = |gap: &str| {
+            let code = format!(r#"return sessionize(query_bucket("{BUCKET_ID}"), {gap}, "app");"#);
+            aw_query::query(&code, &interval, &ds)
+        };
+
+        let expected = json!([
+            {
+                "id": null,
+                "timestamp": "2000-01-01T00:00:00Z",
+                "duration": 720.0,
+                "data": {"active_duration": 660.0, "event_count": 2, "app": "b"}
+            },
+            {
+                "id": null,
+                "timestamp": "2000-01-01T01:00:00Z",
+                "duration": 60.0,
+                "data": {"active_duration": 60.0, "event_count": 1, "app": "c"}
+            }
+        ]);
+        // The gap is a duration, a string such as "15m" or a number of seconds
+        for gap in [r#"duration("15m")"#, r#""15m""#, "900"] {
+            assert_eq!(serde_json::to_value(run(gap).unwrap()).unwrap(), expected);
+        }
+        assert_err_type!(run("-1"), QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_focus_metrics() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let events: Vec<Event> = [
+            (9 * 3600, 600, "a"),
+            (9 * 3600 + 600, 1200, "a"),
+            (9 * 3600 + 1800, 20, "b"),
+            (10 * 3600, 5, "a"),
+        ]
+        .into_iter()
+        .map(|(start, duration, value)| Event {
+            id: None,
+            timestamp: *interval.start() + Duration::seconds(start),
+            duration: Duration::seconds(duration),
+            data: json_map! {"app": json!(value)},
+        })
+        .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(r#"return focus_metrics(query_bucket("{BUCKET_ID}"), "app", "00:00");"#);
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({
+                "switches": 2.0,
+                "switches_per_hour": {
+                    "2000-01-01T09:00:00Z": 1.0,
+                    "2000-01-01T10:00:00Z": 1.0
+                },
+                "longest_focus": [
+                    {"value": "a", "timestamp": "2000-01-01T09:00:00Z", "duration": 1800.0},
+                    {"value": "b", "timestamp": "2000-01-01T09:30:00Z", "duration": 20.0}
+                ],
+                "dwell_times": [
+                    {"from": 0.0, "count": 1.0},
+                    {"from": 10.0, "count": 1.0},
+                    {"from": 30.0, "count": 0.0},
+                    {"from": 60.0, "count": 0.0},
+                    {"from": 300.0, "count": 0.0},
+                    {"from": 900.0, "count": 0.0},
+                    {"from": 1800.0, "count": 1.0},
+                    {"from": 3600.0, "count": 0.0}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_category_tree() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let events: Vec<Event> = [(0, 10, "vim"), (10, 5, "slack"), (15, 20, "mpv")]
+            .into_iter()
+            .map(|(start, duration, app)| Event {
+                id: None,
+                timestamp: *interval.start() + Duration::seconds(start),
+                duration: Duration::seconds(duration),
+                data: json_map! {"app": json!(app)},
+            })
+            .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(
+            r#"
+            events = categorize(query_bucket("{BUCKET_ID}"), [
+                [["Work"], {{"type": "regex", "regex": "slack"}}],
+                [["Work", "Programming"], {{"type": "regex", "regex": "vim"}}]
+            ]);
+            classes = [
+                {{"id": 0, "name": ["Work"], "rule": {{"type": "none"}}, "data": {{"score": 2}}}},
+                {{"id": 1, "name": ["Uncategorized"], "rule": {{"type": "none"}}, "data": {{"score": -1}}}}
+            ];
+            return [category_tree(events), category_tree(events, classes)];"#
+        );
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!([
+                {
+                    "duration": 35.0,
+                    "children": {
+                        "Uncategorized": {"duration": 20.0, "children": {}},
+                        "Work": {
+                            "duration": 15.0,
+                            "children": {
+                                "Programming": {"duration": 10.0, "children": {}}
+                            }
+                        }
+                    }
+                },
+                {
+                    "duration": 35.0,
+                    "score": 10.0,
+                    "children": {
+                        "Uncategorized": {"duration": 20.0, "score": -20.0, "children": {}},
+                        "Work": {
+                            "duration": 15.0,
+                            "score": 30.0,
+                            "children": {
+                                "Programming": {"duration": 10.0, "score": 20.0, "children": {}}
+                            }
+                        }
+                    }
+                }
+            ])
+        );
+
+        let code = format!(r#"return category_tree(query_bucket("{BUCKET_ID}"), [{{"id": 0}}]);"#);
+        match aw_query::query(&code, &interval, &ds) {
+            Err(QueryError::InvalidFunctionParameters(e)) => {
+                assert!(e.starts_with("Invalid classes"), "{e}")
+            }
+            res => panic!("Expected an invalid classes error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_datetime() {
+        let ds = setup_datastore_with_bucket();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let events: Vec<Event> = [(0, 3600), (22 * 3600, 7200)]
+            .into_iter()
+            .map(|(start, duration)| Event {
+                id: None,
+                timestamp: *interval.start() + Duration::seconds(start),
+                duration: Duration::seconds(duration),
+                data: json_map! {"key": json!("value")},
+            })
+            .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds);
+        let json = |code: &str| serde_json::to_value(run(code).unwrap()).unwrap();
+
+        // Datetimes and durations serialize like the timestamps and durations of events
+        assert_eq!(
+            json("return interval_start();"),
+            json!("2000-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            json("return interval_end() - duration(\"2h\");"),
+            json!("2000-01-01T22:00:00Z")
+        );
+        assert_eq!(
+            json("return interval_end() - interval_start();"),
+            json!(86400.0)
+        );
+        assert_eq!(
+            json(r#"return datetime("2000-01-01T01:00:00+01:00") == interval_start();"#),
+            json!(true)
+        );
+        assert_eq!(
+            json(r#"return [duration("1h") * 2, 3 * duration(60), duration("1h") / 4];"#),
+            json!([7200.0, 180.0, 900.0])
+        );
+        assert_eq!(
+            json(r#"return [duration("1d") / duration("1h"), seconds(duration(1.5))];"#),
+            json!([24.0, 1.5])
+        );
+        assert_eq!(
+            json(
+                r#"return [duration("1h") + interval_start() < interval_end(), duration(1) > duration(2)];"#
+            ),
+            json!([true, false])
+        );
+        assert_eq!(
+            json(r#"return str(interval_start());"#),
+            json!("2000-01-01T00:00:00Z")
+        );
+
+        // The timestamp and duration of events can be compared and computed with
+        let code = format!(
+            r#"
+            events = query_bucket("{BUCKET_ID}");
+            return map(events, lambda(e) {{ return e["timestamp"] + e["duration"]; }});"#
+        );
+        assert_eq!(
+            json(&code),
+            json!(["2000-01-02T00:00:00Z", "2000-01-01T01:00:00Z"])
+        );
+
+        // The last two hours of the interval, the events are clipped to it
+        let code = format!(
+            r#"
+            events = query_bucket("{BUCKET_ID}");
+            events = filter_period(events, interval_end() - duration("2h"), interval_end());
+            return sum_durations(events);"#
+        );
+        assert_eq!(json(&code), json!(7200.0));
+        let code = format!(
+            r#"
+            events = query_bucket("{BUCKET_ID}");
+            start = min_timestamp(events);
+            return filter_period(events, start, start + duration("30m"));"#
+        );
+        assert_eq!(json(&code)[0]["duration"], json!(1800.0));
+
+        // now() is recorded, so that the result isn't cached
+        let code = "return now() > interval_end();";
+        let output =
+            aw_query::query_with_options(code, &interval, &ds, &Default::default()).unwrap();
+        assert_eq!(output.result, DataType::Bool(true));
+        assert!(output.dependencies.now);
+
+        assert_err_type!(
+            run(r#"return interval_start() + 1;"#),
+            QueryError::InvalidType(_)
+        );
+        assert_err_type!(
+            run(r#"return duration("1h") / 0;"#),
+            QueryError::MathError(_)
+        );
+        assert_err_type!(
+            run(r#"return datetime("yesterday");"#),
+            QueryError::InvalidFunctionParameters(_)
+        );
+        let diagnostics = aw_query::check("return interval_start() * 2;", &ds);
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_indexing() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds);
+
+        assert_eq!(run("return [1, 2, 3][0];").unwrap(), DataType::Number(1.0));
+        assert_eq!(run("return [1, 2, 3][-1];").unwrap(), DataType::Number(3.0));
+        assert_eq!(
+            run(r#"return "abc"[1];"#).unwrap(),
+            DataType::String("b".to_string())
+        );
+        assert_eq!(
+            run(r#"d = {"a": {"b": [1, 2]}}; return d["a"]["b"][1] * 2;"#).unwrap(),
+            DataType::Number(4.0)
+        );
+        // Binds tighter than the operators
+        assert_eq!(
+            run("l = [1, 2]; return l[0] + l[1] * l[1];").unwrap(),
+            DataType::Number(5.0)
+        );
+
+        // Events are indexed by their fields
+        let code = format!(
+            r#"
+            e = query_bucket("{BUCKET_ID}")[0];
+            return {{"key": e["data"]["key"], "duration": e["duration"], "id": e["id"]}};"#
+        );
+        let result = run(&code).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({"key": "value", "duration": 0.0, "id": 2.0})
+        );
+
+        assert_err_type!(run("return [1][1];"), QueryError::IndexError(_));
+        assert_err_type!(run("return [1][0.5];"), QueryError::IndexError(_));
+        assert_err_type!(run(r#"return {"a": 1}["b"];"#), QueryError::IndexError(_));
+        assert_err_type!(run(r#"return [1]["a"];"#), QueryError::InvalidType(_));
+        assert_err_type!(run("return 1[0];"), QueryError::InvalidType(_));
+
+        // The static checker knows the types of indexed values
+        assert_eq!(aw_query::check("return [1, 2][0] + 1;", &ds).len(), 0);
+        assert_eq!(aw_query::check(r#"return [1, 2]["a"];"#, &ds).len(), 1);
+        assert_eq!(aw_query::check(r#"return [1][0] + "a";"#, &ds).len(), 1);
+    }
+
+    #[test]
+    fn test_stdlib() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| {
+            let result = aw_query::query(code, &interval, &ds).unwrap();
+            serde_json::to_value(&result).unwrap()
+        };
+
+        let code = r#"
+            return {
+                "len": [len("åäö"), len([1, 2]), len({"a": 1})],
+                "str": [str(1), str(1.5), str("a"), str([1, "a"])],
+                "case": [lower("AbC"), upper("AbC"), strip("  a b  ")],
+                "split": split("a.b.c", "."),
+                "join": join(["a", "b"], ", "),
+                "replace": replace("a-b-c", "-", "+"),
+                "affixes": [startswith("abc", "ab"), endswith("abc", "ab")],
+                "regex": [regex_group("v1.2", "v(\d+)\.(\d+)", 2), regex_group("v1.2", "v\d")],
+                "slice": [slice("abcdef", 1, -1), slice([1, 2, 3], -2), slice([1, 2], 5)],
+                "sorted": [sorted([3, 1, 2]), sorted(["b", "a"]), reversed([1, 2, 3])],
+                "dict": [keys({"b": 1, "a": 2}), values({"b": 1, "a": 2})],
+                "get": [get({"a": 1}, "a"), get({"a": 1}, "b", "default")],
+                "merge": merge({"a": 1, "b": 1}, {"b": 2})
+            };"#;
+        assert_eq!(
+            run(code),
+            json!({
+                "len": [3.0, 2.0, 1.0],
+                "str": ["1", "1.5", "a", "[1.0,\"a\"]"],
+                "case": ["abc", "ABC", "a b"],
+                "split": ["a", "b", "c"],
+                "join": "a, b",
+                "replace": "a+b+c",
+                "affixes": [true, false],
+                "regex": ["2", "v1"],
+                "slice": ["bcde", [2.0, 3.0], []],
+                "sorted": [[1.0, 2.0, 3.0], ["a", "b"], [3.0, 2.0, 1.0]],
+                "dict": [["a", "b"], [2.0, 1.0]],
+                "get": [1.0, "default"],
+                "merge": {"a": 1.0, "b": 2.0}
+            })
+        );
+        // A regex which doesn't match gives None
+        let result = aw_query::query(r#"return regex_group("a", "b");"#, &interval, &ds);
+        assert_eq!(result.unwrap(), DataType::None());
+
+        assert_err_type!(
+            aw_query::query("return sorted([1, \"a\"]);", &interval, &ds),
+            QueryError::InvalidType(_)
+        );
+    }
+
+    #[test]
+    fn test_rewrite_data() {
+        let ds = setup_datastore_with_bucket();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let titles = [
+            ("code", "main.rs - aw-server - Visual Studio Code"),
+            ("chrome.exe", "Inbox"),
+            ("Google-chrome", "News"),
+        ];
+        let events: Vec<Event> = titles
+            .iter()
+            .enumerate()
+            .map(|(i, (app, title))| Event {
+                id: None,
+                timestamp: chrono::DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")
+                    .unwrap()
+                    .into(),
+                duration: Duration::seconds(i as i64 + 1),
+                data: json_map! {"app": json!(app), "title": json!(title)},
+            })
+            .collect();
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(
+            r#"
+            events = sort_by_duration(query_bucket("{BUCKET_ID}"));
+            events = map_values(events, "app", {{"chrome.exe": "chrome", "Google-chrome": "chrome"}});
+            events = extract_regex(events, "title", " - ([\w-]+) - Visual Studio Code$", "project", 1);
+            events = rename_key(events, "app", "application");
+            events = set_key(events, "host", "laptop");
+            events = drop_keys(events, ["title"]);
+            events = map_data(events, lambda(data) {{
+                data = merge(data, {{"upper": upper(data["application"])}});
+                return data;
+            }});
+            return events;"#
+        );
+        let result = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = Vec::try_from(&result).unwrap();
+        let data: Vec<_> = events.into_iter().map(|e| e.data).collect();
+        assert_eq!(
+            data,
+            vec![
+                json_map! {"application": json!("chrome"), "host": json!("laptop"), "upper": json!("CHROME")},
+                json_map! {"application": json!("chrome"), "host": json!("laptop"), "upper": json!("CHROME")},
+                json_map! {"application": json!("code"), "host": json!("laptop"), "upper": json!("CODE"), "project": json!("aw-server")},
+            ]
+        );
+
+        let code = format!(
+            r#"return map_data(query_bucket("{BUCKET_ID}"), lambda(data) {{ return 1; }});"#
+        );
+        assert_err_type!(
+            aw_query::query(&code, &interval, &ds),
+            QueryError::InvalidType(_)
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let code = "a = 1;  # comment\n\treturn a;";
+        assert_eq!(
+            aw_query::normalize(code),
+            aw_query::normalize("a=1;return a;")
+        );
+        assert_ne!(
+            aw_query::normalize(code),
+            aw_query::normalize(r#"a=1;return "a";"#)
+        );
+        assert_ne!(
+            aw_query::normalize(r#"return "a b";"#),
+            aw_query::normalize(r#"return "a  b";"#)
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let ds = setup_datastore_with_bucket();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let event = Event {
+            id: None,
+            timestamp: "2000-01-01T00:00:00Z".parse().unwrap(),
+            duration: Duration::milliseconds(1500),
+            data: json_map! {"b": json!(1), "a": json!("value")},
+        };
+        ds.insert_events(BUCKET_ID, &[event]).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds);
+        let serialized = |code: &str| serde_json::to_string(&run(code).unwrap()).unwrap();
+
+        // Keys are in alphabetical order, so equal values always serialize the same
+        assert_eq!(
+            serialized(r#"return {"b": [1, "x"], "a": {"d": true, "c": 1.5}};"#),
+            r#"{"a":{"c":1.5,"d":true},"b":[1.0,"x"]}"#
+        );
+        assert_eq!(
+            serialized(&format!(r#"return query_bucket("{BUCKET_ID}")[0];"#)),
+            r#"{"data":{"a":"value","b":1},"duration":1.5,"id":1,"timestamp":"2000-01-01T00:00:00Z"}"#
+        );
+
+        // Lambdas and functions can't be serialized, so queries can't return them
+        match run("return {\"f\": lambda(x) { return x; }};") {
+            Err(QueryError::InvalidType(e)) => assert_eq!(
+                e,
+                "The query returned a lambda, which cannot be serialized, call it to get a value"
+            ),
+            res => panic!("Expected an InvalidType error, got {res:?}"),
+        }
+        assert_err_type!(run("return [print];"), QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_format() {
+        // Formatting is idempotent and keeps what the code does
+        let check = |code: &str, expected: &str| {
+            let formatted = aw_query::format(code).unwrap();
+            assert_eq!(formatted, expected);
+            assert_eq!(aw_query::format(&formatted).unwrap(), formatted);
+            assert_eq!(
+                aw_query::normalize(&aw_query::format(&formatted).unwrap()),
+                aw_query::normalize(&formatted)
+            );
+        };
+
+        check(
+            r#"events=flood(query_bucket("b"));not_afk=filter_keyvals(events,"status",["not-afk"]);return {"events":events,"duration":sum_durations(not_afk)};"#,
+            r#"events = flood(query_bucket("b"));
+not_afk = filter_keyvals(events, "status", ["not-afk"]);
+return {"duration": sum_durations(not_afk), "events": events};
+"#,
+        );
+
+        // Parentheses are only kept where they are needed
+        check(
+            "a = (1 + 2) * 3 - (4 - 5) - -x + (6 * 7);b = not (a or b) and -(a + 1)[0] == (c < d);",
+            "a = (1 + 2) * 3 - (4 - 5) - -x + 6 * 7;\nb = not (a or b) and -(a + 1)[0] == (c < d);\n",
+        );
+
+        // Comments are kept, the ones inside expressions are moved to before their statement
+        check(
+            r#"# Header
+
+import "lib";  # trailing
+def f(x) { # why
+  if x > 1 { return x; } elif x == 1 { return "one"; }
+  else {
+    # nothing
+  }
+  return [1, # first
+          "\"2\""];
+}
+for e in events { print(lambda(a) { return a; }); }
+# The end
+"#,
+            r#"# Header
+
+import "lib";  # trailing
+def f(x) {
+    # why
+    if x > 1 {
+        return x;
+    } elif x == 1 {
+        return "one";
+    } else {
+        # nothing
+    }
+    # first
+    return [1, "\"2\""];
+}
+for e in events {
+    print(lambda(a) {
+        return a;
+    });
+}
+# The end
+"#,
+        );
+
+        // Long lists are split into one item per line
+        let long = format!("return [{}];", ["\"aaaaaaaaaa\""; 10].join(","));
+        check(
+            &long,
+            &format!("return [\n{}\n];\n", ["    \"aaaaaaaaaa\""; 10].join(",\n")),
+        );
+
+        let e = aw_query::format("a = ;").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "ParsingError(\"Unexpected token Semi\") at line 1, column 5"
+        );
+    }
+
+    /// Runs queries with and without the optimizer on random events, which must give the same
+    /// results while the optimizer actually rewrote the queries
+    #[test]
+    fn test_optimizer() {
+        let ds = setup_datastore_empty();
+        let mut seed: u64 = 42;
+        let mut random = move |max: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % max
+        };
+        let start = chrono::DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        for (bucket_id, values) in [
+            (
+                "window",
+                vec![json!("a"), json!("b"), json!("c"), json!(1), json!(["a"])],
+            ),
+            ("afk", vec![json!("afk"), json!("not-afk")]),
+        ] {
+            let bucket = Bucket {
+                bid: None,
+                id: bucket_id.to_string(),
+                _type: "testtype".to_string(),
+                client: "testclient".to_string(),
+                hostname: "testhost".to_string(),
+                created: None,
+                data: json_map! {},
+                metadata: BucketMetadata::default(),
+                events: None,
+                last_updated: None,
+            };
+            ds.create_bucket(&bucket).unwrap();
+            // Over three days, including events which overlap and cross midnight
+            let events: Vec<Event> = (0..300)
+                .map(|_| {
+                    let key = if bucket_id == "window" {
+                        "app"
+                    } else {
+                        "status"
+                    };
+                    let value = values[random(values.len() as u64) as usize].clone();
+                    let mut data = json_map! {};
+                    match random(10) {
+                        0 => data.insert("title".to_string(), value),
+                        _ => data.insert(key.to_string(), value),
+                    };
+                    Event {
+                        id: None,
+                        timestamp: start + Duration::seconds(random(3 * 86400) as i64),
+                        duration: Duration::seconds(random(7200) as i64),
+                        data,
+                    }
+                })
+                .collect();
+            ds.insert_events(bucket_id, &events).unwrap();
+        }
+
+        let afk = r#"afk = filter_keyvals(query_bucket("afk"), "status", ["not-afk"]);"#;
+        let cases = [
+            (
+                r#"return filter_keyvals(query_bucket("window"), "app", ["a", "c"]);"#.to_string(),
+                "query_bucket+filter_keyvals",
+            ),
+            (
+                r#"return filter_keyvals(query_bucket("window"), "app", []);"#.to_string(),
+                "query_bucket+filter_keyvals",
+            ),
+            (
+                format!(r#"{afk} return filter_period_intersect(query_bucket("window"), afk);"#),
+                "query_bucket+filter_period_intersect",
+            ),
+            (
+                r#"none = []; return filter_period_intersect(query_bucket("window"), none);"#
+                    .to_string(),
+                "query_bucket+filter_period_intersect",
+            ),
+            (
+                format!(
+                    r#"{afk}
+                    events = filter_period_intersect(query_bucket("window"), afk);
+                    return merge_events_by_keys(filter_keyvals(events, "app", ["b"]), ["app"]);"#
+                ),
+                "query_bucket+filter_period_intersect",
+            ),
+            (
+                r#"def f(b) { return filter_keyvals(query_bucket(b), "app", ["b"]); }
+                return sum_durations(f("window"));"#
+                    .to_string(),
+                "query_bucket+filter_keyvals",
+            ),
+        ];
+        let intervals = [
+            "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z",
+            "2000-01-02T00:00:00Z/2000-01-03T00:00:00Z",
+            "2000-01-01T13:30:00Z/2000-01-03T02:00:00Z",
+            "1999-01-01T00:00:00Z/2001-01-01T00:00:00Z",
+        ];
+        for (code, rewritten) in &cases {
+            for ti in intervals {
+                let ti = TimeInterval::new_from_string(ti).unwrap();
+                let options = aw_query::QueryOptions {
+                    explain: true,
+                    ..Default::default()
+                };
+                let optimized = aw_query::query_with_options(code, &ti, &ds, &options).unwrap();
+                let options = aw_query::QueryOptions {
+                    skip_optimizer: true,
+                    ..Default::default()
+                };
+                let plain = aw_query::query_with_options(code, &ti, &ds, &options).unwrap();
+                assert_eq!(optimized.result, plain.result, "{code} in {ti}");
+                let calls = optimized.profile.unwrap().calls;
+                assert!(calls.iter().any(|c| c.name == *rewritten), "{code}");
+            }
+        }
+
+        // Queries which might redefine the functions are not rewritten
+        let code = r#"
+            def filter_keyvals(events, key, values) { return []; }
+            return filter_keyvals(query_bucket("window"), "app", ["a"]);"#;
+        let ti = TimeInterval::new_from_string(intervals[3]).unwrap();
+        assert_eq!(
+            aw_query::query(code, &ti, &ds).unwrap(),
+            DataType::List(vec![])
+        );
+    }
+
     #[test]
     fn test_all_functions() {
         let ds = setup_datastore_populated();
@@ -360,9 +1973,34 @@ mod query_tests {
             filtered_events = filter_keyvals_regex(events, "key", "regex");
             chunked_events = chunk_events_by_key(events, "key");
             merged_events = merge_events_by_keys(events, ["key"]);
+            bins = bin_by_time(events, "1w", "04:00");
+            hours = group_by_hour_of_day(events);
+            weekdays = group_by_weekday(events, "04:00");
+            days = split_by_day(events, "Europe/Stockholm", "04:00");
+            stats = [count(events), mean_duration(events), median_duration(events), percentile(events, 90)];
+            timestamps = [min_timestamp(events), max_timestamp(events)];
+            top_keys = top_n(events, "key", 5);
+            sessions = sessionize(events, "15m", "key");
+            focus = focus_metrics(events, "key", "04:00");
+            tree = category_tree(events, []);
+            period = [now(), interval_start(), interval_end(), datetime("2000-01-01T00:00:00Z")];
+            period = [duration("2h"), duration(60), seconds(duration(1))];
+            recent = filter_period(events, interval_end() - duration("2h"), interval_end());
+            strings = [len("abc"), str(1), lower("A"), upper("a"), strip(" a "), replace("a", "a", "b")];
+            strings = [split("a,b", ","), join(["a", "b"], ","), startswith("ab", "a"), endswith("ab", "b")];
+            strings = [regex_group("ab", "a(b)", 1), slice("abc", 1), slice([1, 2, 3], 0, -1)];
+            lists = [sorted([2, 1]), reversed([1, 2]), keys({{"a": 1}}), values({{"a": 1}})];
+            dicts = [get({{"a": 1}}, "b", 0), merge({{"a": 1}}, {{"b": 2}})];
+            rewritten = map_data(events, lambda(data) {{ return merge(data, {{"new": 1}}); }});
+            rewritten = rename_key(set_key(rewritten, "const", "a"), "const", "renamed");
+            rewritten = drop_keys(extract_regex(rewritten, "key", "^(v)", "extracted", 1), ["new"]);
+            rewritten = map_values(rewritten, "key", {{"value": "mapped"}});
             return  merged_events;"#,
             "testid", "testid"
         );
+        // The static checker accepts every builtin used correctly
+        let diagnostics = aw_query::check(&code, &ds);
+        assert_eq!(diagnostics.len(), 0, "{diagnostics:?}");
         match aw_query::query(&code, &interval, &ds).unwrap() {
             aw_query::DataType::List(l) => l,
             ref data => panic!("Wrong datatype, {data:?}"),
@@ -540,6 +2178,33 @@ mod query_tests {
         let res = aw_query::query(code, &interval, &ds);
         assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
 
+        // Test combined rules of the other types
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "or", "rules": [
+                { "type": "glob", "pattern": "*.rs", "ignore_case": true },
+                { "type": "exact", "value": "vim", "select_keys": ["app"] },
+                { "type": "not", "rule": { "type": "number", "key": "pid", "min": 1 } },
+                { "type": "time_of_day", "start": "22:00", "end": "06:00", "timezone": "Europe/Stockholm" }
+            ] }]]);
+            return  events;"#;
+        aw_query::query(code, &interval, &ds).unwrap();
+
+        // Test rule where a nested rule has an invalid field
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "not", "rule": { "type": "time_of_day", "start": "9", "end": "17:00" } }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Test nested glob rule where the pattern has no closing bracket, which is valid
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "and", "rules": [{ "type": "glob", "pattern": "a[b" }] }]]);
+            return  events;"#;
+        aw_query::query(code, &interval, &ds).unwrap();
+
         // Test regex rule where uncompilable regex is supplied
         let code = r#"
             events = [];
@@ -678,6 +2343,12 @@ mod query_tests {
             num => panic!("Expected number, got {num:?}"),
         };
 
+        let code = String::from("return -2*3 - -1;");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, -5.0),
+            num => panic!("Expected number, got {num:?}"),
+        };
+
         let code = String::from("return 4/2;");
         match aw_query::query(&code, &interval, &ds).unwrap() {
             DataType::Number(n) => assert_eq!(n, 2.0),
@@ -699,5 +2370,18 @@ mod query_tests {
             DataType::Number(n) => assert_eq!(n, 3.0),
             num => panic!("Expected number, got {num:?}"),
         };
+
+        // * binds tighter than + and -
+        let code = String::from("return 1+2*3-4/2;");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 5.0),
+            num => panic!("Expected number, got {num:?}"),
+        };
+
+        let code = String::from("return (1+2)*3;");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 9.0),
+            num => panic!("Expected number, got {num:?}"),
+        };
     }
 }
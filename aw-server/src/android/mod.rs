@@ -41,12 +41,14 @@ pub mod android {
     use crate::endpoints::ServerState;
     use aw_client_rust::blocking::AwClient;
     use aw_client_rust::classes::default_classes;
-    use aw_client_rust::classes::{CategoryId, CategorySpec};
+    use aw_client_rust::classes::CategoryId;
     use aw_client_rust::queries::{
         build_android_canonical_events, AndroidQueryParams, QueryParamsBase,
     };
     use aw_datastore::Datastore;
-    use aw_models::{Bucket, Event, TimeInterval};
+    use aw_models::{Bucket, ClassRule, Event, TimeInterval};
+    use aw_transform::classify::{self, Rule};
+    use chrono::Duration;
 
     static mut DATASTORE: Option<Datastore> = None;
 
@@ -330,6 +332,24 @@ pub mod android {
         string_to_jstring(&env, json!(results).to_string())
     }
 
+    /// The default classes of aw-client-rust as class rules
+    fn default_class_rules() -> Vec<(CategoryId, ClassRule)> {
+        default_classes()
+            .into_iter()
+            .map(|(name, spec)| {
+                let rule = match spec.spec_type.as_str() {
+                    "regex" => ClassRule::Regex {
+                        regex: spec.regex,
+                        ignore_case: Some(spec.ignore_case),
+                        select_keys: None,
+                    },
+                    _ => ClassRule::None,
+                };
+                (name, rule)
+            })
+            .collect()
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn Java_net_activitywatch_android_RustInterface_androidQuery(
         env: JNIEnv,
@@ -356,32 +376,23 @@ pub mod android {
                             Ok(server_classes) => {
                                 if server_classes.is_empty() {
                                     info!("Server classes list is empty, using default classes");
-                                    default_classes()
+                                    default_class_rules()
                                 } else {
-                                    // Convert from aw_models::Class to CategorySpec format
                                     server_classes
-                                        .iter()
-                                        .map(|c| {
-                                            let category_id: CategoryId = c.name.clone();
-                                            let category_spec = CategorySpec {
-                                                spec_type: c.rule.rule_type.clone(),
-                                                regex: c.rule.regex.clone().unwrap_or_default(),
-                                                ignore_case: c.rule.ignore_case.unwrap_or(false),
-                                            };
-                                            (category_id, category_spec)
-                                        })
+                                        .into_iter()
+                                        .map(|c| (c.name, c.rule))
                                         .collect()
                                 }
                             }
                             Err(e) => {
                                 warn!("Failed to parse server classes, using defaults: {:?}", e);
-                                default_classes()
+                                default_class_rules()
                             }
                         }
                     }
                     Err(e) => {
                         info!("Failed to get server classes, using defaults: {:?}", e);
-                        default_classes()
+                        default_class_rules()
                     }
                 }
             }
@@ -390,15 +401,31 @@ pub mod android {
                     "Failed to create client for fetching classes, using defaults: {:?}",
                     e
                 );
-                default_classes()
+                default_class_rules()
             }
         };
 
-        // Build canonical Android query
+        // The queries of aw-client-rust can only categorize with the regex and none rules of
+        // CategorySpec, so the events are categorized here with the rules compiled like the
+        // server does. A class with an invalid rule fails the query.
+        let mut rules: Vec<(Vec<String>, Rule)> = Vec::with_capacity(classes.len());
+        for (name, rule) in &classes {
+            match Rule::try_from(rule) {
+                Ok(rule) => rules.push((name.clone(), rule)),
+                Err(err) => {
+                    return create_error_object(
+                        &env,
+                        format!("Invalid rule of class {:?}: {}", name, err.message),
+                    )
+                }
+            }
+        }
+
+        // Build canonical Android query, the events are categorized below
         let params = AndroidQueryParams {
             base: QueryParamsBase {
                 bid_browsers: Vec::new(),
-                classes,
+                classes: Vec::new(),
                 filter_classes: Vec::new(),
                 filter_afk: true,
                 include_audible: true,
@@ -406,10 +433,7 @@ pub mod android {
             bid_android,
         };
         let query_code = format!(
-            r#"{}
-duration = sum_durations(events);
-cat_events = sort_by_duration(merge_events_by_keys(events, ["$category"]));
-RETURN = {{"events": events, "duration": duration, "cat_events": cat_events}};"#,
+            "{};\nRETURN = events;",
             build_android_canonical_events(&params)
         );
 
@@ -417,8 +441,10 @@ RETURN = {{"events": events, "duration": duration, "cat_events": cat_events}};"#
         let mut results = Vec::new();
 
         for interval in &timeperiods {
-            let result = match aw_query::query(&query_code, interval, &datastore) {
-                Ok(data) => data,
+            let events: Vec<Event> = match aw_query::query(&query_code, interval, &datastore)
+                .and_then(|data| data.try_into())
+            {
+                Ok(events) => events,
                 Err(e) => {
                     return create_error_object(
                         &env,
@@ -426,7 +452,17 @@ RETURN = {{"events": events, "duration": duration, "cat_events": cat_events}};"#
                     )
                 }
             };
-            results.push(result);
+            let events = classify::categorize(events, &rules);
+            let duration: Duration = events.iter().map(|e| e.duration).sum();
+            let cat_events = aw_transform::sort_by_duration(aw_transform::merge_events_by_keys(
+                events.clone(),
+                vec!["$category".to_string()],
+            ));
+            results.push(json!({
+                "events": events,
+                "duration": duration.num_milliseconds() as f64 / 1000.0,
+                "cat_events": cat_events,
+            }));
         }
 
         string_to_jstring(&env, json!(results).to_string())
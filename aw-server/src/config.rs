@@ -31,6 +31,50 @@ pub struct AWAuthConfig {
     pub api_key: Option<String>,
 }
 
+/// Limits for queries, serialised as `[query]` in config.toml.
+/// Requests may ask for lower limits, but never for higher ones.
+#[derive(Serialize, Deserialize)]
+pub struct AWQueryConfig {
+    /// Max wall time in seconds of a query request, for all its timeperiods together.
+    #[serde(default = "default_query_timeout")]
+    pub timeout: f64,
+    /// Max number of events a single query_bucket call may load, unlimited if unset. Also
+    /// limits the events per bucket of the classify explain endpoint, 100000 if unset.
+    #[serde(default)]
+    pub max_bucket_events: Option<usize>,
+    /// Max size in bytes of the JSON result of a single timeperiod, unlimited if unset.
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    /// Max number of timeperiods of a request which are queried at the same time,
+    /// the number of CPUs if unset.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Max memory in bytes used to cache query results, 0 disables the cache
+    #[serde(default = "default_query_cache_max_bytes")]
+    pub cache_max_bytes: usize,
+}
+
+impl AWQueryConfig {
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            Some(threads) => threads.max(1),
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+impl Default for AWQueryConfig {
+    fn default() -> AWQueryConfig {
+        AWQueryConfig {
+            timeout: default_query_timeout(),
+            max_bucket_events: None,
+            max_output_bytes: None,
+            threads: None,
+            cache_max_bytes: default_query_cache_max_bytes(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AWConfig {
     #[serde(default = "default_address")]
@@ -52,6 +96,10 @@ pub struct AWConfig {
     #[serde(default)]
     pub auth: AWAuthConfig,
 
+    // Limits for queries — serialised as [query] section.
+    #[serde(default)]
+    pub query: AWQueryConfig,
+
     // A mapping of watcher names to paths where the
     // custom visualizations are located.
     #[serde(default = "default_custom_static")]
@@ -65,6 +113,7 @@ impl Default for AWConfig {
             port: default_port(),
             testing: default_testing(),
             auth: AWAuthConfig::default(),
+            query: AWQueryConfig::default(),
             cors: default_cors(),
             cors_regex: default_cors(),
             custom_static: default_custom_static(),
@@ -115,6 +164,14 @@ fn default_port() -> u16 {
     }
 }
 
+fn default_query_timeout() -> f64 {
+    300.0
+}
+
+fn default_query_cache_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
 fn default_custom_static() -> std::collections::HashMap<String, String> {
     std::collections::HashMap::new()
 }
@@ -0,0 +1,166 @@
+use rocket::http::Status;
+use rocket::serde::json::{json, Json};
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use aw_datastore::DatastoreError;
+use aw_models::{Class, Event, TimeInterval};
+use aw_transform::classify::{self, Rule};
+
+use crate::config::AWConfig;
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+/// The most events loaded from a bucket when the query config doesn't limit them, as all of
+/// them are explained at once
+const MAX_BUCKET_EVENTS: usize = 100_000;
+
+#[derive(Deserialize)]
+pub struct ExplainRequest {
+    /// The buckets whose events in the timeperiod are classified
+    buckets: Vec<String>,
+    timeperiod: TimeInterval,
+    /// The classes to explain, the classes setting if not given
+    classes: Option<Vec<Class>>,
+}
+
+#[derive(Serialize)]
+pub struct ExplainResponse {
+    events: Vec<ExplainedEvents>,
+    rules: Vec<RuleReport>,
+}
+
+/// The events with the same data which got their category the same way
+#[derive(Serialize)]
+struct ExplainedEvents {
+    data: Map<String, Value>,
+    count: usize,
+    duration: f64,
+    category: Vec<String>,
+    /// The ids of the classes whose rules matched
+    matches: Vec<i32>,
+    /// The id of the class whose category was chosen, null if uncategorized
+    chosen: Option<i32>,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct RuleReport {
+    id: i32,
+    name: Vec<String>,
+    matched: usize,
+    matched_duration: f64,
+    chosen: usize,
+    chosen_duration: f64,
+    /// The ids of the classes chosen over this one for events it matched
+    shadowed_by: Vec<i32>,
+    /// "never_matches", "always_shadowed" or "ok"
+    status: &'static str,
+}
+
+fn seconds(duration: chrono::Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+fn get_classes(state: &ServerState) -> Result<Vec<Class>, HttpErrorJson> {
+    let value = match state.datastore.get_key_value("settings.classes") {
+        Ok(value) => value,
+        Err(DatastoreError::NoSuchKey(_)) => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    serde_json::from_str::<Option<Vec<Class>>>(&value)
+        .map(Option::unwrap_or_default)
+        .map_err(|err| {
+            HttpErrorJson::new(
+                Status::InternalServerError,
+                format!("Invalid classes setting: {err}"),
+            )
+        })
+}
+
+/// Explains which classes matched the events of the buckets in the timeperiod and which of
+/// them gave the events their category, and reports the classes which never match or are
+/// always shadowed by others. Fails with 413 if a bucket has more events in the timeperiod
+/// than the max_bucket_events of the query config allows.
+#[post("/explain", data = "<request>", format = "application/json")]
+pub fn explain(
+    request: Json<ExplainRequest>,
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+) -> Result<Json<ExplainResponse>, HttpErrorJson> {
+    let request = request.into_inner();
+    let classes = match request.classes {
+        Some(classes) => classes,
+        None => get_classes(state)?,
+    };
+    let mut rules: Vec<(Vec<String>, Rule)> = Vec::with_capacity(classes.len());
+    for (i, class) in classes.iter().enumerate() {
+        let rule = Rule::try_from(&class.rule).map_err(|err| {
+            let message = format!("Invalid rule of class {:?}: {}", class.name, err.message);
+            HttpErrorJson::new(Status::BadRequest, message)
+                .with_details(json!({"path": format!("[{i}].rule.{}", err.path)}))
+        })?;
+        rules.push((class.name.clone(), rule));
+    }
+
+    let ti = request.timeperiod;
+    let max_events = config.query.max_bucket_events.unwrap_or(MAX_BUCKET_EVENTS);
+    let mut events: Vec<Event> = Vec::new();
+    for bucket_id in &request.buckets {
+        // Loads one event more than the limit, to tell whether it was exceeded
+        let bucket_events = state.datastore.get_events(
+            bucket_id,
+            Some(*ti.start()),
+            Some(*ti.end()),
+            Some(max_events as u64 + 1),
+        )?;
+        if bucket_events.len() > max_events {
+            return Err(HttpErrorJson::new(
+                Status::PayloadTooLarge,
+                format!(
+                    "Bucket {bucket_id} has more than the limit of {max_events} events in the timeperiod"
+                ),
+            ));
+        }
+        events.extend(bucket_events);
+    }
+
+    let id = |i: &usize| classes[*i].id;
+    let groups = classify::explain_categorize(&events, &rules);
+    let reports = classify::classification_report(&groups, rules.len())
+        .into_iter()
+        .zip(&classes)
+        .map(|(report, class)| RuleReport {
+            id: class.id,
+            name: class.name.clone(),
+            matched: report.matched,
+            matched_duration: seconds(report.matched_duration),
+            chosen: report.chosen,
+            chosen_duration: seconds(report.chosen_duration),
+            shadowed_by: report.shadowed_by.iter().map(id).collect(),
+            status: if report.never_matches() {
+                "never_matches"
+            } else if report.always_shadowed() {
+                "always_shadowed"
+            } else {
+                "ok"
+            },
+        })
+        .collect();
+    let explained = groups
+        .into_iter()
+        .map(|group| ExplainedEvents {
+            data: group.data,
+            count: group.count,
+            duration: seconds(group.duration),
+            category: group.explanation.category,
+            matches: group.explanation.matches.iter().map(id).collect(),
+            chosen: group.explanation.chosen.as_ref().map(id),
+            reason: group.explanation.reason.as_str(),
+        })
+        .collect();
+    Ok(Json(ExplainResponse {
+        events: explained,
+        rules: reports,
+    }))
+}
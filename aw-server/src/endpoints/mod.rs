@@ -51,14 +51,17 @@ pub struct ServerState {
 mod util;
 mod apikey;
 mod bucket;
+mod classify;
 mod cors;
 mod export;
 mod hostcheck;
 mod import;
 mod query;
+mod query_cache;
+mod query_library;
 mod settings;
 
-pub use util::HttpErrorJson;
+pub use util::{HttpErrorJson, JsonStream};
 
 #[get("/")]
 fn root_index(state: &State<ServerState>) -> Option<(ContentType, Vec<u8>)> {
@@ -140,6 +143,7 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
     let hostcheck = hostcheck::HostCheck::new(&config);
     let apikey = apikey::ApiKeyCheck::new(&config);
     let custom_static = config.custom_static.clone();
+    let query_cache = query_cache::QueryCache::new(config.query.cache_max_bytes);
 
     let mut rocket = rocket::custom(config.to_rocket_config())
         .attach(cors.clone())
@@ -148,6 +152,8 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
         .manage(cors)
         .manage(server_state)
         .manage(config)
+        .manage(query::RunningQueries::default())
+        .manage(query_cache)
         .mount(
             "/",
             routes![
@@ -180,7 +186,25 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 bucket::bucket_export
             ],
         )
-        .mount("/api/0/query", routes![query::query])
+        .mount(
+            "/api/0/query",
+            routes![
+                query::query,
+                query::validate,
+                query::format,
+                query::cancel,
+                query_cache::cache_stats
+            ],
+        )
+        .mount(
+            "/api/0/query/libraries",
+            routes![
+                query_library::libraries_get,
+                query_library::library_get,
+                query_library::library_set,
+                query_library::library_delete,
+            ],
+        )
         .mount(
             "/api/0/import",
             routes![import::bucket_import_json, import::bucket_import_form],
@@ -195,6 +219,8 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 settings::settings_get,
             ],
         )
+        .mount("/api/0/schema", routes![settings::settings_schema])
+        .mount("/api/0/classify", routes![classify::explain])
         .mount("/", rocket_cors::catch_all_options_routes());
 
     // for each custom static directory, mount it at the given name
@@ -1,29 +1,221 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use rocket::http::Status;
 use rocket::serde::json::{json, Json, Value};
 use rocket::State;
+use serde::Serialize;
+
+use aw_models::{Query, TimeInterval};
+use aw_query::{DataType, Profile, QueryOptions};
+
+use crate::config::{AWConfig, AWQueryConfig};
+use crate::endpoints::query_cache::QueryCache;
+use crate::endpoints::{HttpErrorJson, JsonStream, ServerState};
+
+/// Cancellation flags of the running queries which were given an id
+#[derive(Default)]
+pub struct RunningQueries(Mutex<HashMap<String, Arc<AtomicBool>>>);
 
-use aw_models::Query;
+/// Removes a query from RunningQueries when it finishes, however it finishes
+struct RunningQuery<'a> {
+    running: &'a RunningQueries,
+    id: String,
+}
 
-use crate::endpoints::{HttpErrorJson, ServerState};
+impl Drop for RunningQuery<'_> {
+    fn drop(&mut self) {
+        self.running.0.lock().unwrap().remove(&self.id);
+    }
+}
 
-#[post("/", data = "<query_req>", format = "application/json")]
-pub fn query(query_req: Json<Query>, state: &State<ServerState>) -> Result<Value, HttpErrorJson> {
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueryResponse {
+    Results(Vec<DataType>),
+    Explained {
+        result: Vec<DataType>,
+        explain: Vec<Profile>,
+    },
+}
+
+#[derive(FromForm)]
+pub struct QueryParams {
+    /// Return a profile of each timeperiod together with the results
+    explain: Option<bool>,
+    /// Makes the query cancellable with `DELETE /api/0/query/<id>`
+    id: Option<String>,
+    /// The limits below can only be lowered from what the server config allows
+    timeout: Option<f64>,
+    max_bucket_events: Option<usize>,
+    max_output_bytes: Option<usize>,
+    /// Run the query even if its results are cached, the new results replace the cached ones
+    no_cache: Option<bool>,
+}
+
+fn lowest(requested: Option<usize>, max: Option<usize>) -> Option<usize> {
+    match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    }
+}
+
+fn query_options(
+    params: &QueryParams,
+    config: &AWQueryConfig,
+) -> Result<QueryOptions, HttpErrorJson> {
+    let timeout = match params.timeout {
+        Some(timeout) if timeout.is_nan() || timeout < 0.0 => {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                format!("Invalid timeout {timeout}, must be a positive number of seconds"),
+            ))
+        }
+        Some(timeout) => timeout.min(config.timeout),
+        None => config.timeout,
+    };
+    let deadline = Duration::try_from_secs_f64(timeout)
+        .ok()
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    Ok(QueryOptions {
+        explain: params.explain.unwrap_or(false),
+        deadline,
+        max_bucket_events: lowest(params.max_bucket_events, config.max_bucket_events),
+        max_output_bytes: lowest(params.max_output_bytes, config.max_output_bytes),
+        ..Default::default()
+    })
+}
+
+/// Runs a query for each timeperiod. With `?explain=true` the results are returned together
+/// with a profile of each run, as `{"result": [...], "explain": [...]}`.
+///
+/// The results are serialized while they are sent, so large results don't need a copy as
+/// JSON in memory.
+///
+/// Results are cached, except when explaining. A cached result is returned even if the query
+/// would now hit one of the limits, as returning it takes no work.
+#[post("/?<params..>", data = "<query_req>", format = "application/json")]
+pub fn query(
+    query_req: Json<Query>,
+    params: QueryParams,
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+    running: &State<RunningQueries>,
+    cache: &State<QueryCache>,
+) -> Result<JsonStream<QueryResponse>, HttpErrorJson> {
     let query_code = query_req.0.query.join("\n");
     let intervals = &query_req.0.timeperiods;
-    let mut results = Vec::new();
-    let datastore = &state.datastore;
-    for interval in intervals {
-        let result = match aw_query::query(&query_code, interval, datastore) {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Query failed: {:?}", e);
+    let mut options = query_options(&params, &config.query)?;
+    let _running_query = match params.id {
+        Some(id) => {
+            let mut queries = running.0.lock().unwrap();
+            if queries.contains_key(&id) {
                 return Err(HttpErrorJson::new(
-                    Status::InternalServerError,
-                    e.to_string(),
+                    Status::Conflict,
+                    format!("A query with id '{id}' is already running"),
                 ));
             }
-        };
-        results.push(result);
+            let cancel = Arc::new(AtomicBool::new(false));
+            queries.insert(id.clone(), Arc::clone(&cancel));
+            options.cancel = Some(cancel);
+            Some(RunningQuery { running, id })
+        }
+        None => None,
+    };
+
+    let use_cache = cache.enabled() && !options.explain;
+    let normalized_code = aw_query::normalize(&query_code);
+    let mut results: Vec<_> = intervals
+        .iter()
+        .map(|ti| match use_cache && !params.no_cache.unwrap_or(false) {
+            true => cache.get(&normalized_code, ti, &state.datastore),
+            false => None,
+        })
+        .collect();
+    let uncached: Vec<TimeInterval> = intervals
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.is_none())
+        .map(|(ti, _)| ti.clone())
+        .collect();
+
+    let counter = state.datastore.change_counter();
+    let threads = config.query.threads();
+    let res =
+        aw_query::query_timeperiods(&query_code, &uncached, &state.datastore, &options, threads);
+    let outputs = match res {
+        Ok(outputs) => outputs,
+        Err(d) => {
+            warn!("Query failed: {}", d);
+            let err = HttpErrorJson::new(Status::InternalServerError, d.error.to_string());
+            return Err(match d.location {
+                // The query list is joined with newlines, so line N is usually its Nth entry
+                Some(location) => err.with_details(json!(location)),
+                None => err,
+            });
+        }
+    };
+    let mut profiles = Vec::new();
+    let mut outputs = uncached.iter().zip(outputs);
+    for result in results.iter_mut().filter(|result| result.is_none()) {
+        let (ti, output) = outputs.next().unwrap();
+        if use_cache {
+            cache.insert(&normalized_code, ti, counter, &output);
+        }
+        *result = Some(output.result);
+        profiles.extend(output.profile);
+    }
+    let results: Vec<_> = results.into_iter().flatten().collect();
+    if options.explain {
+        return Ok(JsonStream(QueryResponse::Explained {
+            result: results,
+            explain: profiles,
+        }));
+    }
+    Ok(JsonStream(QueryResponse::Results(results)))
+}
+
+/// Formats a query in a canonical layout with its comments kept, the timeperiods are ignored.
+/// Returns the code as `{"code": "..."}`, or the parsing error with its location.
+#[post("/format", data = "<query_req>", format = "application/json")]
+pub fn format(query_req: Json<Query>) -> Result<Value, HttpErrorJson> {
+    let query_code = query_req.0.query.join("\n");
+    match aw_query::format(&query_code) {
+        Ok(code) => Ok(json!({ "code": code })),
+        Err(d) => {
+            let err = HttpErrorJson::new(Status::BadRequest, d.error.to_string());
+            Err(match d.location {
+                Some(location) => err.with_details(json!(location)),
+                None => err,
+            })
+        }
+    }
+}
+
+/// Cancels a running query which was started with an id, it fails with a Cancelled error
+#[delete("/<id>")]
+pub fn cancel(id: &str, running: &State<RunningQueries>) -> Result<(), HttpErrorJson> {
+    match running.0.lock().unwrap().get(id) {
+        Some(cancel) => {
+            cancel.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => Err(HttpErrorJson::new(
+            Status::NotFound,
+            format!("No running query with id '{id}'"),
+        )),
     }
-    Ok(json!(results))
+}
+
+/// Checks a query for mistakes without running it, the timeperiods are ignored
+#[post("/validate", data = "<query_req>", format = "application/json")]
+pub fn validate(query_req: Json<Query>, state: &State<ServerState>) -> Value {
+    let query_code = query_req.0.query.join("\n");
+    let errors = aw_query::check(&query_code, &state.datastore);
+    json!({
+        "valid": errors.is_empty(),
+        "errors": errors,
+    })
 }
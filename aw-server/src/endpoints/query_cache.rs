@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+
+use aw_datastore::Datastore;
+use aw_models::TimeInterval;
+use aw_query::{DataType, Dependencies, QueryOutput};
+
+/// Results of earlier query runs, keyed on the normalized query code and the timeperiod.
+///
+/// Instead of being cleared on every write, the entries remember the change counter of the
+/// datastore and are checked against the changes made since whenever they are looked up.
+/// Only the changes to what the query read in its timeperiod invalidate an entry, so the
+/// results of past days stay cached while heartbeats keep coming in for today.
+pub struct QueryCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<(String, String), Entry>,
+    stats: CacheStats,
+    // Counts lookups, to tell which entry was used least recently
+    clock: u64,
+}
+
+struct Entry {
+    result: DataType,
+    dependencies: Dependencies,
+    interval: TimeInterval,
+    /// The datastore change counter from when the entry was last known to be up to date
+    counter: u64,
+    bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries removed because the data they were computed from changed
+    pub invalidations: u64,
+    /// Entries removed to make room for newer ones
+    pub evictions: u64,
+    pub entries: usize,
+    /// Estimate of the memory used by the cached results
+    pub bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl QueryCache {
+    /// A cache using at most about max_bytes of memory, 0 disables it
+    pub fn new(max_bytes: usize) -> QueryCache {
+        QueryCache {
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    /// Returns the cached result of the query code in a timeperiod, if it's still up to date
+    pub fn get(&self, code: &str, ti: &TimeInterval, ds: &Datastore) -> Option<DataType> {
+        // Read before checking the changes, so that changes made while checking are
+        // checked again next time
+        let counter = ds.change_counter();
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let key = (code.to_string(), ti.to_string());
+        let up_to_date = match inner.entries.get(&key) {
+            Some(entry) => match ds.changes_since(entry.counter) {
+                Some(changes) => !changes
+                    .iter()
+                    .any(|change| entry.dependencies.affected_by(change, &entry.interval)),
+                // Too much has changed since to tell
+                None => false,
+            },
+            None => {
+                inner.stats.misses += 1;
+                return None;
+            }
+        };
+        if !up_to_date {
+            inner.remove(&key);
+            inner.stats.invalidations += 1;
+            inner.stats.misses += 1;
+            return None;
+        }
+        inner.stats.hits += 1;
+        let entry = inner.entries.get_mut(&key).unwrap();
+        entry.counter = counter;
+        entry.last_used = clock;
+        Some(entry.result.clone())
+    }
+
+    /// Caches the output of a query run. The counter is the datastore change counter from
+    /// before the query started, so that changes made while it ran invalidate the entry.
+    /// Queries which read the current time are not cached, as their result goes stale.
+    pub fn insert(&self, code: &str, ti: &TimeInterval, counter: u64, output: &QueryOutput) {
+        let bytes = code.len() + aw_query::profile::estimate_memory(&output.result);
+        if bytes > self.max_bytes || output.dependencies.now {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let key = (code.to_string(), ti.to_string());
+        inner.remove(&key);
+        while inner.stats.bytes + bytes > self.max_bytes {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => {
+                    inner.remove(&oldest);
+                    inner.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+        inner.stats.bytes += bytes;
+        let last_used = inner.clock;
+        inner.entries.insert(
+            key,
+            Entry {
+                result: output.result.clone(),
+                dependencies: output.dependencies.clone(),
+                interval: ti.clone(),
+                counter,
+                bytes,
+                last_used,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            entries: inner.entries.len(),
+            max_bytes: self.max_bytes,
+            ..inner.stats.clone()
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &(String, String)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.stats.bytes -= entry.bytes;
+        }
+    }
+}
+
+/// Statistics of the query result cache
+#[get("/cache")]
+pub fn cache_stats(cache: &State<QueryCache>) -> Json<CacheStats> {
+    Json(cache.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use aw_datastore::Datastore;
+    use aw_models::TimeInterval;
+    use aw_query::{DataType, QueryOptions};
+
+    use super::QueryCache;
+
+    #[test]
+    fn test_eviction() {
+        let ds = Datastore::new_in_memory(false);
+        let ti =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-02T00:00:00Z").unwrap();
+        let options = QueryOptions::default();
+        let run = |code: &str| aw_query::query_with_options(code, &ti, &ds, &options).unwrap();
+
+        // Room for two entries
+        let entry_bytes = {
+            let cache = QueryCache::new(usize::MAX);
+            cache.insert("a", &ti, 0, &run("return 1;"));
+            cache.stats().bytes
+        };
+        let cache = QueryCache::new(entry_bytes * 2);
+        cache.insert("a", &ti, 0, &run("return 1;"));
+        cache.insert("b", &ti, 0, &run("return 2;"));
+        assert_eq!(cache.get("a", &ti, &ds), Some(DataType::Number(1.0)));
+
+        // The least recently used entry goes first
+        cache.insert("c", &ti, 0, &run("return 3;"));
+        assert_eq!(cache.get("b", &ti, &ds), None);
+        assert_eq!(cache.get("a", &ti, &ds), Some(DataType::Number(1.0)));
+        assert_eq!(cache.get("c", &ti, &ds), Some(DataType::Number(3.0)));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        assert_eq!(stats.bytes, entry_bytes * 2);
+
+        // Results which don't fit at all are not cached
+        let cache = QueryCache::new(1);
+        cache.insert("a", &ti, 0, &run("return 1;"));
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use aw_datastore::DatastoreError;
+use aw_models::QueryLibrary;
+use aw_query::library;
+
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+fn parse_library(name: &str, value: &str) -> Result<QueryLibrary, HttpErrorJson> {
+    match serde_json::from_str(value) {
+        Ok(library) => Ok(library),
+        Err(err) => Err(HttpErrorJson::new(
+            Status::InternalServerError,
+            format!("Failed to parse stored query library '{name}': {err}"),
+        )),
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), HttpErrorJson> {
+    library::validate_name(name)
+        .map_err(|err| HttpErrorJson::new(Status::BadRequest, err.to_string()))
+}
+
+fn not_found(name: &str) -> HttpErrorJson {
+    HttpErrorJson::new(
+        Status::NotFound,
+        format!("The requested query library '{name}' does not exist"),
+    )
+}
+
+#[get("/")]
+pub fn libraries_get(
+    state: &State<ServerState>,
+) -> Result<Json<HashMap<String, QueryLibrary>>, HttpErrorJson> {
+    let datastore = &state.datastore;
+    let pattern = format!("{}%", library::KEY_PREFIX);
+    let mut libraries = HashMap::new();
+    for (key, value) in datastore.get_key_values(&pattern)? {
+        if let Some(name) = key.strip_prefix(library::KEY_PREFIX) {
+            libraries.insert(name.to_string(), parse_library(name, &value)?);
+        }
+    }
+    Ok(Json(libraries))
+}
+
+#[get("/<name>")]
+pub fn library_get(
+    name: &str,
+    state: &State<ServerState>,
+) -> Result<Json<QueryLibrary>, HttpErrorJson> {
+    validate_name(name)?;
+    let datastore = &state.datastore;
+    match datastore.get_key_value(&library::key(name)) {
+        Ok(value) => Ok(Json(parse_library(name, &value)?)),
+        Err(DatastoreError::NoSuchKey(_)) => Err(not_found(name)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Create or replace a query library
+///
+/// The code is parsed before it is stored and may only contain `def` statements.
+#[post("/<name>", data = "<message>", format = "application/json")]
+pub fn library_set(
+    name: &str,
+    message: Json<QueryLibrary>,
+    state: &State<ServerState>,
+) -> Result<Status, HttpErrorJson> {
+    validate_name(name)?;
+    let mut query_library = message.into_inner();
+    query_library.name = name.to_string();
+    if let Err(err) = library::validate(&query_library) {
+        return Err(HttpErrorJson::new(Status::BadRequest, err.to_string()));
+    }
+    let value = match serde_json::to_string(&query_library) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(HttpErrorJson::new(
+                Status::InternalServerError,
+                format!("Failed to serialize query library: {err}"),
+            ))
+        }
+    };
+
+    let datastore = &state.datastore;
+    match datastore.set_key_value(&library::key(name), &value) {
+        Ok(_) => Ok(Status::Created),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<name>")]
+pub fn library_delete(name: &str, state: &State<ServerState>) -> Result<(), HttpErrorJson> {
+    validate_name(name)?;
+    let datastore = &state.datastore;
+    let key = library::key(name);
+    // Deleting a key which doesn't exist succeeds in the datastore
+    match datastore.get_key_value(&key) {
+        Ok(_) => (),
+        Err(DatastoreError::NoSuchKey(_)) => return Err(not_found(name)),
+        Err(err) => return Err(err.into()),
+    }
+    match datastore.delete_key_value(&key) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -1,10 +1,12 @@
 use crate::endpoints::ServerState;
 use rocket::http::Status;
-use rocket::serde::json::Json;
+use rocket::serde::json::{json, Json, Value};
 use rocket::State;
 use std::collections::HashMap;
 
 use aw_datastore::DatastoreError;
+use aw_models::{Class, SettingValidationError};
+use aw_transform::classify::Rule;
 
 use crate::endpoints::HttpErrorJson;
 
@@ -20,6 +22,46 @@ fn parse_key(key: String) -> Result<String, HttpErrorJson> {
     }
 }
 
+/// Checks the parts of the class rules which the JSON schema cannot express, so that a broken
+/// rule is rejected when it is stored instead of failing `categorize` at query time.
+fn validate_class_rules(key: &str, value: &Value) -> Result<(), SettingValidationError> {
+    let classes: Vec<Class> = match serde_json::from_value(value.clone()) {
+        Ok(classes) => classes,
+        // Type errors have already been reported by aw_models::validate_setting
+        Err(_) => return Ok(()),
+    };
+    for (i, class) in classes.iter().enumerate() {
+        if let Err(err) = Rule::try_from(&class.rule) {
+            return Err(SettingValidationError {
+                key: key.to_string(),
+                path: format!("[{i}].rule.{}", err.path),
+                message: err.message,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn validate_value(key: &str, value: &Value) -> Result<(), HttpErrorJson> {
+    let result = match key {
+        "classes" => {
+            aw_models::validate_setting(key, value).and_then(|_| validate_class_rules(key, value))
+        }
+        _ => aw_models::validate_setting(key, value),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            Err(HttpErrorJson::new(Status::BadRequest, err.to_string()).with_details(json!(err)))
+        }
+    }
+}
+
+#[get("/settings")]
+pub fn settings_schema() -> Value {
+    json!(aw_models::settings_schema())
+}
+
 #[get("/")]
 pub fn settings_get(
     state: &State<ServerState>,
@@ -67,7 +109,8 @@ pub fn setting_set(
     key: String,
     value: Json<serde_json::Value>,
 ) -> Result<Status, HttpErrorJson> {
-    let setting_key = parse_key(key)?;
+    let setting_key = parse_key(key.clone())?;
+    validate_value(&key, &value.0)?;
     let value_str = match serde_json::to_string(&value.0) {
         Ok(value) => value,
         Err(err) => {
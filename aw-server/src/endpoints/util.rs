@@ -1,10 +1,13 @@
-use std::io::Cursor;
+use std::io::{BufWriter, Cursor, ErrorKind, Write};
 
 use rocket::http::ContentType;
 use rocket::http::Header;
 use rocket::http::Status;
 use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
+use rocket::tokio::io::{self, AsyncWriteExt, DuplexStream};
+use rocket::tokio::runtime::Handle;
+use rocket::tokio::task;
 use serde::Serialize;
 
 use aw_models::BucketsExport;
@@ -14,6 +17,8 @@ pub struct HttpErrorJson {
     #[serde(skip_serializing)]
     status: Status,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
 }
 
 impl HttpErrorJson {
@@ -21,8 +26,15 @@ impl HttpErrorJson {
         HttpErrorJson {
             status,
             message: err,
+            details: None,
         }
     }
+
+    /// Attaches machine-readable information about the error, serialized under `details`.
+    pub fn with_details(mut self, details: serde_json::Value) -> HttpErrorJson {
+        self.details = Some(details);
+        self
+    }
 }
 
 impl<'r> Responder<'r, 'static> for HttpErrorJson {
@@ -67,6 +79,59 @@ impl<'r> Responder<'r, 'static> for BucketsExportRocket {
     }
 }
 
+/// Size of the chunks a JsonStream is serialized in
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+/// Bytes serialized ahead of what has been sent, serialization waits for the client after
+const STREAM_BUFFER_BYTES: usize = 4 * STREAM_CHUNK_BYTES;
+
+/// A JSON response which is serialized while it's sent rather than into a string first, so
+/// that large responses such as millions of events only take a few chunks of memory on top
+/// of the value itself.
+///
+/// The status is sent before serialization starts, so a value which fails to serialize cuts
+/// the response short. Only values which are known to serialize should be streamed.
+pub struct JsonStream<T>(pub T);
+
+impl<'r, T: Serialize + Send + 'static> Responder<'r, 'static> for JsonStream<T> {
+    fn respond_to(self, _: &Request) -> response::Result<'static> {
+        let (writer, reader) = io::duplex(STREAM_BUFFER_BYTES);
+        let handle = Handle::current();
+        task::spawn_blocking(move || {
+            let pipe = BlockingWriter { writer, handle };
+            let mut writer = BufWriter::with_capacity(STREAM_CHUNK_BYTES, pipe);
+            let res = serde_json::to_writer(&mut writer, &self.0)
+                .map_err(std::io::Error::from)
+                .and_then(|()| writer.flush());
+            match res {
+                Ok(()) => (),
+                // The client went away
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => (),
+                Err(e) => error!("Failed to serialize a streamed response: {e}"),
+            }
+        });
+        Response::build()
+            .header(ContentType::JSON)
+            .streamed_body(reader)
+            .ok()
+    }
+}
+
+/// Writes to the async pipe a JsonStream is sent from, blocking while the client is behind
+struct BlockingWriter {
+    writer: DuplexStream,
+    handle: Handle,
+}
+
+impl Write for BlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.handle.block_on(self.writer.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.handle.block_on(self.writer.flush())
+    }
+}
+
 use aw_datastore::DatastoreError;
 
 impl From<DatastoreError> for HttpErrorJson {
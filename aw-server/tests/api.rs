@@ -542,6 +542,81 @@ mod api_tests {
             r#"[[{"data":{},"duration":1.0,"id":1,"timestamp":"2018-01-01T01:01:01Z"}]]"#
         );
 
+        // Results are in the order of the timeperiods
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": [
+                    "2000-01-01T00:00:00Z/2001-01-01T00:00:00Z",
+                    "2017-01-01T00:00:00Z/2019-01-01T00:00:00Z",
+                    "2001-01-01T00:00:00Z/2002-01-01T00:00:00Z"
+                ],
+                "query": ["return sum_durations(query_bucket(\"id\"));"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), r#"[0.0,1.0,0.0]"#);
+
+        // Explain returns a profile of each timeperiod together with the results
+        let res = client
+            .post("/api/0/query?explain=true")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["events = query_bucket(\"id\");", "return events;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["result"][0].as_array().unwrap().len(), 1);
+        let profiles = body["explain"].as_array().unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0]["statements"].as_array().unwrap().len(), 2);
+        assert_eq!(profiles[0]["calls"][0]["name"], json!("query_bucket"));
+        assert_eq!(profiles[0]["calls"][0]["output_events"], json!(1));
+
+        // Results larger than the buffers they are streamed through are sent whole
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["s = \"0123456789\";", "for i in [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17] { s = s + s; }", "return s;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body[0].as_str().unwrap().len(), 10 << 17);
+
+        // Results which can't be serialized fail before anything is sent
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["return [lambda(x) { return x; }];"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            body["message"],
+            json!("InvalidType(\"The query returned a lambda, which cannot be serialized, call it to get a value\")")
+        );
+
         // Test error
         let res = client
             .post("/api/0/query")
@@ -556,6 +631,357 @@ mod api_tests {
             .dispatch();
         assert_eq!(res.status(), rocket::http::Status::InternalServerError);
         assert_eq!(res.into_string().unwrap(), r#"{"message":"EmptyQuery"}"#);
+
+        // Errors caused by a specific part of the query tell where it is
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["a = 1;", "return a + no_such_var;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "message": "VariableNotDefined(\"no_such_var\")",
+                "details": {
+                    "line": 2,
+                    "column": 12,
+                    "end_line": 2,
+                    "end_column": 23,
+                    "snippet": "return a + no_such_var;"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let state = endpoints::ServerState {
+            datastore: aw_datastore::Datastore::new_in_memory(false),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let mut aw_config = config::AWConfig::default();
+        aw_config.query.max_output_bytes = Some(5);
+        let server = endpoints::build_rocket(state, aw_config);
+        let client = Client::untracked(server).expect("valid instance");
+
+        let query = |url: &str, code: &str| {
+            let body = json!({
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": [code],
+            });
+            client
+                .post(url.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body.to_string())
+                .dispatch()
+        };
+
+        let res = query("/api/0/query", "return 1;");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // The limit of the server config can't be raised by a request
+        let res = query(
+            "/api/0/query?max_output_bytes=1000",
+            r#"return "too long";"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("OutputLimitError"));
+
+        // But it can be lowered
+        let res = query("/api/0/query?max_output_bytes=2", "return 100;");
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+
+        // A cached result would be returned no matter the time limit
+        let res = query("/api/0/query?timeout=0&no_cache=true", "return 1;");
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        assert!(res.into_string().unwrap().contains("TimeLimitError"));
+
+        let res = query("/api/0/query?timeout=-1", "return 1;");
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Queries with an id can be cancelled while they run, and are forgotten afterwards
+        let res = query("/api/0/query?id=myquery", "return 1;");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .delete("/api/0/query/myquery")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let insert = |timestamp: &str| {
+            let res = client
+                .post("/api/0/buckets/id/events")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(json!([{"timestamp": timestamp, "duration": 1.0, "data": {}}]).to_string())
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        };
+        insert("2000-01-01T10:00:00Z");
+
+        let query = |url: &str, code: &str| -> Value {
+            let body = json!({
+                "timeperiods": [
+                    "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z",
+                    "2000-01-02T00:00:00Z/2000-01-03T00:00:00Z",
+                ],
+                "query": [code],
+            });
+            let res = client
+                .post(url.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body.to_string())
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str(&res.into_string().unwrap()).unwrap()
+        };
+        let stats = || -> Value {
+            let res = client
+                .get("/api/0/query/cache")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str(&res.into_string().unwrap()).unwrap()
+        };
+        let code = r#"return sum_durations(query_bucket("id"));"#;
+
+        assert_eq!(query("/api/0/query", code), json!([1.0, 0.0]));
+        let s = stats();
+        assert_eq!(
+            (s["hits"].clone(), s["misses"].clone()),
+            (json!(0), json!(2))
+        );
+        assert_eq!(s["entries"], json!(2));
+
+        // Comments and whitespace don't matter
+        let same_code = r#"return   sum_durations(query_bucket("id")); # same"#;
+        assert_eq!(query("/api/0/query", same_code), json!([1.0, 0.0]));
+        assert_eq!(stats()["hits"], json!(2));
+
+        // New data only invalidates the timeperiod it's in
+        insert("2000-01-02T10:00:00Z");
+        assert_eq!(query("/api/0/query", code), json!([1.0, 1.0]));
+        let s = stats();
+        assert_eq!(
+            (s["hits"].clone(), s["misses"].clone()),
+            (json!(3), json!(3))
+        );
+        assert_eq!(s["invalidations"], json!(1));
+
+        // Queries which don't read the bucket are not invalidated by it
+        assert_eq!(query("/api/0/query", "return 1;"), json!([1.0, 1.0]));
+        insert("2000-01-01T11:00:00Z");
+        assert_eq!(query("/api/0/query", "return 1;"), json!([1.0, 1.0]));
+        assert_eq!(stats()["hits"], json!(5));
+
+        // no_cache runs the query again and replaces the cached results
+        let hits = stats()["hits"].clone();
+        assert_eq!(query("/api/0/query?no_cache=true", code), json!([2.0, 1.0]));
+        assert_eq!(stats()["hits"], hits);
+        assert_eq!(query("/api/0/query", code), json!([2.0, 1.0]));
+        assert_eq!(stats()["hits"], json!(7));
+
+        // Explained queries are always run
+        let res = query("/api/0/query?explain=true", code);
+        assert_eq!(res["result"], json!([2.0, 1.0]));
+        assert_eq!(stats()["hits"], json!(7));
+
+        // Queries which read the current time are not cached
+        let entries = stats()["entries"].clone();
+        query("/api/0/query", "return str(now());");
+        query("/api/0/query", "return str(now());");
+        assert_eq!(stats()["hits"], json!(7));
+        assert_eq!(stats()["entries"], entries);
+    }
+
+    #[test]
+    fn test_query_validate() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/query/validate")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": [],
+                "query": ["events = flood(\"a\");", "return query_bucket(\"no_such_bucket\");"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["valid"], json!(false));
+        let errors = body["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["line"], json!(1));
+        assert_eq!(errors[0]["column"], json!(16));
+        assert_eq!(errors[0]["snippet"], json!("events = flood(\"a\");"));
+        assert_eq!(
+            errors[1]["message"],
+            json!("BucketQueryError(\"There is no bucket 'no_such_bucket'\")")
+        );
+
+        let res = client
+            .post("/api/0/query/validate")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timeperiods": [], "query": ["return 1;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), r#"{"errors":[],"valid":true}"#);
+    }
+
+    #[test]
+    fn test_query_format() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/query/format")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r##"{
+                "timeperiods": [],
+                "query": ["# Total", "events=query_bucket(\"b\");return {\"n\":count(events),\"d\":sum_durations(events)};"]
+            }"##,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            body["code"],
+            json!("# Total\nevents = query_bucket(\"b\");\nreturn {\"d\": sum_durations(events), \"n\": count(events)};\n")
+        );
+
+        let res = client
+            .post("/api/0/query/format")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timeperiods": [], "query": ["a = 1;", "return (a;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["details"]["line"], json!(2));
+        assert_eq!(body["details"]["column"], json!(10));
+    }
+
+    #[test]
+    fn test_query_libraries() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/query/libraries/math")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"code": "def double(x) { return x * 2; }"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Created);
+
+        // Libraries which contain anything but function definitions are rejected
+        let res = client
+            .post("/api/0/query/libraries/invalid")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"code": "return 1;"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        let res = client
+            .get("/api/0/query/libraries/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let library: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            library,
+            json!({"name": "math", "code": "def double(x) { return x * 2; }"})
+        );
+
+        let res = client
+            .get("/api/0/query/libraries/")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let libraries: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(libraries.as_object().unwrap().len(), 1);
+        assert_eq!(libraries["math"]["name"], "math");
+
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["import \"math\";", "return double(21);"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), r#"[42.0]"#);
+
+        let res = client
+            .delete("/api/0/query/libraries/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = client
+            .get("/api/0/query/libraries/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // Deleting a library which doesn't exist
+        let res = client
+            .delete("/api/0/query/libraries/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // Invalid names are rejected by all handlers
+        let res = client
+            .get("/api/0/query/libraries/in.valid")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .delete("/api/0/query/libraries/in.valid")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
     }
 
     fn set_setting_request(client: &Client, key: &str, value: &Value) -> Status {
@@ -750,6 +1176,190 @@ mod api_tests {
         assert_eq!(res.into_string().unwrap(), "null");
     }
 
+    #[test]
+    fn test_set_setting_validation() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let classes = json!([
+            {"id": 0, "name": ["Work"], "rule": {"type": "regex", "regex": "Google Docs"}},
+            {"id": 1, "name": ["Media"], "rule": {"type": "none"}}
+        ]);
+        let status = set_setting_request(&client, "classes", &classes);
+        assert_eq!(status, rocket::http::Status::Created);
+
+        // Wrong type for a known key
+        let status = set_setting_request(&client, "devmode", &json!("yes"));
+        assert_eq!(status, rocket::http::Status::BadRequest);
+
+        // Regex which fails to compile
+        let classes = json!([
+            {"id": 0, "name": ["Work"], "rule": {"type": "regex", "regex": "Google (Docs"}}
+        ]);
+        let res = client
+            .post("/api/0/settings/classes")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(serde_json::to_string(&classes).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["details"]["key"], "classes");
+        assert_eq!(body["details"]["path"], "[0].rule.regex");
+
+        // Invalid field in a nested rule
+        let classes = json!([
+            {"id": 0, "name": ["Work"], "rule": {"type": "and", "rules": [
+                {"type": "time_of_day", "start": "09:00", "end": "17:00", "timezone": "Nowhere"},
+                {"type": "glob", "pattern": "*.rs"}
+            ]}}
+        ]);
+        let res = client
+            .post("/api/0/settings/classes")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(serde_json::to_string(&classes).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["details"]["path"], "[0].rule.rules[0].timezone");
+
+        // The rejected value must not have been stored
+        let res = client
+            .get("/api/0/settings/classes")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let stored: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(stored[0]["rule"]["regex"], "Google Docs");
+    }
+
+    #[test]
+    fn test_classify_explain() {
+        let state = endpoints::ServerState {
+            datastore: aw_datastore::Datastore::new_in_memory(false),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let mut aw_config = config::AWConfig::default();
+        aw_config.query.max_bucket_events = Some(3);
+        let server = endpoints::build_rocket(state, aw_config);
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/window")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "window", "type": "currentwindow", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let events = json!([
+            {"timestamp": "2000-01-01T10:00:00Z", "duration": 10.0, "data": {"app": "vim"}},
+            {"timestamp": "2000-01-01T10:01:00Z", "duration": 5.0, "data": {"app": "vim"}},
+            {"timestamp": "2000-01-01T10:02:00Z", "duration": 1.0, "data": {"app": "firefox"}},
+        ]);
+        let res = client
+            .post("/api/0/buckets/window/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(serde_json::to_string(&events).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let classes = json!([
+            {"id": 1, "name": ["Work"], "rule": {"type": "regex", "regex": "vim"}},
+            {"id": 2, "name": ["Work", "Programming"], "rule": {"type": "exact", "value": "vim"}},
+            {"id": 3, "name": ["Games"], "rule": {"type": "glob", "pattern": "steam*"}}
+        ]);
+        let status = set_setting_request(&client, "classes", &classes);
+        assert_eq!(status, rocket::http::Status::Created);
+
+        let explain = |body: Value| {
+            client
+                .post("/api/0/classify/explain")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(serde_json::to_string(&body).unwrap())
+                .dispatch()
+        };
+        // The classes setting is used when no classes are given
+        let res = explain(json!({
+            "buckets": ["window"],
+            "timeperiod": "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"
+        }));
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            body["events"],
+            json!([
+                {
+                    "data": {"app": "vim"}, "count": 2, "duration": 15.0,
+                    "category": ["Work", "Programming"], "matches": [1, 2], "chosen": 2,
+                    "reason": "deepest"
+                },
+                {
+                    "data": {"app": "firefox"}, "count": 1, "duration": 1.0,
+                    "category": ["Uncategorized"], "matches": [], "chosen": null,
+                    "reason": "no_match"
+                }
+            ])
+        );
+        let statuses: Vec<&Value> = body["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|rule| &rule["status"])
+            .collect();
+        assert_eq!(statuses, vec!["always_shadowed", "ok", "never_matches"]);
+        assert_eq!(body["rules"][0]["shadowed_by"], json!([2]));
+        assert_eq!(body["rules"][0]["matched_duration"], json!(15.0));
+
+        // Given classes with an invalid rule
+        let res = explain(json!({
+            "buckets": ["window"],
+            "timeperiod": "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z",
+            "classes": [{"id": 1, "name": ["Work"], "rule": {"type": "not", "rule": {"type": "regex", "regex": "("}}}]
+        }));
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(body["details"]["path"], "[0].rule.rule.regex");
+
+        // Missing bucket
+        let res = explain(json!({
+            "buckets": ["nonexistent"],
+            "timeperiod": "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"
+        }));
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // More events than the max_bucket_events of the query config
+        let res = client
+            .post("/api/0/buckets/window/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2000-01-01T10:03:00Z", "duration": 1.0, "data": {"app": "vim"}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = explain(json!({
+            "buckets": ["window"],
+            "timeperiod": "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"
+        }));
+        assert_eq!(res.status(), rocket::http::Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn test_settings_schema() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .get("/api/0/schema/settings")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let schema: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(schema["title"], "Settings");
+        assert!(schema["properties"]["classes"].is_object());
+    }
+
     #[test]
     fn test_cors_catching() {
         let server = setup_testserver();
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc, Weekday};
+
+use aw_models::Event;
+
+/// Sums the durations of events per bin of time. Events which span several bins are split
+/// at the bin boundaries, and only bins which got a part of an event are returned.
+///
+/// The bins are `size` long and aligned to `offset` past midnight UTC, so with an offset
+/// equal to the start of the day, bins of a day or a fraction of a day start with the day.
+///
+/// # Example
+/// ```ignore
+/// size:   1h
+/// offset: 0
+/// input:  [a 00:30-02:15  ]
+/// output: {00:00: 30m, 01:00: 1h, 02:00: 15m}
+/// ```
+pub fn bin_by_time(
+    events: &[Event],
+    size: Duration,
+    offset: Duration,
+) -> BTreeMap<DateTime<Utc>, Duration> {
+    let mut bins = BTreeMap::new();
+    let size_ns = match size.num_nanoseconds() {
+        Some(size_ns) if size_ns > 0 => size_ns,
+        _ => {
+            warn!("Invalid bin size {size}, it has to be positive");
+            return bins;
+        }
+    };
+    let offset_ns = offset.num_nanoseconds().unwrap_or(0).rem_euclid(size_ns);
+    for event in events {
+        let (Some(mut t), Some(end)) = (
+            event.timestamp.timestamp_nanos_opt(),
+            event.calculate_endtime().timestamp_nanos_opt(),
+        ) else {
+            continue;
+        };
+        while t < end {
+            let bin_start = (t - offset_ns).div_euclid(size_ns) * size_ns + offset_ns;
+            let bin_end = bin_start.saturating_add(size_ns);
+            let part = Duration::nanoseconds(end.min(bin_end) - t);
+            *bins
+                .entry(DateTime::from_timestamp_nanos(bin_start))
+                .or_insert_with(Duration::zero) += part;
+            t = bin_end;
+        }
+    }
+    bins
+}
+
+/// Sums the durations of events per hour of the day in UTC, index 0 being the hour from
+/// midnight. With a start of day which is not a whole hour, the hours are shifted to start
+/// at the same minute as the day, and counted for the hour they start in.
+pub fn group_by_hour_of_day(events: &[Event], start_of_day: Duration) -> [Duration; 24] {
+    let mut hours = [Duration::zero(); 24];
+    for (bin_start, duration) in bin_by_time(events, Duration::hours(1), start_of_day) {
+        hours[bin_start.hour() as usize] += duration;
+    }
+    hours
+}
+
+/// Sums the durations of events per day of the week in UTC, index 0 being Monday. The days
+/// start at `start_of_day` past midnight, so time after midnight but before the start of
+/// the day counts for the day before.
+pub fn group_by_weekday(events: &[Event], start_of_day: Duration) -> [Duration; 7] {
+    let mut days = [Duration::zero(); 7];
+    for (bin_start, duration) in bin_by_time(events, Duration::days(1), start_of_day) {
+        let weekday: Weekday = bin_start.weekday();
+        days[weekday.num_days_from_monday() as usize] += duration;
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::DateTime;
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::{bin_by_time, group_by_hour_of_day, group_by_weekday};
+    use crate::test_utils::event;
+
+    #[test]
+    fn test_bin_by_time() {
+        let e1 = event("2000-01-01T00:30:00Z", 105 * 60, json_map! {"test": 1});
+        let e2 = event("2000-01-01T01:50:00Z", 5 * 60, json_map! {"test": 1});
+        let e3 = event("2000-01-01T05:00:00Z", 0, json_map! {"test": 1});
+        let bins = bin_by_time(&[e1.clone(), e2, e3], Duration::hours(1), Duration::zero());
+        let expected = vec![
+            ("2000-01-01T00:00:00Z", Duration::minutes(30)),
+            ("2000-01-01T01:00:00Z", Duration::minutes(65)),
+            ("2000-01-01T02:00:00Z", Duration::minutes(15)),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(t, d)| (DateTime::from_str(t).unwrap(), d))
+            .collect();
+        assert_eq!(bins.into_iter().collect::<Vec<_>>(), expected);
+
+        // Bins of a day start at the start of the day
+        let bins = bin_by_time(&[e1], Duration::days(1), Duration::hours(1));
+        let expected = vec![
+            ("1999-12-31T01:00:00Z", Duration::minutes(30)),
+            ("2000-01-01T01:00:00Z", Duration::minutes(75)),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(t, d)| (DateTime::from_str(t).unwrap(), d))
+            .collect();
+        assert_eq!(bins.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_group_by_hour_of_day() {
+        // Two days apart, so the same hours add up
+        let e1 = event("2000-01-01T23:30:00Z", 60 * 60, json_map! {"test": 1});
+        let e2 = event("2000-01-03T23:00:00Z", 10 * 60, json_map! {"test": 1});
+        let hours = group_by_hour_of_day(&[e1.clone(), e2.clone()], Duration::zero());
+        assert_eq!(hours[23], Duration::minutes(40));
+        assert_eq!(hours[0], Duration::minutes(30));
+        assert_eq!(hours.iter().filter(|d| !d.is_zero()).count(), 2);
+
+        // Hours start at the same minute as the day
+        let hours = group_by_hour_of_day(&[e1, e2], Duration::minutes(4 * 60 + 30));
+        assert_eq!(hours[22], Duration::minutes(10));
+        assert_eq!(hours[23], Duration::minutes(60));
+    }
+
+    #[test]
+    fn test_group_by_weekday() {
+        // 2000-01-03 was a Monday
+        let e = event("2000-01-03T22:00:00Z", 8 * 3600, json_map! {"test": 1});
+        let days = group_by_weekday(std::slice::from_ref(&e), Duration::zero());
+        assert_eq!(days[0], Duration::hours(2));
+        assert_eq!(days[1], Duration::hours(6));
+
+        // Until 04:00 on Tuesday it's still Monday
+        let days = group_by_weekday(&[e], Duration::hours(4));
+        assert_eq!(days[0], Duration::hours(6));
+        assert_eq!(days[1], Duration::hours(2));
+    }
+}
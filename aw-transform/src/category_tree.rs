@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, HashMap};
+
+use aw_models::{Class, Event};
+use chrono::Duration;
+
+/// A category in the tree of category_tree, with the time of its events and its subcategories
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryNode {
+    /// The summed duration of the events in the category and its subcategories
+    pub duration: Duration,
+    /// The summed seconds of the events in the category and its subcategories, each weighted
+    /// by the score of its category, None if no classes were given
+    pub score: Option<f64>,
+    pub children: BTreeMap<String, CategoryNode>,
+}
+
+/// Sums the durations of categorized events, see classify::categorize, into a tree of the
+/// category paths. Every node includes the time of its subcategories, so the root has the
+/// time of all events with a `$category`. Events without one are left out.
+///
+/// With classes every node also gets a score, the seconds of its events times the score of
+/// their category. A category without a score in its ClassData gets the score of its
+/// nearest ancestor with one, or 0 without any.
+///
+/// # Example
+/// ```ignore
+/// input:  [Work,Programming 10s][Work 5s][Media 20s]
+/// output: 35s: {Media: 20s, Work: 15s: {Programming: 10s}}
+/// ```
+pub fn category_tree(events: &[Event], classes: Option<&[Class]>) -> CategoryNode {
+    let scores: Option<HashMap<&[String], f64>> = classes.map(|classes| {
+        classes
+            .iter()
+            .filter_map(|class| {
+                let score = class.data.as_ref()?.score?;
+                Some((class.name.as_slice(), score as f64))
+            })
+            .collect()
+    });
+
+    let mut root = CategoryNode {
+        score: scores.as_ref().map(|_| 0.0),
+        ..Default::default()
+    };
+    for event in events {
+        let Some(path) = event.data.get("$category").and_then(|c| c.as_array()) else {
+            continue;
+        };
+        let path: Vec<String> = path
+            .iter()
+            .filter_map(|name| name.as_str().map(str::to_string))
+            .collect();
+        let weighted = scores.as_ref().map(|scores| {
+            let score = (0..=path.len())
+                .rev()
+                .find_map(|depth| scores.get(&path[..depth]))
+                .unwrap_or(&0.0);
+            event.duration.num_milliseconds() as f64 / 1000.0 * score
+        });
+
+        let mut node = &mut root;
+        add(node, event.duration, weighted);
+        for name in path {
+            node = node.children.entry(name).or_default();
+            add(node, event.duration, weighted);
+        }
+    }
+    root
+}
+
+fn add(node: &mut CategoryNode, duration: Duration, weighted: Option<f64>) {
+    node.duration += duration;
+    if let Some(weighted) = weighted {
+        node.score = Some(node.score.unwrap_or(0.0) + weighted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use aw_models::{Class, ClassData, ClassRule};
+
+    use super::category_tree;
+    use crate::test_utils::event;
+
+    fn class(name: &[&str], score: Option<i32>) -> Class {
+        Class {
+            data: Some(ClassData { color: None, score }),
+            id: 0,
+            name: name.iter().map(|s| s.to_string()).collect(),
+            rule: ClassRule::None,
+        }
+    }
+
+    #[test]
+    fn test_category_tree() {
+        let events = vec![
+            event(
+                "2000-01-01T00:00:00Z",
+                10,
+                json_map! {"$category": ["Work", "Programming"]},
+            ),
+            event("2000-01-01T00:00:00Z", 5, json_map! {"$category": ["Work"]}),
+            event(
+                "2000-01-01T00:00:00Z",
+                20,
+                json_map! {"$category": ["Media", "Video"]},
+            ),
+            event(
+                "2000-01-01T00:00:00Z",
+                1,
+                json_map! {"$category": ["Uncategorized"]},
+            ),
+            event("2000-01-01T00:00:00Z", 100, json_map! {"app": "a"}),
+        ];
+        let tree = category_tree(&events, None);
+        assert_eq!(tree.duration, Duration::seconds(36));
+        assert_eq!(tree.score, None);
+        assert_eq!(
+            tree.children.keys().collect::<Vec<_>>(),
+            vec!["Media", "Uncategorized", "Work"]
+        );
+        let work = &tree.children["Work"];
+        assert_eq!(work.duration, Duration::seconds(15));
+        assert_eq!(work.children["Programming"].duration, Duration::seconds(10));
+        assert!(work.children["Programming"].children.is_empty());
+        assert_eq!(
+            tree.children["Media"].children["Video"].duration,
+            Duration::seconds(20)
+        );
+
+        // Programming inherits the score of Work, Video has its own
+        let classes = vec![
+            class(&["Work"], Some(2)),
+            class(&["Work", "Programming"], None),
+            class(&["Media"], Some(-1)),
+            class(&["Media", "Video"], Some(-3)),
+        ];
+        let tree = category_tree(&events, Some(&classes));
+        assert_eq!(tree.children["Work"].score, Some(30.0));
+        assert_eq!(
+            tree.children["Work"].children["Programming"].score,
+            Some(20.0)
+        );
+        assert_eq!(tree.children["Media"].score, Some(-60.0));
+        assert_eq!(tree.children["Uncategorized"].score, Some(0.0));
+        assert_eq!(tree.score, Some(-30.0));
+    }
+}
@@ -1,12 +1,19 @@
 /// Transforms for classifying (tagging and categorizing) events.
 ///
 /// Based on code in aw_research: https://github.com/ActivityWatch/aw-research/blob/master/aw_research/classify.py
-use aw_models::Event;
-use fancy_regex::Regex;
-use lru::LruCache;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex, OnceLock};
 
+use aw_models::{ClassRule, Event};
+use chrono::{Duration, NaiveTime};
+use chrono_tz::Tz;
+use fancy_regex::Regex;
+use lru::LruCache;
+use serde_json::{Map, Value};
+
 const REGEX_CACHE_CAPACITY: usize = 512;
 
 static REGEX_CACHE: OnceLock<Mutex<LruCache<String, Arc<Regex>>>> = OnceLock::new();
@@ -14,13 +21,27 @@ static REGEX_CACHE: OnceLock<Mutex<LruCache<String, Arc<Regex>>>> = OnceLock::ne
 pub enum Rule {
     None,
     Regex(RegexRule),
+    Exact(ExactRule),
+    /// A glob pattern, compiled into a regex matching whole values
+    Glob(RegexRule),
+    Number(NumberRule),
+    TimeOfDay(TimeOfDayRule),
+    And(Vec<Rule>),
+    Or(Vec<Rule>),
+    Not(Box<Rule>),
 }
 
 impl RuleTrait for Rule {
     fn matches(&self, event: &Event) -> bool {
         match self {
             Rule::None => false,
-            Rule::Regex(rule) => rule.matches(event),
+            Rule::Regex(rule) | Rule::Glob(rule) => rule.matches(event),
+            Rule::Exact(rule) => rule.matches(event),
+            Rule::Number(rule) => rule.matches(event),
+            Rule::TimeOfDay(rule) => rule.matches(event),
+            Rule::And(rules) => rules.iter().all(|rule| rule.matches(event)),
+            Rule::Or(rules) => rules.iter().any(|rule| rule.matches(event)),
+            Rule::Not(rule) => !rule.matches(event),
         }
     }
 }
@@ -29,6 +50,17 @@ trait RuleTrait {
     fn matches(&self, event: &Event) -> bool;
 }
 
+/// The values of the event under the selected keys, or all of its values without any
+fn selected_values<'a>(
+    event: &'a Event,
+    select_keys: &'a Option<Vec<String>>,
+) -> Box<dyn Iterator<Item = &'a Value> + 'a> {
+    match select_keys {
+        Some(select_keys) => Box::new(select_keys.iter().filter_map(|key| event.data.get(key))),
+        None => Box::new(event.data.values()),
+    }
+}
+
 pub struct RegexRule {
     regex: Arc<Regex>,
     select_keys: Option<Vec<String>>,
@@ -78,6 +110,15 @@ impl RegexRule {
         Ok(RegexRule { regex, select_keys })
     }
 
+    /// A rule matching the values which the glob pattern matches as a whole
+    pub fn from_glob(
+        pattern: &str,
+        ignore_case: bool,
+        select_keys: Option<Vec<String>>,
+    ) -> Result<RegexRule, fancy_regex::Error> {
+        RegexRule::new(&glob_to_regex(pattern), ignore_case, select_keys)
+    }
+
     fn value_matches(&self, value: &serde_json::Value) -> bool {
         match value.as_str() {
             Some(value) => self.regex.is_match(value).unwrap_or(false),
@@ -93,13 +134,7 @@ impl RegexRule {
 /// compatibility (or have to maintain "old" query2 functions).
 impl RuleTrait for RegexRule {
     fn matches(&self, event: &Event) -> bool {
-        match &self.select_keys {
-            Some(select_keys) => select_keys
-                .iter()
-                .filter_map(|key| event.data.get(key))
-                .any(|val| self.value_matches(val)),
-            None => event.data.values().any(|val| self.value_matches(val)),
-        }
+        selected_values(event, &self.select_keys).any(|val| self.value_matches(val))
     }
 }
 
@@ -112,6 +147,265 @@ impl From<Regex> for Rule {
     }
 }
 
+/// Translates a glob pattern into a regex matching the same strings as a whole. `*` and `?`
+/// also match `/`, as the values are titles and URLs as often as paths.
+fn glob_to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut regex = String::from("(?s)^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                let negated = chars.get(i + 1) == Some(&'!');
+                let first = i + 1 + negated as usize;
+                // A ] right after the opening bracket is part of the class
+                let len = chars
+                    .get(first + 1..)
+                    .and_then(|rest| rest.iter().position(|&c| c == ']'));
+                match len {
+                    Some(len) => {
+                        let end = first + 1 + len;
+                        regex.push_str(if negated { "[^" } else { "[" });
+                        for &c in &chars[first..end] {
+                            if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        regex.push(']');
+                        i = end;
+                    }
+                    // Without a closing bracket it's just a bracket
+                    None => regex.push_str("\\["),
+                }
+            }
+            c => regex.push_str(&fancy_regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+/// Matches events with a value equal to the given one, strings compared without case if
+/// ignore_case is set
+pub struct ExactRule {
+    value: Value,
+    ignore_case: bool,
+    select_keys: Option<Vec<String>>,
+}
+
+impl ExactRule {
+    pub fn new(value: Value, ignore_case: bool, select_keys: Option<Vec<String>>) -> ExactRule {
+        let value = match value {
+            Value::String(s) if ignore_case => Value::String(s.to_lowercase()),
+            value => value,
+        };
+        ExactRule {
+            value,
+            ignore_case,
+            select_keys,
+        }
+    }
+
+    fn value_matches(&self, value: &Value) -> bool {
+        match (value, &self.value) {
+            (Value::String(s), Value::String(expected)) if self.ignore_case => {
+                s.to_lowercase() == *expected
+            }
+            (value, expected) => value == expected,
+        }
+    }
+}
+
+impl RuleTrait for ExactRule {
+    fn matches(&self, event: &Event) -> bool {
+        selected_values(event, &self.select_keys).any(|val| self.value_matches(val))
+    }
+}
+
+/// Matches events with a number under the key between min and max, both included
+pub struct NumberRule {
+    key: String,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl NumberRule {
+    pub fn new(key: String, min: Option<f64>, max: Option<f64>) -> NumberRule {
+        NumberRule { key, min, max }
+    }
+}
+
+impl RuleTrait for NumberRule {
+    fn matches(&self, event: &Event) -> bool {
+        match event.data.get(&self.key).and_then(Value::as_f64) {
+            Some(n) => self.min.is_none_or(|min| n >= min) && self.max.is_none_or(|max| n <= max),
+            None => false,
+        }
+    }
+}
+
+/// Matches events which start from `start` until `end` in the timezone, past midnight if
+/// `end` is before `start`
+pub struct TimeOfDayRule {
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+}
+
+impl TimeOfDayRule {
+    pub fn new(start: NaiveTime, end: NaiveTime, timezone: Tz) -> TimeOfDayRule {
+        TimeOfDayRule {
+            start,
+            end,
+            timezone,
+        }
+    }
+}
+
+impl RuleTrait for TimeOfDayRule {
+    fn matches(&self, event: &Event) -> bool {
+        let time = event.timestamp.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Why a ClassRule could not be compiled into a Rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleError {
+    /// Path to the invalid field within the rule, such as `rules[1].regex`
+    pub path: String,
+    pub message: String,
+    /// Whether a regex or glob pattern failed to compile
+    pub is_regex: bool,
+}
+
+impl RuleError {
+    fn invalid(path: &str, message: String) -> RuleError {
+        RuleError {
+            path: path.to_string(),
+            message,
+            is_regex: false,
+        }
+    }
+
+    fn within(mut self, parent: &str) -> RuleError {
+        self.path = format!("{parent}.{}", self.path);
+        self
+    }
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at '{}')", self.message, self.path)
+    }
+}
+
+impl TryFrom<&ClassRule> for Rule {
+    type Error = RuleError;
+
+    fn try_from(rule: &ClassRule) -> Result<Self, Self::Error> {
+        let check_select_keys = |select_keys: &Option<Vec<String>>| match select_keys {
+            Some(keys) if keys.is_empty() => Err(RuleError::invalid(
+                "select_keys",
+                "select_keys must not be empty".to_string(),
+            )),
+            _ => Ok(()),
+        };
+        let compile_error = |path: &str, pattern: &str, err: fancy_regex::Error| RuleError {
+            path: path.to_string(),
+            message: format!("Failed to compile regex string '{pattern}': {err}"),
+            is_regex: true,
+        };
+        let compile_all = |rules: &[ClassRule]| {
+            rules
+                .iter()
+                .enumerate()
+                .map(|(i, rule)| Rule::try_from(rule).map_err(|e| e.within(&format!("rules[{i}]"))))
+                .collect::<Result<Vec<Rule>, RuleError>>()
+        };
+        match rule {
+            ClassRule::None => Ok(Rule::None),
+            ClassRule::Regex {
+                regex,
+                ignore_case,
+                select_keys,
+            } => {
+                check_select_keys(select_keys)?;
+                RegexRule::new(regex, ignore_case.unwrap_or(false), select_keys.clone())
+                    .map(Rule::Regex)
+                    .map_err(|err| compile_error("regex", regex, err))
+            }
+            ClassRule::Exact {
+                value,
+                ignore_case,
+                select_keys,
+            } => {
+                check_select_keys(select_keys)?;
+                Ok(Rule::Exact(ExactRule::new(
+                    value.clone(),
+                    ignore_case.unwrap_or(false),
+                    select_keys.clone(),
+                )))
+            }
+            ClassRule::Glob {
+                pattern,
+                ignore_case,
+                select_keys,
+            } => {
+                check_select_keys(select_keys)?;
+                RegexRule::from_glob(pattern, ignore_case.unwrap_or(false), select_keys.clone())
+                    .map(Rule::Glob)
+                    .map_err(|err| compile_error("pattern", pattern, err))
+            }
+            ClassRule::Number { key, min, max } => match (min, max) {
+                (Some(min), Some(max)) if min > max => Err(RuleError::invalid(
+                    "max",
+                    format!("max {max} is less than min {min}"),
+                )),
+                _ => Ok(Rule::Number(NumberRule::new(key.clone(), *min, *max))),
+            },
+            ClassRule::TimeOfDay {
+                start,
+                end,
+                timezone,
+            } => {
+                let parse_time = |path: &str, time: &str| {
+                    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+                        RuleError::invalid(path, format!("Invalid time '{time}', expected HH:MM"))
+                    })
+                };
+                let timezone = match timezone {
+                    Some(timezone) => timezone.parse().map_err(|_| {
+                        RuleError::invalid(
+                            "timezone",
+                            format!("Unknown timezone '{timezone}', expected an IANA timezone name such as 'Europe/Stockholm'"),
+                        )
+                    })?,
+                    None => Tz::UTC,
+                };
+                Ok(Rule::TimeOfDay(TimeOfDayRule::new(
+                    parse_time("start", start)?,
+                    parse_time("end", end)?,
+                    timezone,
+                )))
+            }
+            ClassRule::And { rules } => Ok(Rule::And(compile_all(rules)?)),
+            ClassRule::Or { rules } => Ok(Rule::Or(compile_all(rules)?)),
+            ClassRule::Not { rule } => Ok(Rule::Not(Box::new(
+                Rule::try_from(rule.as_ref()).map_err(|e| e.within("rule"))?,
+            ))),
+        }
+    }
+}
+
 /// Categorizes a list of events
 ///
 /// An event can only have one category, although the category may have a hierarchy,
@@ -126,7 +420,8 @@ pub fn categorize(mut events: Vec<Event>, rules: &[(Vec<String>, Rule)]) -> Vec<
 }
 
 fn categorize_one(mut event: Event, rules: &[(Vec<String>, Rule)]) -> Event {
-    let mut category: Vec<String> = vec!["Uncategorized".into()];
+    let uncategorized = ["Uncategorized".to_string()];
+    let mut category: &[String] = &uncategorized;
     for (cat, rule) in rules {
         if rule.matches(&event) {
             category = _pick_highest_ranking_category(category, cat);
@@ -138,6 +433,181 @@ fn categorize_one(mut event: Event, rules: &[(Vec<String>, Rule)]) -> Event {
     event
 }
 
+fn _pick_highest_ranking_category<'a>(acc: &'a [String], item: &'a [String]) -> &'a [String] {
+    if item.len() >= acc.len() {
+        // If tag is category with greater or equal depth than current, then choose the new one instead.
+        item
+    } else {
+        acc
+    }
+}
+
+/// Why an event got its category, see explain_category
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CategoryReason {
+    /// No rule with a category matched, so the event is uncategorized
+    NoMatch,
+    /// A single rule matched
+    OnlyMatch,
+    /// The chosen rule has a deeper category than the other matching rules
+    Deepest,
+    /// Several matching rules have the deepest category, the last of them is chosen
+    LastOfDeepest,
+}
+
+impl CategoryReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CategoryReason::NoMatch => "no_match",
+            CategoryReason::OnlyMatch => "only_match",
+            CategoryReason::Deepest => "deepest",
+            CategoryReason::LastOfDeepest => "last_of_deepest",
+        }
+    }
+}
+
+/// Which rules matched an event and which of them gave it its category
+#[derive(Debug, Clone, PartialEq)]
+pub struct CategoryExplanation {
+    pub category: Vec<String>,
+    /// The indexes of the matching rules, in order
+    pub matches: Vec<usize>,
+    /// The index of the rule whose category was chosen, None if the event is uncategorized
+    pub chosen: Option<usize>,
+    pub reason: CategoryReason,
+}
+
+/// Explains the category which categorize gives an event
+pub fn explain_category(event: &Event, rules: &[(Vec<String>, Rule)]) -> CategoryExplanation {
+    let mut matches = Vec::new();
+    let mut chosen: Option<usize> = None;
+    // Uncategorized counts as a category one deep, so deeper or equally deep ones replace it
+    let mut depth = 1;
+    for (i, (cat, rule)) in rules.iter().enumerate() {
+        if rule.matches(event) {
+            matches.push(i);
+            if cat.len() >= depth {
+                chosen = Some(i);
+                depth = cat.len();
+            }
+        }
+    }
+    let (category, reason) = match chosen {
+        None => (vec!["Uncategorized".into()], CategoryReason::NoMatch),
+        Some(i) => {
+            let equally_deep = matches.iter().filter(|&&j| rules[j].0.len() == depth);
+            let reason = if matches.len() == 1 {
+                CategoryReason::OnlyMatch
+            } else if equally_deep.count() > 1 {
+                CategoryReason::LastOfDeepest
+            } else {
+                CategoryReason::Deepest
+            };
+            (rules[i].0.clone(), reason)
+        }
+    };
+    CategoryExplanation {
+        category,
+        matches,
+        chosen,
+        reason,
+    }
+}
+
+/// The events with the same data which were categorized for the same reason
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainedEvents {
+    pub data: Map<String, Value>,
+    pub count: usize,
+    pub duration: Duration,
+    pub explanation: CategoryExplanation,
+}
+
+/// Explains the categories of the events, see explain_category, grouped by their data so
+/// that every distinct window title or URL is explained once. Events with the same data are
+/// only split up if they matched different rules, as with time of day rules. The groups
+/// with the most time come first.
+pub fn explain_categorize(events: &[Event], rules: &[(Vec<String>, Rule)]) -> Vec<ExplainedEvents> {
+    // The data is keyed on its JSON, as Map can't be hashed
+    let mut indexes: HashMap<(String, Vec<usize>), usize> = HashMap::new();
+    let mut groups: Vec<ExplainedEvents> = Vec::new();
+    for event in events {
+        let explanation = explain_category(event, rules);
+        let key = (
+            Value::Object(event.data.clone()).to_string(),
+            explanation.matches.clone(),
+        );
+        match indexes.get(&key) {
+            Some(&i) => {
+                groups[i].count += 1;
+                groups[i].duration += event.duration;
+            }
+            None => {
+                indexes.insert(key, groups.len());
+                groups.push(ExplainedEvents {
+                    data: event.data.clone(),
+                    count: 1,
+                    duration: event.duration,
+                    explanation,
+                });
+            }
+        }
+    }
+    groups.sort_by_key(|group| Reverse(group.duration));
+    groups
+}
+
+/// How a rule fared on a set of events, see classification_report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleReport {
+    /// The events which the rule matched
+    pub matched: usize,
+    pub matched_duration: Duration,
+    /// The events which got the category of the rule
+    pub chosen: usize,
+    pub chosen_duration: Duration,
+    /// The indexes of the rules which were chosen over this one for events it matched
+    pub shadowed_by: BTreeSet<usize>,
+}
+
+impl RuleReport {
+    /// Whether the rule matched none of the events
+    pub fn never_matches(&self) -> bool {
+        self.matched == 0
+    }
+
+    /// Whether the rule matched events, but never gave them its category
+    pub fn always_shadowed(&self) -> bool {
+        self.matched > 0 && self.chosen == 0
+    }
+}
+
+/// Reports for each of the rules how many of the events it matched and categorized, to find
+/// the rules which never match or which are always shadowed by other rules. Takes the groups
+/// of explain_categorize, so that the rules aren't matched against the events again.
+pub fn classification_report(groups: &[ExplainedEvents], rule_count: usize) -> Vec<RuleReport> {
+    let mut reports = vec![RuleReport::default(); rule_count];
+    for group in groups {
+        let explanation = &group.explanation;
+        for &i in &explanation.matches {
+            let report = &mut reports[i];
+            report.matched += group.count;
+            report.matched_duration += group.duration;
+            match explanation.chosen {
+                Some(chosen) if chosen == i => {
+                    report.chosen += group.count;
+                    report.chosen_duration += group.duration;
+                }
+                Some(chosen) => {
+                    report.shadowed_by.insert(chosen);
+                }
+                None => (),
+            }
+        }
+    }
+    reports
+}
+
 /// Tags a list of events
 ///
 /// An event can have many tags (as opposed to only one category) which will be put into the `$tags` key of
@@ -163,15 +633,6 @@ fn tag_one(mut event: Event, rules: &[(String, Rule)]) -> Event {
     event
 }
 
-fn _pick_highest_ranking_category(acc: Vec<String>, item: &[String]) -> Vec<String> {
-    if item.len() >= acc.len() {
-        // If tag is category with greater or equal depth than current, then choose the new one instead.
-        item.to_vec()
-    } else {
-        acc
-    }
-}
-
 #[test]
 fn test_rule() {
     let mut e_match = Event::default();
@@ -239,6 +700,155 @@ fn test_rule_select_keys_empty_list() {
     let result = RegexRule::new("test", false, Some(vec![]));
     assert!(result.is_err());
 }
+#[test]
+fn test_rule_exact() {
+    let mut event = Event::default();
+    event
+        .data
+        .insert("app".into(), serde_json::json!("Firefox"));
+    event.data.insert("pid".into(), serde_json::json!(123));
+
+    let exact = |value, ignore_case| Rule::Exact(ExactRule::new(value, ignore_case, None));
+    assert!(exact(serde_json::json!("Firefox"), false).matches(&event));
+    assert!(!exact(serde_json::json!("firefox"), false).matches(&event));
+    assert!(exact(serde_json::json!("firefox"), true).matches(&event));
+    assert!(!exact(serde_json::json!("Fire"), false).matches(&event));
+    assert!(exact(serde_json::json!(123), false).matches(&event));
+
+    let title_only = ExactRule::new(
+        serde_json::json!("Firefox"),
+        false,
+        Some(vec!["title".into()]),
+    );
+    assert!(!Rule::Exact(title_only).matches(&event));
+}
+
+#[test]
+fn test_rule_glob() {
+    let cases = [
+        ("*.rs", "main.rs", true),
+        ("*.rs", "main.rs - vim", false),
+        ("*vim*", "main.rs - vim", true),
+        ("ma?n.rs", "main.rs", true),
+        ("ma?n.rs", "man.rs", false),
+        ("[ms]ain.rs", "sain.rs", true),
+        ("[!ms]ain.rs", "sain.rs", false),
+        ("[a-c]", "b", true),
+        ("[]]", "]", true),
+        ("a[b", "a[b", true),
+        ("(a+b)", "(a+b)", true),
+        ("(a+b)", "aab", false),
+    ];
+    for (pattern, value, expected) in cases {
+        let mut event = Event::default();
+        event.data.insert("title".into(), serde_json::json!(value));
+        let rule = Rule::Glob(RegexRule::from_glob(pattern, false, None).unwrap());
+        assert_eq!(rule.matches(&event), expected, "{pattern} on {value}");
+    }
+}
+
+#[test]
+fn test_rule_number() {
+    let mut event = Event::default();
+    event.data.insert("pid".into(), serde_json::json!(10));
+    event.data.insert("name".into(), serde_json::json!("10"));
+
+    let number = |key: &str, min, max| Rule::Number(NumberRule::new(key.into(), min, max));
+    assert!(number("pid", Some(10.0), Some(10.0)).matches(&event));
+    assert!(number("pid", None, Some(20.0)).matches(&event));
+    assert!(!number("pid", Some(10.5), None).matches(&event));
+    // Only numbers are compared
+    assert!(!number("name", None, None).matches(&event));
+    assert!(!number("missing", None, None).matches(&event));
+}
+
+#[test]
+fn test_rule_time_of_day() {
+    use chrono::{DateTime, NaiveTime};
+    use std::str::FromStr;
+
+    let at = |timestamp: &str| Event {
+        timestamp: DateTime::from_str(timestamp).unwrap(),
+        ..Default::default()
+    };
+    let time = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+    let work = Rule::TimeOfDay(TimeOfDayRule::new(time("09:00"), time("17:00"), Tz::UTC));
+    assert!(work.matches(&at("2000-01-01T09:00:00Z")));
+    assert!(!work.matches(&at("2000-01-01T17:00:00Z")));
+    assert!(!work.matches(&at("2000-01-01T08:59:59Z")));
+
+    // Past midnight, in the timezone
+    let night = Rule::TimeOfDay(TimeOfDayRule::new(
+        time("22:00"),
+        time("06:00"),
+        Tz::Europe__Stockholm,
+    ));
+    assert!(night.matches(&at("2000-01-01T21:30:00Z")));
+    assert!(night.matches(&at("2000-01-01T04:00:00Z")));
+    assert!(!night.matches(&at("2000-01-01T05:00:00Z")));
+}
+
+#[test]
+fn test_rule_from_class_rule() {
+    use chrono::DateTime;
+    use std::str::FromStr;
+
+    let class_rule: ClassRule = serde_json::from_value(serde_json::json!({
+        "type": "and",
+        "rules": [
+            {"type": "glob", "pattern": "*.rs", "select_keys": ["title"]},
+            {"type": "time_of_day", "start": "09:00", "end": "17:00"},
+            {"type": "not", "rule": {"type": "exact", "value": "slack", "select_keys": ["app"]}},
+            {"type": "or", "rules": [
+                {"type": "regex", "regex": "^code$"},
+                {"type": "number", "key": "pid", "max": 100}
+            ]}
+        ]
+    }))
+    .unwrap();
+    let rule = Rule::try_from(&class_rule).unwrap();
+
+    let mut event = Event {
+        timestamp: DateTime::from_str("2000-01-01T10:00:00Z").unwrap(),
+        ..Default::default()
+    };
+    event.data.insert("app".into(), serde_json::json!("vim"));
+    event
+        .data
+        .insert("title".into(), serde_json::json!("main.rs"));
+    event.data.insert("pid".into(), serde_json::json!(50));
+    assert!(rule.matches(&event));
+
+    event.data.insert("pid".into(), serde_json::json!(500));
+    assert!(!rule.matches(&event));
+    event.data.insert("app".into(), serde_json::json!("code"));
+    assert!(rule.matches(&event));
+    event.timestamp = DateTime::from_str("2000-01-01T18:00:00Z").unwrap();
+    assert!(!rule.matches(&event));
+
+    let error = |rule: serde_json::Value| {
+        let class_rule: ClassRule = serde_json::from_value(rule).unwrap();
+        Rule::try_from(&class_rule).err().unwrap()
+    };
+    let err = error(serde_json::json!({"type": "or", "rules": [
+        {"type": "none"},
+        {"type": "not", "rule": {"type": "regex", "regex": "("}}
+    ]}));
+    assert_eq!(err.path, "rules[1].rule.regex");
+    assert!(err.is_regex);
+    let err = error(serde_json::json!({"type": "time_of_day", "start": "9", "end": "17:00"}));
+    assert_eq!(err.path, "start");
+    assert!(!err.is_regex);
+    let err = error(serde_json::json!(
+        {"type": "time_of_day", "start": "09:00", "end": "17:00", "timezone": "Mars/Olympus"}
+    ));
+    assert_eq!(err.path, "timezone");
+    let err = error(serde_json::json!({"type": "number", "key": "pid", "min": 2, "max": 1}));
+    assert_eq!(err.path, "max");
+    let err = error(serde_json::json!({"type": "exact", "value": 1, "select_keys": []}));
+    assert_eq!(err.path, "select_keys");
+}
+
 #[test]
 fn test_categorize() {
     let mut e = Event::default();
@@ -290,6 +900,127 @@ fn test_categorize_uncategorized() {
     );
 }
 
+#[test]
+fn test_explain_category() {
+    let app = |app: &str| {
+        let mut e = Event::default();
+        e.data.insert("app".into(), serde_json::json!(app));
+        e
+    };
+    let rule = |category: &[&str], regex: &str| {
+        (
+            category
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>(),
+            Rule::from(Regex::new(regex).unwrap()),
+        )
+    };
+    let rules = vec![
+        rule(&["Work"], "vim|code"),
+        rule(&["Work", "Programming"], "vim"),
+        rule(&["Media"], "mpv"),
+        rule(&["Media", "Video"], "vlc"),
+        rule(&["Comms", "Chat"], "vlc"),
+    ];
+
+    let explanation = explain_category(&app("vim"), &rules);
+    assert_eq!(explanation.category, vec!["Work", "Programming"]);
+    assert_eq!(explanation.matches, vec![0, 1]);
+    assert_eq!(explanation.chosen, Some(1));
+    assert_eq!(explanation.reason, CategoryReason::Deepest);
+
+    let explanation = explain_category(&app("code"), &rules);
+    assert_eq!(
+        (explanation.chosen, explanation.reason),
+        (Some(0), CategoryReason::OnlyMatch)
+    );
+
+    let explanation = explain_category(&app("vlc"), &rules);
+    assert_eq!(explanation.category, vec!["Comms", "Chat"]);
+    assert_eq!(explanation.reason, CategoryReason::LastOfDeepest);
+
+    let explanation = explain_category(&app("firefox"), &rules);
+    assert_eq!(explanation.category, vec!["Uncategorized"]);
+    assert_eq!(
+        (explanation.chosen, explanation.reason),
+        (None, CategoryReason::NoMatch)
+    );
+
+    // Explaining gives the same categories as categorize
+    let events: Vec<Event> = ["vim", "code", "vlc", "mpv", "firefox"]
+        .into_iter()
+        .map(app)
+        .collect();
+    for event in categorize(events.clone(), &rules) {
+        assert_eq!(
+            event.data["$category"],
+            serde_json::json!(explain_category(&event, &rules).category)
+        );
+    }
+
+    let mut long_vim = app("vim");
+    long_vim.duration = Duration::seconds(10);
+    let groups = explain_categorize(&[app("code"), long_vim, app("vim"), app("code")], &rules);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].data["app"], "vim");
+    assert_eq!(
+        (groups[0].count, groups[0].duration),
+        (2, Duration::seconds(10))
+    );
+    assert_eq!(groups[0].explanation.chosen, Some(1));
+    assert_eq!(groups[1].count, 2);
+}
+
+#[test]
+fn test_classification_report() {
+    let app = |app: &str, secs: i64| {
+        let mut e = Event {
+            duration: Duration::seconds(secs),
+            ..Default::default()
+        };
+        e.data.insert("app".into(), serde_json::json!(app));
+        e
+    };
+    let rules: Vec<(Vec<String>, Rule)> = vec![
+        (
+            vec!["Work".into()],
+            Rule::from(Regex::new("vim|code").unwrap()),
+        ),
+        (
+            vec!["Work".into(), "Programming".into()],
+            Rule::from(Regex::new("vim").unwrap()),
+        ),
+        // Shadowed by the deeper rule above for every event it matches
+        (
+            vec!["Editors".into()],
+            Rule::from(Regex::new("vim").unwrap()),
+        ),
+        (
+            vec!["Games".into()],
+            Rule::from(Regex::new("steam").unwrap()),
+        ),
+    ];
+    let events = vec![app("vim", 10), app("code", 5), app("vim", 1)];
+    let reports = classification_report(&explain_categorize(&events, &rules), rules.len());
+
+    assert_eq!(reports[0].matched, 3);
+    assert_eq!(reports[0].matched_duration, Duration::seconds(16));
+    assert_eq!(reports[0].chosen, 1);
+    assert_eq!(reports[0].chosen_duration, Duration::seconds(5));
+    assert_eq!(reports[0].shadowed_by.iter().collect::<Vec<_>>(), vec![&1]);
+    assert!(!reports[0].always_shadowed());
+
+    assert_eq!((reports[1].matched, reports[1].chosen), (2, 2));
+    assert!(reports[1].shadowed_by.is_empty());
+
+    assert!(reports[2].always_shadowed());
+    assert_eq!(reports[2].shadowed_by.iter().collect::<Vec<_>>(), vec![&1]);
+
+    assert!(reports[3].never_matches());
+    assert!(!reports[3].always_shadowed());
+}
+
 #[test]
 fn test_tag() {
     let mut e = Event::default();
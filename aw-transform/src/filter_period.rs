@@ -1,5 +1,5 @@
 use aw_models::Event;
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 
 use crate::sort_by_timestamp;
 
@@ -68,6 +68,34 @@ pub fn filter_period_intersect(events: Vec<Event>, filter_events: Vec<Event>) ->
     }
 }
 
+/// Clips events to the period from start to end, and removes those outside of it. Unlike
+/// filter_period_intersect the events keep their order, and events without a duration are
+/// kept if they are in the period.
+///
+/// # Example
+/// ```ignore
+/// period:  |        |
+/// events: [a   ][b     ][c]
+/// output:   [a ][b     ]
+/// ```
+pub fn filter_period(events: Vec<Event>, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Event> {
+    events
+        .into_iter()
+        .filter_map(|mut event| {
+            let event_end = event.calculate_endtime().min(end);
+            let event_start = event.timestamp.max(start);
+            // Events which only touch the start of the period are outside of it
+            let touching = event_start == event_end && !event.duration.is_zero();
+            if event_start > event_end || event_start >= end || touching {
+                return None;
+            }
+            event.timestamp = event_start;
+            event.duration = event_end - event_start;
+            Some(event)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -79,7 +107,7 @@ mod tests {
 
     use aw_models::Event;
 
-    use super::filter_period_intersect;
+    use super::{filter_period, filter_period_intersect};
 
     #[test]
     fn test_filter_period_intersect() {
@@ -155,4 +183,33 @@ mod tests {
         assert_eq!(res[0].timestamp, timestamp_01s);
         assert_eq!(res[0].duration, Duration::milliseconds(1000));
     }
+
+    #[test]
+    fn test_filter_period() {
+        let event = |timestamp: &str, secs: i64| Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::seconds(secs),
+            data: json_map! {"test": json!(1)},
+        };
+        let start = DateTime::from_str("2000-01-01T00:00:10Z").unwrap();
+        let end = DateTime::from_str("2000-01-01T00:00:20Z").unwrap();
+        let events = vec![
+            event("2000-01-01T00:00:15Z", 10),
+            event("2000-01-01T00:00:05Z", 10),
+            event("2000-01-01T00:00:00Z", 5),
+            event("2000-01-01T00:00:05Z", 5),
+            event("2000-01-01T00:00:12Z", 0),
+            event("2000-01-01T00:00:20Z", 5),
+        ];
+        let res = filter_period(events, start, end);
+        assert_eq!(
+            res,
+            vec![
+                event("2000-01-01T00:00:15Z", 5),
+                event("2000-01-01T00:00:10Z", 5),
+                event("2000-01-01T00:00:12Z", 0),
+            ]
+        );
+    }
 }
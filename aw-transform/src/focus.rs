@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{Map, Value};
+
+use aw_models::Event;
+
+use crate::stats::merge_by_value;
+
+/// Lower bounds in seconds of the bins of dwell_time_distribution, each bin ends where the
+/// next one starts and the last one has no end
+pub const DWELL_TIME_BINS: [i64; 8] = [0, 10, 30, 60, 5 * 60, 15 * 60, 30 * 60, 60 * 60];
+
+/// The events which have the key, sorted by timestamp, with the value of the key
+fn keyed<'a>(events: &'a [Event], key: &str) -> Vec<(&'a Value, &'a Event)> {
+    let mut keyed: Vec<(&Value, &Event)> = events
+        .iter()
+        .filter_map(|event| Some((event.data.get(key)?, event)))
+        .collect();
+    keyed.sort_by_key(|(_, event)| event.timestamp);
+    keyed
+}
+
+/// Joins consecutive events with the same value of the key into periods of uninterrupted
+/// focus on that value. A period is interrupted by an event with another value or by a gap
+/// between events, so short gaps should be flooded first. Events without the key are left
+/// out. The periods have the key and its value as data.
+///
+/// # Example
+/// ```ignore
+/// key:    app
+/// input:  [a][a][b]  [b][a]
+/// output: [a   ][b]  [b][a]
+/// ```
+pub fn focus_periods(events: &[Event], key: &str) -> Vec<Event> {
+    let mut periods: Vec<Event> = Vec::new();
+    for (value, event) in keyed(events, key) {
+        if let Some(period) = periods.last_mut() {
+            let end = period.calculate_endtime();
+            if period.data.get(key) == Some(value) && event.timestamp <= end {
+                period.duration = end.max(event.calculate_endtime()) - period.timestamp;
+                continue;
+            }
+        }
+        let mut data = Map::new();
+        data.insert(key.to_string(), value.clone());
+        periods.push(Event {
+            id: None,
+            timestamp: event.timestamp,
+            duration: event.duration,
+            data,
+        });
+    }
+    periods
+}
+
+/// Counts the switches between different values of the key per hour, each switch counted
+/// in the hour of the event switched to. Consecutive events with different values are a
+/// switch even with a gap between them. Every hour in which an event with the key starts
+/// is included, also the ones without switches.
+///
+/// The hours are aligned to `start_of_day` past midnight UTC like in bin_by_time, so they
+/// start at the same minute as the day.
+///
+/// # Example
+/// ```ignore
+/// key:    app
+/// input:  [a 09:10][b 09:20][b 09:30][a 10:05][a 11:00]
+/// output: {09:00: 1, 10:00: 1, 11:00: 0}
+/// ```
+pub fn switches_per_hour(
+    events: &[Event],
+    key: &str,
+    start_of_day: Duration,
+) -> BTreeMap<DateTime<Utc>, usize> {
+    let hour_ns = Duration::hours(1).num_nanoseconds().unwrap();
+    let offset_ns = start_of_day
+        .num_nanoseconds()
+        .unwrap_or(0)
+        .rem_euclid(hour_ns);
+    let mut hours = BTreeMap::new();
+    let mut prev: Option<&Value> = None;
+    for (value, event) in keyed(events, key) {
+        let Some(t) = event.timestamp.timestamp_nanos_opt() else {
+            continue;
+        };
+        let hour_start = (t - offset_ns).div_euclid(hour_ns) * hour_ns + offset_ns;
+        let switches = hours
+            .entry(DateTime::from_timestamp_nanos(hour_start))
+            .or_insert(0);
+        if prev.is_some_and(|prev| prev != value) {
+            *switches += 1;
+        }
+        prev = Some(value);
+    }
+    hours
+}
+
+/// The longest focus period of each value of the key, see focus_periods, longest first.
+/// Values with equally long periods are ordered by first appearance.
+pub fn longest_focus(events: &[Event], key: &str) -> Vec<Event> {
+    let periods = focus_periods(events, key);
+    merge_by_value(
+        periods.iter().map(|period| (&period.data[key], period)),
+        |longest, period| {
+            if period.duration > longest.duration {
+                *longest = period;
+            }
+        },
+        |period| period.duration,
+    )
+    .into_iter()
+    .map(|(_, period)| period.clone())
+    .collect()
+}
+
+/// Counts the focus periods of the key, see focus_periods, per bin of how long they lasted.
+/// The bins start at the durations in DWELL_TIME_BINS.
+///
+/// # Example
+/// ```ignore
+/// key:    app
+/// input:  [a 5s][b 20s][a 25s][c 2h]
+/// output: [1, 2, 0, 0, 0, 0, 0, 1]
+/// ```
+pub fn dwell_time_distribution(events: &[Event], key: &str) -> [usize; DWELL_TIME_BINS.len()] {
+    let mut bins = [0; DWELL_TIME_BINS.len()];
+    for period in focus_periods(events, key) {
+        let bin = DWELL_TIME_BINS
+            .iter()
+            .rposition(|&from| period.duration >= Duration::seconds(from))
+            .unwrap_or(0);
+        bins[bin] += 1;
+    }
+    bins
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::DateTime;
+    use chrono::Duration;
+    use chrono::Utc;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::*;
+    use crate::test_utils::event;
+
+    fn apps(events: &[Event]) -> Vec<(String, i64)> {
+        events
+            .iter()
+            .map(|e| (e.data["app"].to_string(), e.duration.num_seconds()))
+            .collect()
+    }
+
+    #[test]
+    fn test_focus_periods() {
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 10, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:10Z", 10, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:20Z", 5, json_map! {"app": "b"}),
+            // A gap interrupts the focus on b
+            event("2000-01-01T00:00:30Z", 5, json_map! {"app": "b"}),
+            event("2000-01-01T00:00:35Z", 5, json_map! {"app": "a"}),
+            // Without the key
+            event("2000-01-01T00:00:40Z", 5, json_map! {"title": "x"}),
+        ];
+        let periods = focus_periods(&events, "app");
+        assert_eq!(
+            apps(&periods),
+            vec![
+                ("\"a\"".to_string(), 20),
+                ("\"b\"".to_string(), 5),
+                ("\"b\"".to_string(), 5),
+                ("\"a\"".to_string(), 5)
+            ]
+        );
+        assert_eq!(periods[0].data, json_map! {"app": json!("a")});
+    }
+
+    #[test]
+    fn test_switches_per_hour() {
+        let events = vec![
+            event("2000-01-01T09:10:00Z", 60, json_map! {"app": "a"}),
+            event("2000-01-01T09:30:00Z", 60, json_map! {"app": "b"}),
+            event("2000-01-01T09:20:00Z", 60, json_map! {"app": "b"}),
+            event("2000-01-01T10:05:00Z", 60, json_map! {"app": "a"}),
+            event("2000-01-01T11:00:00Z", 60, json_map! {"app": "a"}),
+        ];
+        let hour = |h: &str| DateTime::<Utc>::from_str(h).unwrap();
+        let hours = switches_per_hour(&events, "app", Duration::zero());
+        assert_eq!(
+            hours.into_iter().collect::<Vec<_>>(),
+            vec![
+                (hour("2000-01-01T09:00:00Z"), 1),
+                (hour("2000-01-01T10:00:00Z"), 1),
+                (hour("2000-01-01T11:00:00Z"), 0),
+            ]
+        );
+
+        // Hours start at the same minute as the day
+        let hours = switches_per_hour(&events, "app", Duration::minutes(4 * 60 + 30));
+        assert_eq!(
+            hours.into_iter().collect::<Vec<_>>(),
+            vec![
+                (hour("2000-01-01T08:30:00Z"), 1),
+                (hour("2000-01-01T09:30:00Z"), 1),
+                (hour("2000-01-01T10:30:00Z"), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_longest_focus() {
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 10, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:10Z", 30, json_map! {"app": "b"}),
+            event("2000-01-01T00:00:40Z", 20, json_map! {"app": "a"}),
+            event("2000-01-01T00:01:00Z", 20, json_map! {"app": "a"}),
+            event("2000-01-01T00:01:20Z", 5, json_map! {"app": "c"}),
+            event("2000-01-01T00:01:25Z", 5, json_map! {"app": "d"}),
+        ];
+        let longest = longest_focus(&events, "app");
+        assert_eq!(
+            apps(&longest),
+            vec![
+                ("\"a\"".to_string(), 40),
+                ("\"b\"".to_string(), 30),
+                ("\"c\"".to_string(), 5),
+                ("\"d\"".to_string(), 5)
+            ]
+        );
+        assert_eq!(
+            longest[0].timestamp,
+            DateTime::<Utc>::from_str("2000-01-01T00:00:40Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dwell_time_distribution() {
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 5, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:05Z", 20, json_map! {"app": "b"}),
+            event("2000-01-01T00:00:25Z", 25, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:50Z", 10, json_map! {"app": "b"}),
+            event("2000-01-01T00:01:00Z", 7200, json_map! {"app": "c"}),
+        ];
+        assert_eq!(
+            dwell_time_distribution(&events, "app"),
+            [1, 3, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(dwell_time_distribution(&[], "app"), [0; 8]);
+    }
+}
@@ -40,7 +40,7 @@ mod filter_keyvals;
 pub use filter_keyvals::{exclude_keyvals, filter_keyvals, filter_keyvals_regex};
 
 mod filter_period;
-pub use filter_period::filter_period_intersect;
+pub use filter_period::{filter_period, filter_period_intersect};
 
 mod split_url;
 pub use split_url::split_url_event;
@@ -50,3 +50,48 @@ pub use period_union::period_union;
 
 mod union_no_overlap;
 pub use union_no_overlap::union_no_overlap;
+
+mod bin;
+pub use bin::{bin_by_time, group_by_hour_of_day, group_by_weekday};
+
+mod split_by_day;
+pub use split_by_day::split_by_day;
+
+mod stats;
+pub use stats::{
+    max_timestamp, mean_duration, median_duration, min_timestamp, percentile_duration, top_n,
+};
+
+mod map_data;
+pub use map_data::{drop_keys, extract_regex, map_data, map_values, rename_key, set_key};
+
+mod sessionize;
+pub use sessionize::sessionize;
+
+mod focus;
+pub use focus::{
+    dwell_time_distribution, focus_periods, longest_focus, switches_per_hour, DWELL_TIME_BINS,
+};
+
+mod category_tree;
+pub use category_tree::{category_tree, CategoryNode};
+
+#[cfg(test)]
+mod test_utils {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration};
+    use serde_json::{Map, Value};
+
+    use aw_models::Event;
+
+    /// An event without an id for the tests of the transforms
+    pub fn event(timestamp: &str, secs: i64, data: Map<String, Value>) -> Event {
+        Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::seconds(secs),
+            data,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use fancy_regex::Regex;
+use serde_json::map::Map;
+use serde_json::value::Value;
+
+use aw_models::Event;
+
+/// Replaces the data of each event with what a function returns for it, stops at the first
+/// error
+pub fn map_data<E>(
+    mut events: Vec<Event>,
+    mut f: impl FnMut(Map<String, Value>) -> Result<Map<String, Value>, E>,
+) -> Result<Vec<Event>, E> {
+    for event in events.iter_mut() {
+        let data = std::mem::take(&mut event.data);
+        event.data = f(data)?;
+    }
+    Ok(events)
+}
+
+/// Sets a key to the same value in all events, replacing any previous value
+///
+/// # Example
+/// ```ignore
+/// key:    a
+/// value:  1
+/// input:  [a:0][b:2]
+/// output: [a:1][a:1, b:2]
+/// ```
+pub fn set_key(mut events: Vec<Event>, key: &str, value: &Value) -> Vec<Event> {
+    for event in events.iter_mut() {
+        event.data.insert(key.to_string(), value.clone());
+    }
+    events
+}
+
+/// Moves the value of a key to another key, replacing any value the other key had. Events
+/// without the key are left as they are.
+///
+/// # Example
+/// ```ignore
+/// from:   a
+/// to:     b
+/// input:  [a:1][a:2, b:3][c:4]
+/// output: [b:1][b:2][c:4]
+/// ```
+pub fn rename_key(mut events: Vec<Event>, from: &str, to: &str) -> Vec<Event> {
+    for event in events.iter_mut() {
+        if let Some(value) = event.data.remove(from) {
+            event.data.insert(to.to_string(), value);
+        }
+    }
+    events
+}
+
+/// Removes keys from the data of all events
+///
+/// # Example
+/// ```ignore
+/// keys:   [a, b]
+/// input:  [a:1, c:2][b:3]
+/// output: [c:2][]
+/// ```
+pub fn drop_keys(mut events: Vec<Event>, keys: &[String]) -> Vec<Event> {
+    for event in events.iter_mut() {
+        for key in keys {
+            event.data.remove(key);
+        }
+    }
+    events
+}
+
+/// Sets a key to the text matched by a group of a regex on the string value of another key,
+/// 0 being the whole match. Events where the regex or the group doesn't match are left as
+/// they are.
+///
+/// # Example
+/// ```ignore
+/// source: title
+/// regex:  " - (\w+) - Visual Studio Code$"
+/// target: project
+/// group:  1
+/// input:  [title:"main.rs - aw-server - Visual Studio Code"][title:"Inbox"]
+/// output: [title:"main.rs - aw-server - Visual Studio Code", project:"aw-server"][title:"Inbox"]
+/// ```
+pub fn extract_regex(
+    mut events: Vec<Event>,
+    source: &str,
+    regex: &Regex,
+    target: &str,
+    group: usize,
+) -> Vec<Event> {
+    for event in events.iter_mut() {
+        let captured = match event.data.get(source).and_then(|v| v.as_str()) {
+            Some(value) => match regex.captures(value) {
+                Ok(captures) => captures
+                    .and_then(|c| c.get(group))
+                    .map(|m| m.as_str().to_string()),
+                Err(err) => {
+                    warn!("Failed to run regex: {}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(captured) = captured {
+            event
+                .data
+                .insert(target.to_string(), Value::String(captured));
+        }
+    }
+    events
+}
+
+/// Replaces string values of a key which are in the mapping with what they map to, such as
+/// the names an app has on different platforms with a single name. Other values are left
+/// as they are.
+///
+/// # Example
+/// ```ignore
+/// key:     app
+/// mapping: {"chrome.exe": "Chrome", "Google-chrome": "Chrome"}
+/// input:   [app:"chrome.exe"][app:"Google-chrome"][app:"Firefox"]
+/// output:  [app:"Chrome"][app:"Chrome"][app:"Firefox"]
+/// ```
+pub fn map_values(
+    mut events: Vec<Event>,
+    key: &str,
+    mapping: &HashMap<String, Value>,
+) -> Vec<Event> {
+    for event in events.iter_mut() {
+        let mapped = match event.data.get(key).and_then(|v| v.as_str()) {
+            Some(value) => mapping.get(value),
+            None => None,
+        };
+        if let Some(mapped) = mapped {
+            event.data.insert(key.to_string(), mapped.clone());
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fancy_regex::Regex;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::event;
+
+    #[test]
+    fn test_map_data() {
+        let events = vec![event("2000-01-01T00:00:00Z", 1, json_map! {"a": 1})];
+        let res: Result<_, ()> = map_data(events.clone(), |mut data| {
+            data.insert("b".to_string(), json!(2));
+            Ok(data)
+        });
+        assert_eq!(res.unwrap()[0].data, json_map! {"a": 1, "b": 2});
+        let res = map_data(events, |_| Err("failed"));
+        assert_eq!(res, Err("failed"));
+    }
+
+    #[test]
+    fn test_set_rename_drop() {
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 1, json_map! {"a": 0}),
+            event("2000-01-01T00:00:00Z", 1, json_map! {"b": 2}),
+        ];
+        let res = set_key(events.clone(), "a", &json!(1));
+        assert_eq!(res[0].data, json_map! {"a": 1});
+        assert_eq!(res[1].data, json_map! {"a": 1, "b": 2});
+
+        let res = rename_key(events.clone(), "a", "b");
+        assert_eq!(res[0].data, json_map! {"b": 0});
+        assert_eq!(res[1].data, json_map! {"b": 2});
+
+        let res = drop_keys(events, &["a".to_string(), "c".to_string()]);
+        assert_eq!(res[0].data, json_map! {});
+        assert_eq!(res[1].data, json_map! {"b": 2});
+    }
+
+    #[test]
+    fn test_extract_regex() {
+        let events = vec![
+            event(
+                "2000-01-01T00:00:00Z",
+                1,
+                json_map! {"title": "main.rs - aw-server - Visual Studio Code"},
+            ),
+            event("2000-01-01T00:00:00Z", 1, json_map! {"title": "Inbox"}),
+            event("2000-01-01T00:00:00Z", 1, json_map! {"title": 1}),
+        ];
+        let regex = Regex::new(r" - ([\w-]+) - Visual Studio Code$").unwrap();
+        let res = extract_regex(events.clone(), "title", &regex, "project", 1);
+        assert_eq!(res[0].data["project"], json!("aw-server"));
+        assert_eq!(&res[1..], &events[1..]);
+
+        // A group which doesn't exist leaves the events as they are
+        let res = extract_regex(events.clone(), "title", &regex, "project", 2);
+        assert_eq!(res, events);
+    }
+
+    #[test]
+    fn test_map_values() {
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 1, json_map! {"app": "chrome.exe"}),
+            event("2000-01-01T00:00:00Z", 1, json_map! {"app": "Firefox"}),
+            event("2000-01-01T00:00:00Z", 1, json_map! {"title": "chrome.exe"}),
+        ];
+        let mut mapping = HashMap::new();
+        mapping.insert("chrome.exe".to_string(), json!("chrome"));
+        let res = map_values(events.clone(), "app", &mapping);
+        assert_eq!(res[0].data, json_map! {"app": "chrome"});
+        assert_eq!(&res[1..], &events[1..]);
+    }
+}
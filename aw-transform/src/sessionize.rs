@@ -0,0 +1,127 @@
+use std::cmp::max;
+
+use aw_models::Event;
+use chrono::Duration;
+use serde_json::{Map, Value};
+
+use crate::{sort_by_timestamp, top_n};
+
+/// Groups events into sessions, stretches of activity separated by gaps longer than `gap`,
+/// and returns an event for each session which spans it from the start of its first event
+/// to the end of its last. Unlike flood the events themselves are left as they are.
+///
+/// The data of a session event has:
+/// - `active_duration`: the seconds covered by its events, overlaps counted once
+/// - `event_count`: the number of events in it
+/// - `key`: the value of the key with the longest summed duration in the session, left out
+///   if none of its events have the key
+///
+/// # Example
+/// ```ignore
+/// gap:    2 spaces
+/// input:  [a ] [b]  [a]    [b ]
+/// output: [a          ]    [b ]
+/// ```
+pub fn sessionize(events: Vec<Event>, gap: Duration, key: &str) -> Vec<Event> {
+    let mut sessions = Vec::new();
+    let mut session: Vec<Event> = Vec::new();
+    let mut session_end = None;
+    for event in sort_by_timestamp(events) {
+        if let Some(end) = session_end {
+            if event.timestamp - end > gap {
+                sessions.push(summarize(&session, key));
+                session.clear();
+                session_end = None;
+            }
+        }
+        session_end = max(session_end, Some(event.calculate_endtime()));
+        session.push(event);
+    }
+    if !session.is_empty() {
+        sessions.push(summarize(&session, key));
+    }
+    sessions
+}
+
+/// The event of a session, from its events sorted by timestamp
+fn summarize(events: &[Event], key: &str) -> Event {
+    let start = events[0].timestamp;
+    let mut end = start;
+    let mut active = Duration::zero();
+    for event in events {
+        let event_end = event.calculate_endtime();
+        // Events are sorted by start, so only the part after the ones before can be new
+        if event_end > end {
+            active += event_end - max(event.timestamp, end);
+            end = event_end;
+        }
+    }
+
+    let mut data = Map::new();
+    data.insert(
+        "active_duration".to_string(),
+        Value::from(active.num_milliseconds() as f64 / 1000.0),
+    );
+    data.insert("event_count".to_string(), Value::from(events.len()));
+    if let Some((value, _)) = top_n(events, key, 1).pop() {
+        data.insert(key.to_string(), value);
+    }
+    Event {
+        id: None,
+        timestamp: start,
+        duration: end - start,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::DateTime;
+    use chrono::Duration;
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::sessionize;
+    use crate::test_utils::event;
+
+    #[test]
+    fn test_sessionize() {
+        let events = vec![
+            event("2000-01-01T00:05:00Z", 60, json_map! {"app": "b"}),
+            event("2000-01-01T00:00:00Z", 120, json_map! {"app": "a"}),
+            // Overlaps the first event, the overlap is only active once
+            event("2000-01-01T00:01:00Z", 120, json_map! {"app": "a"}),
+            // Right after a gap of 10 minutes, the longest gap within a session
+            event("2000-01-01T00:16:00Z", 60, json_map! {"app": "b"}),
+            event("2000-01-01T01:00:00Z", 0, json_map! {"app": "c"}),
+        ];
+        let sessions = sessionize(events, Duration::minutes(10), "app");
+        assert_eq!(sessions.len(), 2);
+
+        let start: DateTime<Utc> = DateTime::from_str("2000-01-01T00:00:00Z").unwrap();
+        assert_eq!(sessions[0].timestamp, start);
+        assert_eq!(sessions[0].duration, Duration::minutes(17));
+        assert_eq!(
+            serde_json::Value::Object(sessions[0].data.clone()),
+            json!({"active_duration": 300.0, "event_count": 4, "app": "a"})
+        );
+
+        assert_eq!(sessions[1].timestamp, start + Duration::hours(1));
+        assert_eq!(sessions[1].duration, Duration::zero());
+        assert_eq!(
+            serde_json::Value::Object(sessions[1].data.clone()),
+            json!({"active_duration": 0.0, "event_count": 1, "app": "c"})
+        );
+
+        // Without the key there is no dominant value
+        let sessions = sessionize(
+            vec![event("2000-01-01T00:00:00Z", 1, json_map! {"app": "a"})],
+            Duration::minutes(10),
+            "title",
+        );
+        assert_eq!(sessions[0].data.get("title"), None);
+        assert!(sessionize(vec![], Duration::minutes(10), "app").is_empty());
+    }
+}
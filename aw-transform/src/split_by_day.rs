@@ -0,0 +1,158 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use aw_models::Event;
+
+/// Splits events at the boundaries between days in a timezone, so that each part can be
+/// counted for the day it's in rather than the day the event started.
+///
+/// The days start at `start_of_day` past local midnight. When the clocks are turned back
+/// and the start of the day happens twice, the day starts at the first of them. When the
+/// clocks are turned forward past it, the day starts when they were turned.
+///
+/// # Example
+/// ```ignore
+/// tz:           Europe/Stockholm (UTC+1)
+/// start_of_day: 04:00
+/// input:  [a 01-01 02:00-05:00 UTC  ]
+/// output: [a 02:00-03:00][a 03:00-05:00]
+/// ```
+pub fn split_by_day(events: Vec<Event>, tz: Tz, start_of_day: Duration) -> Vec<Event> {
+    let mut split_events = Vec::with_capacity(events.len());
+    for mut event in events {
+        let end = event.calculate_endtime();
+        let mut day = local_day(event.timestamp, tz, start_of_day);
+        loop {
+            day = match day.succ_opt() {
+                Some(day) => day,
+                None => break,
+            };
+            let boundary = match day_start(day, tz, start_of_day) {
+                Some(boundary) => boundary,
+                None => break,
+            };
+            if boundary >= end {
+                break;
+            }
+            if boundary > event.timestamp {
+                let mut part = event.clone();
+                part.duration = boundary - event.timestamp;
+                split_events.push(part);
+                event.timestamp = boundary;
+                event.duration = end - boundary;
+            }
+        }
+        split_events.push(event);
+    }
+    split_events
+}
+
+/// The day which a point in time belongs to, in a timezone with days starting at
+/// `start_of_day` past midnight
+fn local_day(timestamp: DateTime<Utc>, tz: Tz, start_of_day: Duration) -> NaiveDate {
+    (timestamp.with_timezone(&tz).naive_local() - start_of_day).date()
+}
+
+/// When a day starts, in a timezone with days starting at `start_of_day` past midnight
+fn day_start(day: NaiveDate, tz: Tz, start_of_day: Duration) -> Option<DateTime<Utc>> {
+    let start = day.and_hms_opt(0, 0, 0)? + start_of_day;
+    // Clocks are never turned forward by more than a day, the start is right after the gap
+    for minutes in 0..24 * 60 {
+        match tz.from_local_datetime(&(start + Duration::minutes(minutes))) {
+            LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => {
+                return Some(t.with_timezone(&Utc))
+            }
+            LocalResult::None => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use chrono_tz::Tz;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::split_by_day;
+    use crate::test_utils::event;
+
+    fn parts(events: Vec<Event>) -> Vec<(String, Duration)> {
+        events
+            .into_iter()
+            .map(|e| (e.timestamp.to_rfc3339(), e.duration))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_by_day() {
+        let e = event("2000-01-01T20:00:00Z", 30 * 3600, json_map! {"test": 1});
+
+        // In UTC, at midnight
+        let split = split_by_day(vec![e.clone()], Tz::UTC, Duration::zero());
+        assert_eq!(
+            parts(split),
+            vec![
+                ("2000-01-01T20:00:00+00:00".to_string(), Duration::hours(4)),
+                ("2000-01-02T00:00:00+00:00".to_string(), Duration::hours(24)),
+                ("2000-01-03T00:00:00+00:00".to_string(), Duration::hours(2)),
+            ]
+        );
+
+        // Local days starting at 04:00 in UTC+1
+        let split = split_by_day(vec![e], Tz::Europe__Stockholm, Duration::hours(4));
+        assert_eq!(
+            parts(split),
+            vec![
+                ("2000-01-01T20:00:00+00:00".to_string(), Duration::hours(7)),
+                ("2000-01-02T03:00:00+00:00".to_string(), Duration::hours(23)),
+            ]
+        );
+
+        // Events within a day are kept as they are
+        let e = event("2000-01-01T10:00:00Z", 3600, json_map! {"test": 1});
+        let split = split_by_day(vec![e.clone()], Tz::Europe__Stockholm, Duration::zero());
+        assert_eq!(split, vec![e]);
+    }
+
+    #[test]
+    fn test_split_by_day_dst() {
+        // Clocks went forward from 02:00 to 03:00 on 2021-03-28 in Stockholm, so that day
+        // was 23 hours long, and back from 03:00 to 02:00 on 2021-10-31, a day of 25 hours
+        let e = event("2021-03-27T23:00:00Z", 24 * 3600, json_map! {"test": 1});
+        let split = split_by_day(vec![e], Tz::Europe__Stockholm, Duration::zero());
+        assert_eq!(
+            parts(split),
+            vec![
+                ("2021-03-27T23:00:00+00:00".to_string(), Duration::hours(23)),
+                ("2021-03-28T22:00:00+00:00".to_string(), Duration::hours(1)),
+            ]
+        );
+        let e = event("2021-10-30T22:00:00Z", 26 * 3600, json_map! {"test": 1});
+        let split = split_by_day(vec![e], Tz::Europe__Stockholm, Duration::zero());
+        assert_eq!(
+            parts(split),
+            vec![
+                ("2021-10-30T22:00:00+00:00".to_string(), Duration::hours(25)),
+                ("2021-10-31T23:00:00+00:00".to_string(), Duration::hours(1)),
+            ]
+        );
+
+        // A start of day inside the skipped hour starts the day when the clocks turned
+        let e = event("2021-03-27T12:00:00Z", 24 * 3600, json_map! {"test": 1});
+        let split = split_by_day(
+            vec![e],
+            Tz::Europe__Stockholm,
+            Duration::minutes(2 * 60 + 30),
+        );
+        assert_eq!(
+            parts(split),
+            vec![
+                ("2021-03-27T12:00:00+00:00".to_string(), Duration::hours(13)),
+                ("2021-03-28T01:00:00+00:00".to_string(), Duration::hours(11)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+
+use aw_models::Event;
+
+/// The mean duration of the events, None if there are no events
+pub fn mean_duration(events: &[Event]) -> Option<Duration> {
+    if events.is_empty() {
+        return None;
+    }
+    let total: i128 = events
+        .iter()
+        .map(|e| e.duration.num_nanoseconds().unwrap_or(i64::MAX) as i128)
+        .sum();
+    Some(Duration::nanoseconds((total / events.len() as i128) as i64))
+}
+
+/// The median duration of the events, None if there are no events
+pub fn median_duration(events: &[Event]) -> Option<Duration> {
+    percentile_duration(events, 50.0)
+}
+
+/// The duration which `percentile` percent of the events are shorter than, interpolated
+/// between the two closest events like most spreadsheets do. None if there are no events
+/// or the percentile is not between 0 and 100.
+///
+/// # Example
+/// ```ignore
+/// percentile: 25
+/// input:  [1s, 2s, 3s, 4s, 5s]
+/// output: 2s
+/// ```
+pub fn percentile_duration(events: &[Event], percentile: f64) -> Option<Duration> {
+    if events.is_empty() || !(0.0..=100.0).contains(&percentile) {
+        return None;
+    }
+    let mut durations: Vec<i64> = events
+        .iter()
+        .map(|e| e.duration.num_nanoseconds().unwrap_or(i64::MAX))
+        .collect();
+    durations.sort_unstable();
+    let rank = percentile / 100.0 * (durations.len() - 1) as f64;
+    let (lower, upper) = (
+        durations[rank.floor() as usize],
+        durations[rank.ceil() as usize],
+    );
+    let interpolated = lower as f64 + (upper - lower) as f64 * rank.fract();
+    Some(Duration::nanoseconds(interpolated.round() as i64))
+}
+
+/// The earliest start of the events, None if there are no events
+pub fn min_timestamp(events: &[Event]) -> Option<DateTime<Utc>> {
+    events.iter().map(|e| e.timestamp).min()
+}
+
+/// The latest start of the events, None if there are no events
+pub fn max_timestamp(events: &[Event]) -> Option<DateTime<Utc>> {
+    events.iter().map(|e| e.timestamp).max()
+}
+
+/// The `n` values of a key with the longest summed duration, longest first. Events without
+/// the key are left out, and values with the same duration are ordered by first appearance.
+///
+/// # Example
+/// ```ignore
+/// key:    app
+/// n:      2
+/// input:  [app: a 1s][app: b 2s][app: a 2s][app: c 1s]
+/// output: [(a, 3s), (b, 2s)]
+/// ```
+pub fn top_n(events: &[Event], key: &str, n: usize) -> Vec<(Value, Duration)> {
+    let values = events
+        .iter()
+        .filter_map(|event| Some((event.data.get(key)?, event.duration)));
+    let mut totals = merge_by_value(values, |total, duration| *total += duration, |d| *d);
+    totals.truncate(n);
+    totals
+}
+
+/// Merges the items with the same value into the first one with it, and sorts them by
+/// their duration, longest first. Values with the same duration are ordered by first
+/// appearance.
+pub(crate) fn merge_by_value<'a, T>(
+    items: impl IntoIterator<Item = (&'a Value, T)>,
+    mut merge: impl FnMut(&mut T, T),
+    duration: impl Fn(&T) -> Duration,
+) -> Vec<(Value, T)> {
+    // Values are keyed on their JSON, as Value can't be hashed
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    let mut merged: Vec<(Value, T)> = Vec::new();
+    for (value, item) in items {
+        let json = value.to_string();
+        match indexes.get(&json) {
+            Some(&i) => merge(&mut merged[i].1, item),
+            None => {
+                indexes.insert(json, merged.len());
+                merged.push((value.clone(), item));
+            }
+        }
+    }
+    // A stable sort keeps the values with the same duration in order of appearance
+    merged.sort_by_key(|(_, item)| std::cmp::Reverse(duration(item)));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::*;
+    use crate::test_utils::event;
+
+    #[test]
+    fn test_durations() {
+        let events: Vec<Event> = [4, 1, 3, 5, 2]
+            .iter()
+            .map(|secs| event("2000-01-01T00:00:00Z", *secs, json_map! {"app": "a"}))
+            .collect();
+        assert_eq!(mean_duration(&events), Some(Duration::seconds(3)));
+        assert_eq!(median_duration(&events), Some(Duration::seconds(3)));
+        assert_eq!(
+            percentile_duration(&events, 25.0),
+            Some(Duration::seconds(2))
+        );
+        assert_eq!(
+            percentile_duration(&events, 100.0),
+            Some(Duration::seconds(5))
+        );
+        // Interpolated between 4s and 5s
+        assert_eq!(
+            percentile_duration(&events, 90.0),
+            Some(Duration::milliseconds(4600))
+        );
+        assert_eq!(percentile_duration(&events, 101.0), None);
+
+        // The median of an even number of events is between the middle ones
+        assert_eq!(
+            median_duration(&events[..4]),
+            Some(Duration::milliseconds(3500))
+        );
+
+        assert_eq!(mean_duration(&[]), None);
+        assert_eq!(median_duration(&[]), None);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let e1 = event("2000-01-01T00:00:00Z", 100, json_map! {"app": "a"});
+        let e2 = event("2000-01-01T00:00:10Z", 1, json_map! {"app": "a"});
+        let events = [e2.clone(), e1.clone()];
+        assert_eq!(min_timestamp(&events), Some(e1.timestamp));
+        assert_eq!(max_timestamp(&events), Some(e2.timestamp));
+        assert_eq!(min_timestamp(&[]), None);
+    }
+
+    #[test]
+    fn test_top_n() {
+        let mut events = vec![
+            event("2000-01-01T00:00:00Z", 1, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:01Z", 2, json_map! {"app": "b"}),
+            event("2000-01-01T00:00:03Z", 2, json_map! {"app": "a"}),
+            event("2000-01-01T00:00:05Z", 3, json_map! {"app": "c"}),
+        ];
+        events.push(Event {
+            data: json_map! {"other": json!("d")},
+            ..events[0].clone()
+        });
+        let top = top_n(&events, "app", 2);
+        assert_eq!(
+            top,
+            vec![
+                (json!("a"), Duration::seconds(3)),
+                (json!("c"), Duration::seconds(3)),
+            ]
+        );
+        assert_eq!(top_n(&events, "app", 10).len(), 3);
+        assert_eq!(top_n(&events, "missing", 10), vec![]);
+    }
+}